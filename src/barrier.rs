@@ -0,0 +1,380 @@
+// Cluster barriers over CPG
+// Copyright (c) 2021 Red Hat, Inc.
+//
+// All rights reserved.
+//
+// Author: Christine Caulfield (ccaulfi@redhat.com)
+//
+
+//! A barrier is identified by name. When the first member arrives at a barrier, everyone
+//! who is in the group at that moment becomes a participant, and the barrier completes
+//! once all of the participants have arrived. Processes that join the group later are
+//! not waited for until the next time around.
+//!
+//! Each time a barrier completes its generation number goes up by one, so the same
+//! name can be used over and over (eg once per step of an upgrade).
+//!
+//! What happens when a participant leaves the group before arriving is decided by the
+//! [crate::barrier::LeavePolicy] given to [crate::barrier::Barrier::new]. All members of a
+//! group should use the same policy.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::cpg;
+use crate::wire::{Reader, Writer};
+use crate::{CsError, DispatchFlags, NodeId, Result};
+
+/// What to do when a participant leaves the group before it has arrived at a barrier
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LeavePolicy {
+    /// Stop waiting for it, the barrier completes when everyone else has arrived
+    Exclude,
+    /// The barrier fails, [Barrier::wait] returns CsErrFailedOperation to everyone waiting
+    Fail,
+}
+
+const MSG_ARRIVE: u8 = 1;
+const MSG_WITHDRAW: u8 = 2;
+
+type Owner = (NodeId, u32);
+
+// One trip through a barrier
+struct Round {
+    generation: u64,
+    participants: BTreeSet<Owner>,
+    // Who has arrived, and the id of their request
+    arrived: BTreeMap<Owner, u64>,
+}
+
+struct State {
+    me: Owner,
+    policy: LeavePolicy,
+    members: BTreeSet<Owner>,
+    // Generations completed, by barrier name
+    generations: HashMap<String, u64>,
+    rounds: HashMap<String, Round>,
+    // Arrivals for the next round from members already in the current one
+    early: HashMap<String, Vec<(Owner, u64, u64)>>,
+    next_id: u64,
+    outcomes: HashMap<u64, Result<u64>>,
+    // Our own withdrawals that have come back to us
+    withdrawn: HashSet<u64>,
+}
+
+lazy_static! {
+    static ref BARRIER_HASH: Mutex<HashMap<u64, Arc<Mutex<State>>>> = Mutex::new(HashMap::new());
+}
+
+impl State {
+    fn finish_round(&mut self, name: &str, result: Result<u64>) {
+        if let Some(round) = self.rounds.remove(name) {
+            for (owner, id) in &round.arrived {
+                if *owner == self.me {
+                    self.outcomes.insert(*id, result);
+                }
+            }
+            if let Ok(generation) = result {
+                self.generations.insert(name.to_string(), generation + 1);
+            }
+        }
+        self.start_next(name);
+    }
+
+    // Start the next round with anyone who was already waiting for it
+    fn start_next(&mut self, name: &str) {
+        if let Some(early) = self.early.remove(name) {
+            for (owner, id, generation) in early {
+                self.arrive(name, owner, id, generation);
+            }
+        }
+    }
+
+    fn check_complete(&mut self, name: &str) {
+        let complete = match self.rounds.get(name) {
+            Some(round) => round
+                .participants
+                .iter()
+                .all(|p| round.arrived.contains_key(p)),
+            None => false,
+        };
+        if complete {
+            let generation = self.rounds[name].generation;
+            self.finish_round(name, Ok(generation));
+        }
+    }
+
+    fn arrive(&mut self, name: &str, from: Owner, id: u64, generation: u64) {
+        let completed = self.generations.get(name).copied().unwrap_or(0);
+        let members = &self.members;
+        let round = self
+            .rounds
+            .entry(name.to_string())
+            .or_insert_with(|| Round {
+                generation: completed,
+                participants: members.clone(),
+                arrived: BTreeMap::new(),
+            });
+        if round.arrived.contains_key(&from) {
+            self.early
+                .entry(name.to_string())
+                .or_default()
+                .push((from, id, generation));
+            return;
+        }
+        // Members that joined recently don't know how many times the barrier has been used,
+        // so go with the highest generation anyone has claimed.
+        if generation > round.generation {
+            round.generation = generation;
+        }
+        round.participants.insert(from);
+        round.arrived.insert(from, id);
+        self.check_complete(name);
+    }
+
+    fn withdraw(&mut self, name: &str, from: Owner, id: u64) {
+        if let Some(round) = self.rounds.get_mut(name) {
+            if round.arrived.get(&from) == Some(&id) {
+                round.arrived.remove(&from);
+                if round.arrived.is_empty() {
+                    self.rounds.remove(name);
+                }
+            }
+        }
+        if let Some(early) = self.early.get_mut(name) {
+            early.retain(|e| !(e.0 == from && e.1 == id));
+        }
+        if from == self.me {
+            self.withdrawn.insert(id);
+        }
+        if !self.rounds.contains_key(name) {
+            self.start_next(name);
+        }
+    }
+
+    fn apply_message(&mut self, from: Owner, msg: &[u8]) -> Result<()> {
+        let mut r = Reader::new(msg);
+        match r.get_u8()? {
+            MSG_ARRIVE => {
+                let name = r.get_str()?;
+                let id = r.get_u64()?;
+                let generation = r.get_u64()?;
+                self.arrive(&name, from, id, generation);
+            }
+            MSG_WITHDRAW => {
+                let name = r.get_str()?;
+                let id = r.get_u64()?;
+                self.withdraw(&name, from, id);
+            }
+            _ => return Err(CsError::CsErrMessageError),
+        }
+        Ok(())
+    }
+
+    fn apply_left(&mut self, left: &[Owner]) {
+        for early in self.early.values_mut() {
+            early.retain(|e| !left.contains(&e.0));
+        }
+        let names: Vec<String> = self.rounds.keys().cloned().collect();
+        for name in names {
+            let round = self.rounds.get_mut(&name).unwrap();
+            let missing = left
+                .iter()
+                .any(|l| round.participants.contains(l) && !round.arrived.contains_key(l));
+            for l in left {
+                round.participants.remove(l);
+                round.arrived.remove(l);
+            }
+            if round.arrived.is_empty() {
+                // Only the leavers had got here, start again next time
+                self.rounds.remove(&name);
+            } else if missing && self.policy == LeavePolicy::Fail {
+                self.finish_round(&name, Err(CsError::CsErrFailedOperation));
+            } else {
+                self.check_complete(&name);
+            }
+        }
+    }
+}
+
+fn find_state(handle: &cpg::Handle) -> Option<Arc<Mutex<State>>> {
+    BARRIER_HASH
+        .lock()
+        .unwrap()
+        .get(&handle.cpg_handle)
+        .cloned()
+}
+
+fn barrier_deliver_fn(
+    handle: &cpg::Handle,
+    _group_name: cpg::CpgName,
+    nodeid: NodeId,
+    pid: u32,
+    msg: &[u8],
+    _msg_len: usize,
+) {
+    if let Some(state) = find_state(handle) {
+        let _ = state.lock().unwrap().apply_message((nodeid, pid), msg);
+    }
+}
+
+fn barrier_confchg_fn(
+    handle: &cpg::Handle,
+    _group_name: &cpg::CpgName,
+    member_list: Vec<cpg::Address>,
+    left_list: Vec<cpg::Address>,
+    _joined_list: Vec<cpg::Address>,
+) {
+    if let Some(state) = find_state(handle) {
+        let mut state = state.lock().unwrap();
+        state.members = member_list.iter().map(|a| (a.nodeid, a.pid)).collect();
+        let left: Vec<Owner> = left_list.iter().map(|a| (a.nodeid, a.pid)).collect();
+        state.apply_left(&left);
+    }
+}
+
+/// Membership of a group of processes that synchronise on barriers,
+/// created with [Barrier::new]. The group is left when this is dropped or
+/// explicitly with [Barrier::finalize]
+pub struct Barrier {
+    handle: cpg::Handle,
+    group: String,
+    state: Arc<Mutex<State>>,
+    closed: bool,
+}
+
+impl Barrier {
+    /// Join the CPG group `group`, all processes using the same barriers must
+    /// use the same group
+    pub fn new(group: &str, policy: LeavePolicy) -> Result<Barrier> {
+        let md = cpg::ModelData::ModelV1(cpg::Model1Data {
+            flags: cpg::Model1Flags::None,
+            deliver_fn: Some(barrier_deliver_fn),
+            confchg_fn: Some(barrier_confchg_fn),
+            totem_confchg_fn: None,
+        });
+        let handle = cpg::initialize(&md, 0)?;
+        let nodeid = match cpg::local_get(handle) {
+            Ok(n) => n,
+            Err(e) => {
+                let _ = cpg::finalize(handle);
+                return Err(e);
+            }
+        };
+        let state = Arc::new(Mutex::new(State {
+            me: (nodeid, std::process::id()),
+            policy,
+            members: BTreeSet::new(),
+            generations: HashMap::new(),
+            rounds: HashMap::new(),
+            early: HashMap::new(),
+            next_id: 0,
+            outcomes: HashMap::new(),
+            withdrawn: HashSet::new(),
+        }));
+        BARRIER_HASH
+            .lock()
+            .unwrap()
+            .insert(handle.cpg_handle, state.clone());
+
+        if let Err(e) = cpg::join(handle, group) {
+            BARRIER_HASH.lock().unwrap().remove(&handle.cpg_handle);
+            let _ = cpg::finalize(handle);
+            return Err(e);
+        }
+        Ok(Barrier {
+            handle,
+            group: group.to_string(),
+            state,
+            closed: false,
+        })
+    }
+
+    /// The CPG [cpg::Handle] used by the barriers
+    pub fn handle(&self) -> cpg::Handle {
+        self.handle
+    }
+
+    /// Process incoming barrier traffic, see [cpg::dispatch]
+    pub fn dispatch(&self, flags: DispatchFlags) -> Result<()> {
+        cpg::dispatch(self.handle, flags)
+    }
+
+    /// Arrive at barrier `name` and wait for all the other participants to get there.
+    /// Returns the generation of the barrier that completed.
+    /// If `timeout` expires first then our arrival is withdrawn and CsErrTimeout returned,
+    /// unless the barrier turns out to have completed (or failed) before the withdrawal
+    /// reached the other members, in which case that is returned as usual.
+    pub fn wait(&self, name: &str, timeout: Duration) -> Result<u64> {
+        let (id, generation) = {
+            let mut state = self.state.lock().unwrap();
+            state.next_id += 1;
+            (
+                state.next_id,
+                state.generations.get(name).copied().unwrap_or(0),
+            )
+        };
+        let mut w = Writer::new(MSG_ARRIVE);
+        w.put_str(name).put_u64(id).put_u64(generation);
+        cpg::mcast_joined_retry(self.handle, cpg::Guarantee::TypeAgreed, w.as_slice())?;
+
+        let mut outcome = None;
+        cpg::dispatch_until(self.handle, Some(Instant::now() + timeout), || {
+            outcome = self.state.lock().unwrap().outcomes.remove(&id);
+            outcome.is_some()
+        })?;
+        match outcome {
+            Some(r) => r,
+            None => {
+                let mut w = Writer::new(MSG_WITHDRAW);
+                w.put_str(name).put_u64(id);
+                cpg::mcast_joined_retry(self.handle, cpg::Guarantee::TypeAgreed, w.as_slice())?;
+
+                // Everyone sees the withdrawal in the same place in the message order, so
+                // once it is back here we know whether the barrier completed before it.
+                cpg::dispatch_until(self.handle, None, || {
+                    self.state.lock().unwrap().withdrawn.contains(&id)
+                })?;
+                let mut state = self.state.lock().unwrap();
+                state.withdrawn.remove(&id);
+                state
+                    .outcomes
+                    .remove(&id)
+                    .unwrap_or(Err(CsError::CsErrTimeout))
+            }
+        }
+    }
+
+    /// The number of times barrier `name` has completed, as far as we know
+    pub fn generation(&self, name: &str) -> u64 {
+        self.state
+            .lock()
+            .unwrap()
+            .generations
+            .get(name)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.closed = true;
+        let _ = cpg::leave(self.handle, &self.group);
+        BARRIER_HASH.lock().unwrap().remove(&self.handle.cpg_handle);
+        cpg::finalize(self.handle)
+    }
+
+    /// Leave the group and close the CPG connection
+    pub fn finalize(mut self) -> Result<()> {
+        self.close()
+    }
+}
+
+impl Drop for Barrier {
+    fn drop(&mut self) {
+        if !self.closed {
+            // Nowhere to report an error
+            let _ = self.close();
+        }
+    }
+}
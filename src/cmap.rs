@@ -12,18 +12,27 @@
 use crate::sys::cmap as ffi;
 
 use num_enum::TryFromPrimitive;
-use std::any::type_name;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::ffi::CString;
 use std::fmt;
 use std::os::raw::{c_char, c_int, c_void};
-use std::ptr::copy_nonoverlapping;
-use std::sync::Mutex;
+use std::str::FromStr;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::{Arc, Mutex};
 
 use crate::string_from_bytes;
 use crate::{CsError, DispatchFlags, Result};
 
+/// Typed view of the corosync.conf settings held in icmap
+pub mod config;
+/// A live in-process copy of a cmap subtree
+pub mod mirror;
+/// Sets of changes to push into cmap with [apply]
+pub mod patch;
+/// Whole-map snapshots, export in JSON & corosync-cmapctl format, and diffs
+pub mod snapshot;
+
 // Maps:
 /// "Maps" available to [initialize]
 pub enum Map {
@@ -68,17 +77,13 @@ pub struct Handle {
     cmap_handle: u64,
 }
 
-#[derive(Copy, Clone)]
-/// A handle for a specific CMAP tracker. returned from [track_add].
-/// There may be multiple TrackHandles per [Handle]
-pub struct TrackHandle {
-    track_handle: u64,
-    notify_callback: NotifyCallback,
-}
+// Called every time a tracker reports a change in a tracked value,
+// with the event, the key name, the old value and the new value
+type NotifyFn = Arc<Mutex<dyn FnMut(TrackType, &str, &Data, &Data) + Send>>;
 
 // Used to convert CMAP handles into one of ours, for callbacks
 lazy_static! {
-    static ref TRACKHANDLE_HASH: Mutex<HashMap<u64, TrackHandle>> = Mutex::new(HashMap::new());
+    static ref TRACKHANDLE_HASH: Mutex<HashMap<u64, NotifyFn>> = Mutex::new(HashMap::new());
     static ref HANDLE_HASH: Mutex<HashMap<u64, Handle>> = Mutex::new(HashMap::new());
 }
 
@@ -196,6 +201,30 @@ pub enum DataType {
     Unknown = 999,
 }
 
+/// Parses the type names used by corosync-cmapctl (i8, u8 ... flt, dbl, str, bin)
+/// as well as the names printed by Display (Int8, UInt8 ... Binary), ignoring case
+impl FromStr for DataType {
+    type Err = CsError;
+
+    fn from_str(s: &str) -> Result<DataType> {
+        match s.to_ascii_lowercase().as_str() {
+            "i8" | "int8" => Ok(DataType::Int8),
+            "u8" | "uint8" => Ok(DataType::UInt8),
+            "i16" | "int16" => Ok(DataType::Int16),
+            "u16" | "uint16" => Ok(DataType::UInt16),
+            "i32" | "int32" => Ok(DataType::Int32),
+            "u32" | "uint32" => Ok(DataType::UInt32),
+            "i64" | "int64" => Ok(DataType::Int64),
+            "u64" | "uint64" => Ok(DataType::UInt64),
+            "flt" | "float" => Ok(DataType::Float),
+            "dbl" | "double" => Ok(DataType::Double),
+            "str" | "string" => Ok(DataType::String),
+            "bin" | "binary" => Ok(DataType::Binary),
+            _ => Err(CsError::CsErrInvalidParam),
+        }
+    }
+}
+
 fn cmap_to_enum(cmap_type: u32) -> DataType {
     match DataType::try_from(cmap_type) {
         Ok(e) => e,
@@ -205,6 +234,7 @@ fn cmap_to_enum(cmap_type: u32) -> DataType {
 
 /// Data returned from the cmap::get() call and tracker & iterators.
 /// Contains the data itself and the type of that data.
+#[derive(Clone, Debug, PartialEq)]
 pub enum Data {
     Int8(i8),
     UInt8(u8),
@@ -262,6 +292,194 @@ impl fmt::Display for Data {
 }
 
 const CMAP_KEYNAME_MAXLENGTH: usize = 255;
+const CMAP_KEYNAME_MINLENGTH: usize = 3;
+
+// The same rules that corosync uses for key names, so we can reject them
+// before sending anything
+fn validate_key_name(key_name: &str) -> Result<()> {
+    if key_name.len() < CMAP_KEYNAME_MINLENGTH || key_name.len() >= CMAP_KEYNAME_MAXLENGTH {
+        return Err(CsError::CsErrInvalidParam);
+    }
+    if key_name.bytes().all(|c| c == b'.' || is_segment_char(c)) {
+        Ok(())
+    } else {
+        Err(CsError::CsErrInvalidParam)
+    }
+}
+
+// Characters allowed in key names, apart from the '.' between segments
+fn is_segment_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"_-/:".contains(&c)
+}
+
+/// A cmap key name, such as `nodelist.node.3.ring0_addr`, made up of segments separated by dots.
+/// A KeyPath is always a valid key name (or the start of one), so it can be built up
+/// piece by piece instead of with format!() and then passed to any of the cmap functions.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct KeyPath {
+    path: String,
+}
+
+/// One segment of a [KeyPath]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Segment<'a> {
+    text: &'a str,
+}
+
+impl<'a> Segment<'a> {
+    pub fn as_str(&self) -> &'a str {
+        self.text
+    }
+
+    /// The segment without any number on the end, so "node" for `node2`
+    /// and "" for `3`
+    pub fn name(&self) -> &'a str {
+        self.text.trim_end_matches(|c: char| c.is_ascii_digit())
+    }
+
+    /// The number on the end of the segment, so 2 for `node2` and 3 for `3`.
+    /// None if there isn't one.
+    pub fn index(&self) -> Option<u64> {
+        self.text[self.name().len()..].parse().ok()
+    }
+}
+
+impl fmt::Display for Segment<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.text)
+    }
+}
+
+fn validate_segment(segment: &str) -> Result<()> {
+    if !segment.is_empty() && segment.bytes().all(is_segment_char) {
+        Ok(())
+    } else {
+        Err(CsError::CsErrInvalidParam)
+    }
+}
+
+impl KeyPath {
+    /// Start a path with its first segment
+    pub fn new(segment: &str) -> Result<KeyPath> {
+        validate_segment(segment)?;
+        let path = KeyPath {
+            path: segment.to_string(),
+        };
+        path.check_length()?;
+        Ok(path)
+    }
+
+    /// Parse a whole key name, or the start of one. Returns CsErrInvalidParam if it has
+    /// characters that corosync doesn't allow, empty segments, or is too long.
+    pub fn parse(key_name: &str) -> Result<KeyPath> {
+        for segment in key_name.split('.') {
+            validate_segment(segment)?;
+        }
+        let path = KeyPath {
+            path: key_name.to_string(),
+        };
+        path.check_length()?;
+        Ok(path)
+    }
+
+    fn check_length(&self) -> Result<()> {
+        if self.path.len() >= CMAP_KEYNAME_MAXLENGTH {
+            Err(CsError::CsErrInvalidParam)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Add a segment to the end
+    pub fn push(&mut self, segment: &str) -> Result<()> {
+        validate_segment(segment)?;
+        if self.path.len() + 1 + segment.len() >= CMAP_KEYNAME_MAXLENGTH {
+            return Err(CsError::CsErrInvalidParam);
+        }
+        self.path.push('.');
+        self.path.push_str(segment);
+        Ok(())
+    }
+
+    /// A new path with `segment` added to the end
+    pub fn child(&self, segment: &str) -> Result<KeyPath> {
+        let mut path = self.clone();
+        path.push(segment)?;
+        Ok(path)
+    }
+
+    /// A new path with a number added to the end as a segment, like the 3 in `nodelist.node.3`
+    pub fn index(&self, index: u64) -> Result<KeyPath> {
+        self.child(&index.to_string())
+    }
+
+    /// The path without its last segment, None if there is only one segment
+    pub fn parent(&self) -> Option<KeyPath> {
+        self.path.rfind('.').map(|i| KeyPath {
+            path: self.path[..i].to_string(),
+        })
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = Segment<'_>> {
+        self.path.split('.').map(|text| Segment { text })
+    }
+
+    /// The last segment
+    pub fn last(&self) -> Segment<'_> {
+        // There is always at least one segment
+        self.segments().last().unwrap()
+    }
+
+    /// The numbers in the path, from segments that are a number or end in one.
+    /// `[3]` for `nodelist.node.3.ring0_addr` and `[2, 0]` for
+    /// `stats.knet.node2.link0.latency_ave`
+    pub fn indices(&self) -> Vec<u64> {
+        self.segments().filter_map(|s| s.index()).collect()
+    }
+
+    /// Returns true if `prefix` is this path or one of its parents. Unlike a string
+    /// comparison this matches whole segments, so `totem` is a prefix of `totem.token`
+    /// but not of `totemx`
+    pub fn starts_with(&self, prefix: &KeyPath) -> bool {
+        match self.path.strip_prefix(&prefix.path) {
+            Some(rest) => rest.is_empty() || rest.starts_with('.'),
+            None => false,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.path
+    }
+}
+
+impl AsRef<str> for KeyPath {
+    fn as_ref(&self) -> &str {
+        &self.path
+    }
+}
+
+impl fmt::Display for KeyPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.path)
+    }
+}
+
+impl FromStr for KeyPath {
+    type Err = CsError;
+
+    fn from_str(s: &str) -> Result<KeyPath> {
+        KeyPath::parse(s)
+    }
+}
+
+impl TryFrom<&str> for KeyPath {
+    type Error = CsError;
+
+    fn try_from(s: &str) -> Result<KeyPath> {
+        KeyPath::parse(s)
+    }
+}
+
 fn string_to_cstring_validated(key: &str, maxlen: usize) -> Result<CString> {
     if maxlen > 0 && key.chars().count() >= maxlen {
         return Err(CsError::CsErrInvalidParam);
@@ -297,21 +515,301 @@ fn set_value(
     }
 }
 
-// Returns type and size
-fn generic_to_cmap<T>(_value: T) -> (DataType, usize) {
-    match type_name::<T>() {
-        "u8" => (DataType::UInt8, 1),
-        "i8" => (DataType::Int8, 1),
-        "u16" => (DataType::UInt16, 2),
-        "i16" => (DataType::Int16, 2),
-        "u32" => (DataType::UInt32, 4),
-        "i32" => (DataType::Int32, 4),
-        "u64" => (DataType::UInt64, 4),
-        "f32" => (DataType::Float, 4),
-        "f64" => (DataType::Double, 8),
-        "&str" => (DataType::String, 0),
-        // Binary not currently supported here
-        _ => (DataType::Unknown, 0),
+impl Data {
+    /// The [DataType] of this value
+    pub fn data_type(&self) -> DataType {
+        match self {
+            Data::Int8(_) => DataType::Int8,
+            Data::UInt8(_) => DataType::UInt8,
+            Data::Int16(_) => DataType::Int16,
+            Data::UInt16(_) => DataType::UInt16,
+            Data::Int32(_) => DataType::Int32,
+            Data::UInt32(_) => DataType::UInt32,
+            Data::Int64(_) => DataType::Int64,
+            Data::UInt64(_) => DataType::UInt64,
+            Data::Float(_) => DataType::Float,
+            Data::Double(_) => DataType::Double,
+            Data::String(_) => DataType::String,
+            Data::Binary(_) => DataType::Binary,
+            Data::Unknown => DataType::Unknown,
+        }
+    }
+
+    /// The value of a String, or None for any other type
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Data::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The value of a Binary, or None for any other type
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Data::Binary(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// Parse `value` as a `dtype`, the same way as `corosync-cmapctl -s key type value`.
+    /// Integers can be decimal or hex with a 0x prefix, binaries are a string of hex digits.
+    /// Returns CsErrInvalidParam if the value doesn't parse or is out of range for the type.
+    pub fn parse(dtype: DataType, value: &str) -> Result<Data> {
+        match dtype {
+            DataType::Int8 => Ok(Data::Int8(parse_int(value)?)),
+            DataType::UInt8 => Ok(Data::UInt8(parse_int(value)?)),
+            DataType::Int16 => Ok(Data::Int16(parse_int(value)?)),
+            DataType::UInt16 => Ok(Data::UInt16(parse_int(value)?)),
+            DataType::Int32 => Ok(Data::Int32(parse_int(value)?)),
+            DataType::UInt32 => Ok(Data::UInt32(parse_int(value)?)),
+            DataType::Int64 => Ok(Data::Int64(parse_int(value)?)),
+            DataType::UInt64 => Ok(Data::UInt64(parse_int(value)?)),
+            DataType::Float => value
+                .trim()
+                .parse()
+                .map(Data::Float)
+                .map_err(|_| CsError::CsErrInvalidParam),
+            DataType::Double => value
+                .trim()
+                .parse()
+                .map(Data::Double)
+                .map_err(|_| CsError::CsErrInvalidParam),
+            DataType::String => Ok(Data::String(value.to_string())),
+            DataType::Binary => parse_hex(value).map(Data::Binary),
+            DataType::Unknown => Err(CsError::CsErrInvalidParam),
+        }
+    }
+}
+
+fn parse_int<T: TryFrom<i128>>(value: &str) -> Result<T> {
+    let value = value.trim();
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(d) => (true, d),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+    // from_str_radix() takes a sign of its own, so one here would be a second one
+    if digits.starts_with(['+', '-']) {
+        return Err(CsError::CsErrInvalidParam);
+    }
+    let n = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) if !hex.starts_with(['+', '-']) => i128::from_str_radix(hex, 16),
+        Some(_) => return Err(CsError::CsErrInvalidParam),
+        None => digits.parse::<i128>(),
+    }
+    .map_err(|_| CsError::CsErrInvalidParam)?;
+    let n = if negative { -n } else { n };
+    T::try_from(n).map_err(|_| CsError::CsErrInvalidParam)
+}
+
+fn parse_hex(value: &str) -> Result<Vec<u8>> {
+    let digits: Vec<u8> = value.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    let nibble = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    digits
+        .chunks(2)
+        .map(|pair| match pair {
+            [hi, lo] => Some((nibble(*hi)? << 4) | nibble(*lo)?),
+            // An odd number of digits
+            _ => None,
+        })
+        .map(|b| b.ok_or(CsError::CsErrInvalidParam))
+        .collect()
+}
+
+macro_rules! impl_data_from {
+    ($t:ty, $variant:ident) => {
+        impl From<$t> for Data {
+            fn from(v: $t) -> Data {
+                Data::$variant(v)
+            }
+        }
+    };
+}
+
+impl_data_from!(i8, Int8);
+impl_data_from!(u8, UInt8);
+impl_data_from!(i16, Int16);
+impl_data_from!(u16, UInt16);
+impl_data_from!(i32, Int32);
+impl_data_from!(u32, UInt32);
+impl_data_from!(i64, Int64);
+impl_data_from!(u64, UInt64);
+impl_data_from!(f32, Float);
+impl_data_from!(f64, Double);
+impl_data_from!(String, String);
+impl_data_from!(Vec<u8>, Binary);
+
+impl From<&str> for Data {
+    fn from(v: &str) -> Data {
+        Data::String(v.to_string())
+    }
+}
+
+impl From<&[u8]> for Data {
+    fn from(v: &[u8]) -> Data {
+        Data::Binary(v.to_vec())
+    }
+}
+
+// Converting from Data to a primitive accepts any variant that always fits,
+// so a UInt8 can be read as a u64 or an i16, but a UInt64 can't be read as a u32
+// even if the value happens to be small enough.
+macro_rules! impl_try_from_data {
+    ($t:ty, $($variant:ident),+) => {
+        impl TryFrom<&Data> for $t {
+            type Error = CsError;
+
+            fn try_from(data: &Data) -> Result<$t> {
+                match data {
+                    $(Data::$variant(v) => Ok(<$t>::from(*v)),)+
+                    _ => Err(CsError::CsErrInvalidParam),
+                }
+            }
+        }
+    };
+}
+
+impl_try_from_data!(i8, Int8);
+impl_try_from_data!(u8, UInt8);
+impl_try_from_data!(i16, Int8, UInt8, Int16);
+impl_try_from_data!(u16, UInt8, UInt16);
+impl_try_from_data!(i32, Int8, UInt8, Int16, UInt16, Int32);
+impl_try_from_data!(u32, UInt8, UInt16, UInt32);
+impl_try_from_data!(i64, Int8, UInt8, Int16, UInt16, Int32, UInt32, Int64);
+impl_try_from_data!(u64, UInt8, UInt16, UInt32, UInt64);
+impl_try_from_data!(f32, Int8, UInt8, Int16, UInt16, Float);
+impl_try_from_data!(f64, Int8, UInt8, Int16, UInt16, Int32, UInt32, Float, Double);
+
+impl TryFrom<&Data> for String {
+    type Error = CsError;
+
+    fn try_from(data: &Data) -> Result<String> {
+        data.as_str()
+            .map(|s| s.to_string())
+            .ok_or(CsError::CsErrInvalidParam)
+    }
+}
+
+impl TryFrom<&Data> for Vec<u8> {
+    type Error = CsError;
+
+    fn try_from(data: &Data) -> Result<Vec<u8>> {
+        data.as_bytes()
+            .map(|b| b.to_vec())
+            .ok_or(CsError::CsErrInvalidParam)
+    }
+}
+
+/// A Rust type that can be stored in cmap with [set] and read back with [get].
+/// Implemented for all the integer and float types, String/str, `Vec<u8>`/`[u8]`
+/// and [Data] itself.
+pub trait CmapValue {
+    /// Convert the value to the [Data] that will be stored
+    fn to_data(&self) -> Data;
+    /// Convert [Data] read from cmap back to this type. Returns CsErrInvalidParam
+    /// if the data is of a different type
+    fn from_data(data: Data) -> Result<Self>
+    where
+        Self: Sized;
+}
+
+macro_rules! impl_cmap_value {
+    ($t:ty, $variant:ident) => {
+        impl CmapValue for $t {
+            fn to_data(&self) -> Data {
+                Data::$variant(*self)
+            }
+            fn from_data(data: Data) -> Result<$t> {
+                match data {
+                    Data::$variant(v) => Ok(v),
+                    _ => Err(CsError::CsErrInvalidParam),
+                }
+            }
+        }
+    };
+}
+
+impl_cmap_value!(i8, Int8);
+impl_cmap_value!(u8, UInt8);
+impl_cmap_value!(i16, Int16);
+impl_cmap_value!(u16, UInt16);
+impl_cmap_value!(i32, Int32);
+impl_cmap_value!(u32, UInt32);
+impl_cmap_value!(i64, Int64);
+impl_cmap_value!(u64, UInt64);
+impl_cmap_value!(f32, Float);
+impl_cmap_value!(f64, Double);
+
+/// Stored as a UInt64
+impl CmapValue for usize {
+    fn to_data(&self) -> Data {
+        Data::UInt64(*self as u64)
+    }
+    fn from_data(data: Data) -> Result<usize> {
+        match data {
+            Data::UInt64(v) => usize::try_from(v).map_err(|_| CsError::CsErrInvalidParam),
+            _ => Err(CsError::CsErrInvalidParam),
+        }
+    }
+}
+
+/// Stored as an Int64
+impl CmapValue for isize {
+    fn to_data(&self) -> Data {
+        Data::Int64(*self as i64)
+    }
+    fn from_data(data: Data) -> Result<isize> {
+        match data {
+            Data::Int64(v) => isize::try_from(v).map_err(|_| CsError::CsErrInvalidParam),
+            _ => Err(CsError::CsErrInvalidParam),
+        }
+    }
+}
+
+impl CmapValue for String {
+    fn to_data(&self) -> Data {
+        Data::String(self.clone())
+    }
+    fn from_data(data: Data) -> Result<String> {
+        match data {
+            Data::String(v) => Ok(v),
+            _ => Err(CsError::CsErrInvalidParam),
+        }
+    }
+}
+
+impl CmapValue for str {
+    fn to_data(&self) -> Data {
+        Data::String(self.to_string())
+    }
+}
+
+impl CmapValue for Vec<u8> {
+    fn to_data(&self) -> Data {
+        Data::Binary(self.clone())
+    }
+    fn from_data(data: Data) -> Result<Vec<u8>> {
+        match data {
+            Data::Binary(v) => Ok(v),
+            _ => Err(CsError::CsErrInvalidParam),
+        }
+    }
+}
+
+impl CmapValue for [u8] {
+    fn to_data(&self) -> Data {
+        Data::Binary(self.to_vec())
+    }
+}
+
+impl CmapValue for Data {
+    fn to_data(&self) -> Data {
+        self.clone()
+    }
+    fn from_data(data: Data) -> Result<Data> {
+        Ok(data)
     }
 }
 
@@ -332,34 +830,39 @@ fn is_numeric_type(dtype: DataType) -> bool {
 }
 
 /// Function to set a generic numeric value
-/// This doesn't work for strings or binaries
-pub fn set_number<T: Copy>(handle: Handle, key_name: &str, value: T) -> Result<()> {
-    let (c_type, c_size) = generic_to_cmap(value);
-
-    if is_numeric_type(c_type) {
-        let mut tmp = value;
-        let c_value: *mut c_void = &mut tmp as *mut _ as *mut c_void;
-        set_value(handle, key_name, c_type, c_value as *mut c_void, c_size)
+/// This doesn't work for strings or binaries, use [set] for those
+pub fn set_number<T: CmapValue + Copy>(
+    handle: Handle,
+    key_name: &(impl AsRef<str> + ?Sized),
+    value: T,
+) -> Result<()> {
+    let key_name = key_name.as_ref();
+    let data = value.to_data();
+    if is_numeric_type(data.data_type()) {
+        set_data(handle, key_name, &data)
     } else {
         Err(CsError::CsErrNotSupported)
     }
 }
 
-pub fn set_u8(handle: Handle, key_name: &str, value: u8) -> Result<()> {
+pub fn set_u8(handle: Handle, key_name: &(impl AsRef<str> + ?Sized), value: u8) -> Result<()> {
+    let key_name = key_name.as_ref();
     let mut tmp = value;
     let c_value: *mut c_void = &mut tmp as *mut _ as *mut c_void;
     set_value(handle, key_name, DataType::UInt8, c_value as *mut c_void, 1)
 }
 
 /// Sets an i8 value into cmap
-pub fn set_i8(handle: Handle, key_name: &str, value: i8) -> Result<()> {
+pub fn set_i8(handle: Handle, key_name: &(impl AsRef<str> + ?Sized), value: i8) -> Result<()> {
+    let key_name = key_name.as_ref();
     let mut tmp = value;
     let c_value: *mut c_void = &mut tmp as *mut _ as *mut c_void;
     set_value(handle, key_name, DataType::Int8, c_value as *mut c_void, 1)
 }
 
 /// Sets a u16 value into cmap
-pub fn set_u16(handle: Handle, key_name: &str, value: u16) -> Result<()> {
+pub fn set_u16(handle: Handle, key_name: &(impl AsRef<str> + ?Sized), value: u16) -> Result<()> {
+    let key_name = key_name.as_ref();
     let mut tmp = value;
     let c_value: *mut c_void = &mut tmp as *mut _ as *mut c_void;
     set_value(
@@ -372,28 +875,32 @@ pub fn set_u16(handle: Handle, key_name: &str, value: u16) -> Result<()> {
 }
 
 /// Sets an i16 value into cmap
-pub fn set_i16(handle: Handle, key_name: &str, value: i16) -> Result<()> {
+pub fn set_i16(handle: Handle, key_name: &(impl AsRef<str> + ?Sized), value: i16) -> Result<()> {
+    let key_name = key_name.as_ref();
     let mut tmp = value;
     let c_value: *mut c_void = &mut tmp as *mut _ as *mut c_void;
     set_value(handle, key_name, DataType::Int16, c_value as *mut c_void, 2)
 }
 
 /// Sets a u32 value into cmap
-pub fn set_u32(handle: Handle, key_name: &str, value: u32) -> Result<()> {
+pub fn set_u32(handle: Handle, key_name: &(impl AsRef<str> + ?Sized), value: u32) -> Result<()> {
+    let key_name = key_name.as_ref();
     let mut tmp = value;
     let c_value: *mut c_void = &mut tmp as *mut _ as *mut c_void;
     set_value(handle, key_name, DataType::UInt32, c_value, 4)
 }
 
 /// Sets an i32 value into cmap
-pub fn set_i132(handle: Handle, key_name: &str, value: i32) -> Result<()> {
+pub fn set_i132(handle: Handle, key_name: &(impl AsRef<str> + ?Sized), value: i32) -> Result<()> {
+    let key_name = key_name.as_ref();
     let mut tmp = value;
     let c_value: *mut c_void = &mut tmp as *mut _ as *mut c_void;
     set_value(handle, key_name, DataType::Int32, c_value as *mut c_void, 4)
 }
 
 /// Sets a u64 value into cmap
-pub fn set_u64(handle: Handle, key_name: &str, value: u64) -> Result<()> {
+pub fn set_u64(handle: Handle, key_name: &(impl AsRef<str> + ?Sized), value: u64) -> Result<()> {
+    let key_name = key_name.as_ref();
     let mut tmp = value;
     let c_value: *mut c_void = &mut tmp as *mut _ as *mut c_void;
     set_value(
@@ -406,26 +913,37 @@ pub fn set_u64(handle: Handle, key_name: &str, value: u64) -> Result<()> {
 }
 
 /// Sets an i64 value into cmap
-pub fn set_i164(handle: Handle, key_name: &str, value: i64) -> Result<()> {
+pub fn set_i164(handle: Handle, key_name: &(impl AsRef<str> + ?Sized), value: i64) -> Result<()> {
+    let key_name = key_name.as_ref();
     let mut tmp = value;
     let c_value: *mut c_void = &mut tmp as *mut _ as *mut c_void;
     set_value(handle, key_name, DataType::Int64, c_value as *mut c_void, 8)
 }
 
 /// Sets a string value into cmap
-pub fn set_string(handle: Handle, key_name: &str, value: &str) -> Result<()> {
+pub fn set_string(
+    handle: Handle,
+    key_name: &(impl AsRef<str> + ?Sized),
+    value: &str,
+) -> Result<()> {
+    let key_name = key_name.as_ref();
     let v_string = string_to_cstring_validated(value, 0)?;
     set_value(
         handle,
         key_name,
         DataType::String,
         v_string.as_ptr() as *mut c_void,
-        value.chars().count(),
+        value.len(),
     )
 }
 
 /// Sets a binary value into cmap
-pub fn set_binary(handle: Handle, key_name: &str, value: &[u8]) -> Result<()> {
+pub fn set_binary(
+    handle: Handle,
+    key_name: &(impl AsRef<str> + ?Sized),
+    value: &[u8],
+) -> Result<()> {
+    let key_name = key_name.as_ref();
     set_value(
         handle,
         key_name,
@@ -435,162 +953,89 @@ pub fn set_binary(handle: Handle, key_name: &str, value: &[u8]) -> Result<()> {
     )
 }
 
-/// Sets a [Data] type into cmap
-pub fn set(handle: Handle, key_name: &str, data: &Data) -> Result<()> {
-    let (datatype, datalen, c_value) = match data {
-        Data::Int8(v) => {
-            let mut tmp = *v;
-            let cv: *mut c_void = &mut tmp as *mut _ as *mut c_void;
-            (DataType::Int8, 1, cv)
-        }
-        Data::UInt8(v) => {
-            let mut tmp = *v;
-            let cv: *mut c_void = &mut tmp as *mut _ as *mut c_void;
-            (DataType::UInt8, 1, cv)
-        }
-        Data::Int16(v) => {
-            let mut tmp = *v;
-            let cv: *mut c_void = &mut tmp as *mut _ as *mut c_void;
-            (DataType::Int16, 2, cv)
-        }
-        Data::UInt16(v) => {
-            let mut tmp = *v;
-            let cv: *mut c_void = &mut tmp as *mut _ as *mut c_void;
-            (DataType::UInt8, 2, cv)
-        }
-        Data::Int32(v) => {
-            let mut tmp = *v;
-            let cv: *mut c_void = &mut tmp as *mut _ as *mut c_void;
-            (DataType::Int32, 4, cv)
-        }
-        Data::UInt32(v) => {
-            let mut tmp = *v;
-            let cv: *mut c_void = &mut tmp as *mut _ as *mut c_void;
-            (DataType::UInt32, 4, cv)
-        }
-        Data::Int64(v) => {
-            let mut tmp = *v;
-            let cv: *mut c_void = &mut tmp as *mut _ as *mut c_void;
-            (DataType::Int64, 8, cv)
-        }
-        Data::UInt64(v) => {
-            let mut tmp = *v;
-            let cv: *mut c_void = &mut tmp as *mut _ as *mut c_void;
-            (DataType::UInt64, 8, cv)
-        }
-        Data::Float(v) => {
-            let mut tmp = *v;
-            let cv: *mut c_void = &mut tmp as *mut _ as *mut c_void;
-            (DataType::Float, 4, cv)
-        }
-        Data::Double(v) => {
-            let mut tmp = *v;
-            let cv: *mut c_void = &mut tmp as *mut _ as *mut c_void;
-            (DataType::Double, 8, cv)
-        }
-        Data::String(v) => {
-            let cv = string_to_cstring_validated(v, 0)?;
-            // Can't let cv go out of scope
-            return set_value(
-                handle,
-                key_name,
-                DataType::String,
-                cv.as_ptr() as *mut c_void,
-                v.chars().count(),
-            );
-        }
-        Data::Binary(v) => {
-            // Vec doesn't return quite the right types.
-            return set_value(
-                handle,
-                key_name,
-                DataType::Binary,
-                v.as_ptr() as *mut c_void,
-                v.len(),
-            );
-        }
+/// Sets any [CmapValue] into cmap, with the cmap type that matches the Rust type.
+pub fn set<T: CmapValue + ?Sized>(
+    handle: Handle,
+    key_name: &(impl AsRef<str> + ?Sized),
+    value: &T,
+) -> Result<()> {
+    let key_name = key_name.as_ref();
+    set_data(handle, key_name, &value.to_data())
+}
+
+fn set_data(handle: Handle, key_name: &str, data: &Data) -> Result<()> {
+    // The value needs to stay alive until set_value() returns
+    let mut bytes = match data {
+        Data::Int8(v) => v.to_ne_bytes().to_vec(),
+        Data::UInt8(v) => v.to_ne_bytes().to_vec(),
+        Data::Int16(v) => v.to_ne_bytes().to_vec(),
+        Data::UInt16(v) => v.to_ne_bytes().to_vec(),
+        Data::Int32(v) => v.to_ne_bytes().to_vec(),
+        Data::UInt32(v) => v.to_ne_bytes().to_vec(),
+        Data::Int64(v) => v.to_ne_bytes().to_vec(),
+        Data::UInt64(v) => v.to_ne_bytes().to_vec(),
+        Data::Float(v) => v.to_ne_bytes().to_vec(),
+        Data::Double(v) => v.to_ne_bytes().to_vec(),
+        Data::String(v) => return set_string(handle, key_name, v),
+        Data::Binary(v) => return set_binary(handle, key_name, v),
         Data::Unknown => return Err(CsError::CsErrInvalidParam),
     };
-
-    set_value(handle, key_name, datatype, c_value, datalen)
+    let len = bytes.len();
+    set_value(
+        handle,
+        key_name,
+        data.data_type(),
+        bytes.as_mut_ptr() as *mut c_void,
+        len,
+    )
 }
 
 // Local function to parse out values from the C mess
 // Assumes the c_value is complete. So cmap::get() will need to check the size
 //   and re-get before calling us with a resized buffer
 fn c_to_data(value_size: usize, c_key_type: u32, c_value: *const u8) -> Result<Data> {
-    unsafe {
-        match cmap_to_enum(c_key_type) {
-            DataType::UInt8 => {
-                let mut ints = [0u8; 1];
-                copy_nonoverlapping(c_value as *mut u8, ints.as_mut_ptr() as *mut u8, value_size);
-                Ok(Data::UInt8(ints[0]))
-            }
-            DataType::Int8 => {
-                let mut ints = [0i8; 1];
-                copy_nonoverlapping(c_value as *mut u8, ints.as_mut_ptr() as *mut u8, value_size);
-                Ok(Data::Int8(ints[0]))
-            }
-            DataType::UInt16 => {
-                let mut ints = [0u16; 1];
-                copy_nonoverlapping(c_value as *mut u8, ints.as_mut_ptr() as *mut u8, value_size);
-                Ok(Data::UInt16(ints[0]))
-            }
-            DataType::Int16 => {
-                let mut ints = [0i16; 1];
-                copy_nonoverlapping(c_value as *mut u8, ints.as_mut_ptr() as *mut u8, value_size);
-                Ok(Data::Int16(ints[0]))
-            }
-            DataType::UInt32 => {
-                let mut ints = [0u32; 1];
-                copy_nonoverlapping(c_value as *mut u8, ints.as_mut_ptr() as *mut u8, value_size);
-                Ok(Data::UInt32(ints[0]))
-            }
-            DataType::Int32 => {
-                let mut ints = [0i32; 1];
-                copy_nonoverlapping(c_value as *mut u8, ints.as_mut_ptr() as *mut u8, value_size);
-                Ok(Data::Int32(ints[0]))
-            }
-            DataType::UInt64 => {
-                let mut ints = [0u64; 1];
-                copy_nonoverlapping(c_value as *mut u8, ints.as_mut_ptr() as *mut u8, value_size);
-                Ok(Data::UInt64(ints[0]))
-            }
-            DataType::Int64 => {
-                let mut ints = [0i64; 1];
-                copy_nonoverlapping(c_value as *mut u8, ints.as_mut_ptr() as *mut u8, value_size);
-                Ok(Data::Int64(ints[0]))
-            }
-            DataType::Float => {
-                let mut ints = [0f32; 1];
-                copy_nonoverlapping(c_value as *mut u8, ints.as_mut_ptr() as *mut u8, value_size);
-                Ok(Data::Float(ints[0]))
-            }
-            DataType::Double => {
-                let mut ints = [0f64; 1];
-                copy_nonoverlapping(c_value as *mut u8, ints.as_mut_ptr() as *mut u8, value_size);
-                Ok(Data::Double(ints[0]))
-            }
+    let bytes = if c_value.is_null() || value_size == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(c_value, value_size) }
+    };
+    Data::from_bytes(cmap_to_enum(c_key_type), bytes)
+}
+
+// Numbers shorter than their type are zero-filled, as corosync does
+fn ne_bytes<const N: usize>(bytes: &[u8]) -> [u8; N] {
+    let mut b = [0u8; N];
+    let len = std::cmp::min(N, bytes.len());
+    b[..len].copy_from_slice(&bytes[..len]);
+    b
+}
+
+impl Data {
+    /// Convert the raw bytes of a cmap value, as returned by [get_into], into a [Data]
+    pub fn from_bytes(dtype: DataType, bytes: &[u8]) -> Result<Data> {
+        match dtype {
+            DataType::Int8 => Ok(Data::Int8(i8::from_ne_bytes(ne_bytes(bytes)))),
+            DataType::UInt8 => Ok(Data::UInt8(u8::from_ne_bytes(ne_bytes(bytes)))),
+            DataType::Int16 => Ok(Data::Int16(i16::from_ne_bytes(ne_bytes(bytes)))),
+            DataType::UInt16 => Ok(Data::UInt16(u16::from_ne_bytes(ne_bytes(bytes)))),
+            DataType::Int32 => Ok(Data::Int32(i32::from_ne_bytes(ne_bytes(bytes)))),
+            DataType::UInt32 => Ok(Data::UInt32(u32::from_ne_bytes(ne_bytes(bytes)))),
+            DataType::Int64 => Ok(Data::Int64(i64::from_ne_bytes(ne_bytes(bytes)))),
+            DataType::UInt64 => Ok(Data::UInt64(u64::from_ne_bytes(ne_bytes(bytes)))),
+            DataType::Float => Ok(Data::Float(f32::from_ne_bytes(ne_bytes(bytes)))),
+            DataType::Double => Ok(Data::Double(f64::from_ne_bytes(ne_bytes(bytes)))),
             DataType::String => {
-                let mut ints = Vec::<u8>::new();
-                ints.resize(value_size, 0u8);
-                copy_nonoverlapping(c_value as *mut u8, ints.as_mut_ptr() as *mut u8, value_size);
-                // -1 here so CString doesn't see the NUL
-                let cs = match CString::new(&ints[0..value_size - 1_usize]) {
-                    Ok(c1) => c1,
-                    Err(_) => return Err(CsError::CsErrLibrary),
-                };
-                match cs.into_string() {
-                    Ok(s) => Ok(Data::String(s)),
+                // Strings include the NUL terminator
+                let s = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+                if s.contains(&0) {
+                    return Err(CsError::CsErrLibrary);
+                }
+                match std::str::from_utf8(s) {
+                    Ok(s) => Ok(Data::String(s.to_string())),
                     Err(_) => Err(CsError::CsErrLibrary),
                 }
             }
-            DataType::Binary => {
-                let mut ints = Vec::<u8>::new();
-                ints.resize(value_size, 0u8);
-                copy_nonoverlapping(c_value as *mut u8, ints.as_mut_ptr() as *mut u8, value_size);
-                Ok(Data::Binary(ints))
-            }
+            DataType::Binary => Ok(Data::Binary(bytes.to_vec())),
             DataType::Unknown => Ok(Data::Unknown),
         }
     }
@@ -598,50 +1043,90 @@ fn c_to_data(value_size: usize, c_key_type: u32, c_value: *const u8) -> Result<D
 
 const INITIAL_SIZE: usize = 256;
 
-/// Get a value from cmap, returned as a [Data] struct, so could be anything
-pub fn get(handle: Handle, key_name: &str) -> Result<Data> {
+/// Get a value from cmap as a Rust type. Use [Data] to get whatever type the key holds,
+/// any other type returns CsErrInvalidParam if the key is of a different type.
+pub fn get<T: CmapValue>(handle: Handle, key_name: &(impl AsRef<str> + ?Sized)) -> Result<T> {
+    let key_name = key_name.as_ref();
+    T::from_data(get_data(handle, key_name)?)
+}
+
+fn get_data(handle: Handle, key_name: &str) -> Result<Data> {
+    let mut buf = Vec::new();
+    let dtype = get_into(handle, key_name, &mut buf)?;
+    Data::from_bytes(dtype, &buf)
+}
+
+/// Get the raw bytes of a cmap value into `buf`, reusing its allocation, and return
+/// the type of the value. [Data::from_bytes] converts the bytes to a [Data].
+/// `buf` is only grown if the value doesn't fit in its capacity, so reusing one buffer
+/// for lots of reads avoids allocating each time.
+pub fn get_into(
+    handle: Handle,
+    key_name: &(impl AsRef<str> + ?Sized),
+    buf: &mut Vec<u8>,
+) -> Result<DataType> {
+    let key_name = key_name.as_ref();
     let csname = string_to_cstring_validated(key_name, CMAP_KEYNAME_MAXLENGTH)?;
-    let mut value_size: usize = 16;
-    let mut c_key_type: u32 = 0;
-    let mut c_value = Vec::<u8>::new();
+    get_raw(handle.cmap_handle, csname.as_ptr(), buf, 0)
+}
 
-    // First guess at a size for Strings and Binaries. Expand if needed
-    c_value.resize(INITIAL_SIZE, 0u8);
+// Read a value into buf. size_hint is the value size if we already know it
+// (from cmap_iter_next), otherwise we start with whatever buf can hold.
+fn get_raw(
+    cmap_handle: u64,
+    c_name: *const c_char,
+    buf: &mut Vec<u8>,
+    size_hint: usize,
+) -> Result<DataType> {
+    let size = std::cmp::max(std::cmp::max(buf.capacity(), size_hint), INITIAL_SIZE);
+    buf.resize(size, 0u8);
+    let mut c_key_type: u32 = 0;
 
-    unsafe {
-        let res = ffi::cmap_get(
-            handle.cmap_handle,
-            csname.as_ptr(),
-            c_value.as_mut_ptr() as *mut c_void,
-            &mut value_size,
-            &mut c_key_type,
-        );
+    // Goes round at most twice, the second time with a buffer of the right size
+    for _ in 0..2 {
+        let mut value_size = buf.len();
+        let res = unsafe {
+            ffi::cmap_get(
+                cmap_handle,
+                c_name,
+                buf.as_mut_ptr() as *mut c_void,
+                &mut value_size,
+                &mut c_key_type,
+            )
+        };
         if res == ffi::CS_OK {
-            if value_size > INITIAL_SIZE {
-                // Need to try again with a bigger buffer
-                c_value.resize(value_size, 0u8);
-                let res2 = ffi::cmap_get(
-                    handle.cmap_handle,
-                    csname.as_ptr(),
-                    c_value.as_mut_ptr() as *mut c_void,
-                    &mut value_size,
+            if value_size <= buf.len() {
+                buf.truncate(value_size);
+                return Ok(cmap_to_enum(c_key_type));
+            }
+            buf.resize(value_size, 0u8);
+        } else if res == ffi::CS_ERR_INVALID_PARAM {
+            // Might just mean the buffer is too small, ask for the real size
+            let mut needed = 0usize;
+            let res2 = unsafe {
+                ffi::cmap_get(
+                    cmap_handle,
+                    c_name,
+                    std::ptr::null_mut(),
+                    &mut needed,
                     &mut c_key_type,
-                );
-                if res2 != ffi::CS_OK {
-                    return Err(CsError::from_c(res2));
-                }
+                )
+            };
+            if res2 != ffi::CS_OK || needed <= buf.len() {
+                return Err(CsError::from_c(res));
             }
-
-            // Convert to Rust type and return as a Data enum
-            c_to_data(value_size, c_key_type, c_value.as_ptr())
+            buf.resize(needed, 0u8);
         } else {
-            Err(CsError::from_c(res))
+            return Err(CsError::from_c(res));
         }
     }
+    // The value grew between calls
+    Err(CsError::CsErrTryAgain)
 }
 
 /// increment the value in a cmap key (must be a numeric type)
-pub fn inc(handle: Handle, key_name: &str) -> Result<()> {
+pub fn inc(handle: Handle, key_name: &(impl AsRef<str> + ?Sized)) -> Result<()> {
+    let key_name = key_name.as_ref();
     let csname = string_to_cstring_validated(key_name, CMAP_KEYNAME_MAXLENGTH)?;
     let res = unsafe { ffi::cmap_inc(handle.cmap_handle, csname.as_ptr()) };
     if res == ffi::CS_OK {
@@ -652,7 +1137,8 @@ pub fn inc(handle: Handle, key_name: &str) -> Result<()> {
 }
 
 /// decrement the value in a cmap key (must be a numeric type)
-pub fn dec(handle: Handle, key_name: &str) -> Result<()> {
+pub fn dec(handle: Handle, key_name: &(impl AsRef<str> + ?Sized)) -> Result<()> {
+    let key_name = key_name.as_ref();
     let csname = string_to_cstring_validated(key_name, CMAP_KEYNAME_MAXLENGTH)?;
     let res = unsafe { ffi::cmap_dec(handle.cmap_handle, csname.as_ptr()) };
     if res == ffi::CS_OK {
@@ -662,6 +1148,63 @@ pub fn dec(handle: Handle, key_name: &str) -> Result<()> {
     }
 }
 
+/// Apply a [patch::Patch] of sets and deletes, in order. Every entry is validated first
+/// (key names, and that the new value has the same type as the current one unless
+/// [patch::ApplyOptions::allow_type_change] is set), and if any are invalid nothing is changed.
+/// The returned report lists what each entry did, or would do for a dry run, and whether it worked.
+/// Only a failure to read the current values returns Err.
+pub fn apply(
+    handle: Handle,
+    patch: &patch::Patch,
+    options: &patch::ApplyOptions,
+) -> Result<patch::ApplyReport> {
+    patch::apply(handle, patch, options)
+}
+
+/// Delete a key from cmap
+pub fn delete(handle: Handle, key_name: &(impl AsRef<str> + ?Sized)) -> Result<()> {
+    let key_name = key_name.as_ref();
+    let csname = string_to_cstring_validated(key_name, CMAP_KEYNAME_MAXLENGTH)?;
+    let res = unsafe { ffi::cmap_delete(handle.cmap_handle, csname.as_ptr()) };
+    if res == ffi::CS_OK {
+        Ok(())
+    } else {
+        Err(CsError::from_c(res))
+    }
+}
+
+/// Returned from [delete_prefix]
+#[derive(Debug, Default)]
+pub struct DeletedKeys {
+    /// Keys that were deleted
+    pub deleted: Vec<String>,
+    /// Keys that could not be deleted, and why
+    pub errors: Vec<(String, CsError)>,
+}
+
+/// Delete every key that starts with `prefix`. The keys are listed first and then deleted
+/// one at a time, so a failure to delete one key doesn't stop the rest being deleted.
+/// Keys that have already gone by the time we get to them are not counted as errors.
+/// Only a failure to list the keys returns Err.
+pub fn delete_prefix(handle: Handle, prefix: &(impl AsRef<str> + ?Sized)) -> Result<DeletedKeys> {
+    let prefix = prefix.as_ref();
+    // Don't delete while iterating, corosync doesn't like that
+    let keys = CmapIterStart::new(handle, prefix)?
+        .into_iter()
+        .map(|i| i.map(|i| i.key_name))
+        .collect::<Result<Vec<String>>>()?;
+
+    let mut result = DeletedKeys::default();
+    for key in keys {
+        match delete(handle, &key) {
+            Ok(()) => result.deleted.push(key),
+            Err(CsError::CsErrNotExist) => {}
+            Err(e) => result.errors.push((key, e)),
+        }
+    }
+    Ok(result)
+}
+
 // Callback for CMAP notify events from corosync, convert params to Rust and pass on.
 extern "C" fn rust_notify_fn(
     cmap_handle: ffi::cmap_handle_t,
@@ -670,108 +1213,159 @@ extern "C" fn rust_notify_fn(
     key_name: *const ::std::os::raw::c_char,
     new_value: ffi::cmap_notify_value,
     old_value: ffi::cmap_notify_value,
-    user_data: *mut ::std::os::raw::c_void,
+    _user_data: *mut ::std::os::raw::c_void,
 ) {
     // If cmap_handle doesn't match then throw away the callback.
-    if let Some(r_cmap_handle) = HANDLE_HASH.lock().unwrap().get(&cmap_handle) {
-        if let Some(h) = TRACKHANDLE_HASH.lock().unwrap().get(&cmap_track_handle) {
-            let r_keyname = match string_from_bytes(key_name, CMAP_KEYNAME_MAXLENGTH) {
-                Ok(s) => s,
-                Err(_) => return,
-            };
+    if !HANDLE_HASH.lock().unwrap().contains_key(&cmap_handle) {
+        return;
+    }
+    // Don't hold the lock while calling the closure, it might want to add or drop trackers
+    let notify_fn = match TRACKHANDLE_HASH.lock().unwrap().get(&cmap_track_handle) {
+        Some(f) => f.clone(),
+        None => return,
+    };
 
-            let r_old = match c_to_data(old_value.len, old_value.type_, old_value.data as *const u8)
-            {
-                Ok(v) => v,
-                Err(_) => return,
-            };
-            let r_new = match c_to_data(new_value.len, new_value.type_, new_value.data as *const u8)
-            {
-                Ok(v) => v,
-                Err(_) => return,
-            };
+    let r_keyname = match string_from_bytes(key_name, CMAP_KEYNAME_MAXLENGTH) {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    let r_old = match c_to_data(old_value.len, old_value.type_, old_value.data as *const u8) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+    let r_new = match c_to_data(new_value.len, new_value.type_, new_value.data as *const u8) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
 
-            if let Some(cb) = h.notify_callback.notify_fn {
-                (cb)(
-                    r_cmap_handle,
-                    h,
-                    TrackType { bits: event },
-                    &r_keyname,
-                    &r_old,
-                    &r_new,
-                    user_data as u64,
-                );
-            }
+    let mut f = notify_fn.lock().unwrap();
+    (f)(TrackType { bits: event }, &r_keyname, &r_old, &r_new);
+}
+
+/// A change reported by a tracker made with [track_channel]
+#[derive(Clone, Debug)]
+pub struct TrackEvent {
+    pub event: TrackType,
+    pub key_name: String,
+    pub old_value: Data,
+    pub new_value: Data,
+}
+
+/// A cmap tracker, returned from [track_add]. The tracker is removed when this is
+/// dropped, or explicitly with [track_delete]. There may be multiple Trackers per [Handle]
+pub struct Tracker {
+    handle: Handle,
+    track_handle: u64,
+}
+
+impl Tracker {
+    fn delete(&mut self) -> Result<()> {
+        // Remove it even if corosync complains, it won't be called again either way
+        TRACKHANDLE_HASH.lock().unwrap().remove(&self.track_handle);
+        let res = unsafe { ffi::cmap_track_delete(self.handle.cmap_handle, self.track_handle) };
+        if res == ffi::CS_OK {
+            Ok(())
+        } else {
+            Err(CsError::from_c(res))
         }
     }
 }
 
-/// Callback function called every time a tracker reports a change in a tracked value
-#[derive(Copy, Clone)]
-pub struct NotifyCallback {
-    pub notify_fn: Option<
-        fn(
-            handle: &Handle,
-            track_handle: &TrackHandle,
-            event: TrackType,
-            key_name: &str,
-            new_value: &Data,
-            old_value: &Data,
-            user_data: u64,
-        ),
-    >,
-}
-
-/// Track changes in cmap values, multiple [TrackHandle]s per [Handle] are allowed
-pub fn track_add(
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        if self.track_handle != 0 {
+            // Nowhere to report an error
+            let _ = self.delete();
+        }
+    }
+}
+
+/// Track changes in cmap values. `notify_fn` is called from [dispatch] with the
+/// event, key name, old value and new value of each change.
+pub fn track_add<F>(
     handle: Handle,
-    key_name: &str,
+    key_name: &(impl AsRef<str> + ?Sized),
     track_type: TrackType,
-    notify_callback: &NotifyCallback,
-    user_data: u64,
-) -> Result<TrackHandle> {
+    notify_fn: F,
+) -> Result<Tracker>
+where
+    F: FnMut(TrackType, &str, &Data, &Data) + Send + 'static,
+{
+    let key_name = key_name.as_ref();
     let c_name = string_to_cstring_validated(key_name, CMAP_KEYNAME_MAXLENGTH)?;
     let mut c_trackhandle = 0u64;
+
+    // Hold the lock until the closure is registered so that an event for the
+    // new tracker can't arrive before it is
+    let mut trackers = TRACKHANDLE_HASH.lock().unwrap();
     let res = unsafe {
         ffi::cmap_track_add(
             handle.cmap_handle,
             c_name.as_ptr(),
             track_type.bits,
             Some(rust_notify_fn),
-            user_data as *mut c_void,
+            std::ptr::null_mut(),
             &mut c_trackhandle,
         )
     };
     if res == ffi::CS_OK {
-        let rhandle = TrackHandle {
+        trackers.insert(c_trackhandle, Arc::new(Mutex::new(notify_fn)));
+        Ok(Tracker {
+            handle,
             track_handle: c_trackhandle,
-            notify_callback: *notify_callback,
-        };
-        TRACKHANDLE_HASH
-            .lock()
-            .unwrap()
-            .insert(c_trackhandle, rhandle);
-        Ok(rhandle)
+        })
     } else {
         Err(CsError::from_c(res))
     }
 }
 
-/// Remove a tracker frm this [Handle]
-pub fn track_delete(handle: Handle, track_handle: TrackHandle) -> Result<()> {
-    let res = unsafe { ffi::cmap_track_delete(handle.cmap_handle, track_handle.track_handle) };
-    if res == ffi::CS_OK {
-        TRACKHANDLE_HASH
-            .lock()
-            .unwrap()
-            .remove(&track_handle.track_handle);
-        Ok(())
-    } else {
-        Err(CsError::from_c(res))
-    }
+/// Track changes in cmap values, delivering them as [TrackEvent]s down a channel
+/// rather than calling a function. [dispatch] must still be called to receive events.
+/// The channel is closed when the [Tracker] is dropped.
+///
+/// When the channel already holds `bound` events, [dispatch] blocks until the receiver
+/// catches up, so nothing is lost. Events for every other tracker on the handle wait too.
+/// If the thread reading the channel is also the one calling [dispatch], or waits for
+/// anything that thread holds, a full channel hangs both of them; make `bound` bigger than
+/// the number of changes that can happen between reads.
+pub fn track_channel(
+    handle: Handle,
+    key_name: &(impl AsRef<str> + ?Sized),
+    track_type: TrackType,
+    bound: usize,
+) -> Result<(Tracker, Receiver<TrackEvent>)> {
+    let key_name = key_name.as_ref();
+    let (tx, rx) = sync_channel(bound);
+    let tracker = track_add(
+        handle,
+        key_name,
+        track_type,
+        move |event, key_name, old, new| {
+            // If the receiver has gone then there's nobody to tell
+            let _ = tx.send(TrackEvent {
+                event,
+                key_name: key_name.to_string(),
+                old_value: old.clone(),
+                new_value: new.clone(),
+            });
+        },
+    )?;
+    Ok((tracker, rx))
+}
+
+/// Remove a tracker from its [Handle], returning any error from corosync.
+/// Dropping the [Tracker] does the same but ignores errors.
+pub fn track_delete(mut tracker: Tracker) -> Result<()> {
+    let res = tracker.delete();
+    // Stop Drop doing it again
+    tracker.track_handle = 0;
+    res
 }
 
 /// Create one of these to start iterating over cmap values.
+/// Each item is a Result, as errors can occur part way through iterating.
+/// The iteration is finished with corosync when this (or the iterator made from it) is dropped,
+/// so it is fine to stop early.
 pub struct CmapIterStart {
     iter_handle: u64,
     cmap_handle: u64,
@@ -780,6 +1374,9 @@ pub struct CmapIterStart {
 pub struct CmapIntoIter {
     cmap_handle: u64,
     iter_handle: u64,
+    finished: bool,
+    // Reused for every value rather than allocating one per key
+    buf: Vec<u8>,
 }
 
 /// Value returned from the iterator. contains the key name and the [Data]
@@ -803,10 +1400,30 @@ impl fmt::Debug for CmapIter {
     }
 }
 
-impl Iterator for CmapIntoIter {
-    type Item = CmapIter;
+impl CmapIntoIter {
+    fn finish(&mut self) {
+        if !self.finished {
+            self.finished = true;
+            unsafe {
+                // Yeah, we don't check this return code. There's nowhere to report it.
+                ffi::cmap_iter_finalize(self.cmap_handle, self.iter_handle)
+            };
+        }
+    }
+
+    // Stop iterating and return the error
+    fn fail(&mut self, err: CsError) -> Option<Result<CmapIter>> {
+        self.finish();
+        Some(Err(err))
+    }
+}
 
-    fn next(&mut self) -> Option<CmapIter> {
+impl CmapIntoIter {
+    // Move on to the next key, returning its name, type and size
+    fn next_key(&mut self) -> Option<Result<([u8; CMAP_KEYNAME_MAXLENGTH + 1], u32, usize)>> {
+        if self.finished {
+            return None;
+        }
         let mut c_key_name = [0u8; CMAP_KEYNAME_MAXLENGTH + 1];
         let mut c_value_len = 0usize;
         let mut c_value_type = 0u32;
@@ -820,55 +1437,133 @@ impl Iterator for CmapIntoIter {
             )
         };
         if res == ffi::CS_OK {
-            // Return the Data for this iteration
-            let mut c_value = Vec::<u8>::new();
-            c_value.resize(c_value_len, 0u8);
-            let res = unsafe {
-                ffi::cmap_get(
-                    self.cmap_handle,
-                    c_key_name.as_ptr() as *mut c_char,
-                    c_value.as_mut_ptr() as *mut c_void,
-                    &mut c_value_len,
-                    &mut c_value_type,
-                )
-            };
-            if res == ffi::CS_OK {
-                match c_to_data(c_value_len, c_value_type, c_value.as_ptr()) {
-                    Ok(d) => {
-                        let r_keyname = match string_from_bytes(
-                            c_key_name.as_ptr() as *mut c_char,
-                            CMAP_KEYNAME_MAXLENGTH,
-                        ) {
-                            Ok(s) => s,
-                            Err(_) => return None,
-                        };
-                        Some(CmapIter {
-                            key_name: r_keyname,
-                            data: d,
-                        })
-                    }
-                    Err(_) => None,
-                }
-            } else {
-                // cmap_get returned error
-                None
-            }
+            Some(Ok((c_key_name, c_value_type, c_value_len)))
         } else if res == ffi::CS_ERR_NO_SECTIONS {
             // End of list
-            unsafe {
-                // Yeah, we don't check this return code. There's nowhere to report it.
-                ffi::cmap_iter_finalize(self.cmap_handle, self.iter_handle)
-            };
+            self.finish();
             None
         } else {
-            None
+            self.finish();
+            Some(Err(CsError::from_c(res)))
+        }
+    }
+}
+
+impl Iterator for CmapIntoIter {
+    type Item = Result<CmapIter>;
+
+    fn next(&mut self) -> Option<Result<CmapIter>> {
+        let (c_key_name, _, c_value_len) = match self.next_key()? {
+            Ok(k) => k,
+            Err(e) => return Some(Err(e)),
+        };
+        // Return the Data for this iteration
+        let dtype = match get_raw(
+            self.cmap_handle,
+            c_key_name.as_ptr() as *const c_char,
+            &mut self.buf,
+            c_value_len,
+        ) {
+            Ok(t) => t,
+            Err(e) => return self.fail(e),
+        };
+        let d = match Data::from_bytes(dtype, &self.buf) {
+            Ok(d) => d,
+            Err(e) => return self.fail(e),
+        };
+        match string_from_bytes(c_key_name.as_ptr() as *mut c_char, CMAP_KEYNAME_MAXLENGTH) {
+            Ok(r_keyname) => Some(Ok(CmapIter {
+                key_name: r_keyname,
+                data: d,
+            })),
+            Err(e) => self.fail(e),
         }
     }
 }
 
+/// Iterator over cmap keys that doesn't fetch the values, made with [CmapIterStart::keys]
+pub struct CmapKeys {
+    inner: CmapIntoIter,
+}
+
+/// A key returned from [CmapKeys]. The value is only read from cmap when
+/// [CmapKey::value] or [CmapKey::value_into] is called, so it could have changed
+/// (or gone) since the key was listed.
+pub struct CmapKey {
+    cmap_handle: u64,
+    key_name: String,
+    data_type: DataType,
+    value_len: usize,
+}
+
+impl CmapKey {
+    pub fn key_name(&self) -> &str {
+        &self.key_name
+    }
+
+    /// Type of the value when the key was listed
+    pub fn data_type(&self) -> DataType {
+        self.data_type
+    }
+
+    /// Size of the value in bytes when the key was listed
+    pub fn value_len(&self) -> usize {
+        self.value_len
+    }
+
+    /// Read the value
+    pub fn value(&self) -> Result<Data> {
+        let mut buf = Vec::new();
+        let dtype = self.value_into(&mut buf)?;
+        Data::from_bytes(dtype, &buf)
+    }
+
+    /// Read the raw value into `buf`, like [get_into]
+    pub fn value_into(&self, buf: &mut Vec<u8>) -> Result<DataType> {
+        let csname = string_to_cstring_validated(&self.key_name, CMAP_KEYNAME_MAXLENGTH)?;
+        get_raw(self.cmap_handle, csname.as_ptr(), buf, self.value_len)
+    }
+}
+
+impl fmt::Debug for CmapKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.key_name, self.data_type)
+    }
+}
+
+impl Iterator for CmapKeys {
+    type Item = Result<CmapKey>;
+
+    fn next(&mut self) -> Option<Result<CmapKey>> {
+        let (c_key_name, c_value_type, c_value_len) = match self.inner.next_key()? {
+            Ok(k) => k,
+            Err(e) => return Some(Err(e)),
+        };
+        match string_from_bytes(c_key_name.as_ptr() as *mut c_char, CMAP_KEYNAME_MAXLENGTH) {
+            Ok(key_name) => Some(Ok(CmapKey {
+                cmap_handle: self.inner.cmap_handle,
+                key_name,
+                data_type: cmap_to_enum(c_value_type),
+                value_len: c_value_len,
+            })),
+            Err(e) => {
+                self.inner.finish();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl Drop for CmapIntoIter {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
 impl CmapIterStart {
     /// Create a new [CmapIterStart] object for iterating over a list of cmap keys
-    pub fn new(cmap_handle: Handle, prefix: &str) -> Result<CmapIterStart> {
+    pub fn new(cmap_handle: Handle, prefix: &(impl AsRef<str> + ?Sized)) -> Result<CmapIterStart> {
+        let prefix = prefix.as_ref();
         let mut iter_handle: u64 = 0;
         let res = unsafe {
             let c_prefix = string_to_cstring_validated(prefix, CMAP_KEYNAME_MAXLENGTH)?;
@@ -883,16 +1578,41 @@ impl CmapIterStart {
             Err(CsError::from_c(res))
         }
     }
+
+    /// Iterate over everything, returning the first error if there is one
+    pub fn collect_all(self) -> Result<Vec<CmapIter>> {
+        self.into_iter().collect()
+    }
+
+    /// Iterate over the keys only, values are fetched when asked for with [CmapKey::value]
+    pub fn keys(self) -> CmapKeys {
+        CmapKeys {
+            inner: self.into_iter(),
+        }
+    }
+}
+
+impl Drop for CmapIterStart {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::cmap_iter_finalize(self.cmap_handle, self.iter_handle);
+        }
+    }
 }
 
 impl IntoIterator for CmapIterStart {
-    type Item = CmapIter;
+    type Item = Result<CmapIter>;
     type IntoIter = CmapIntoIter;
 
     fn into_iter(self) -> Self::IntoIter {
+        let (iter_handle, cmap_handle) = (self.iter_handle, self.cmap_handle);
+        // The CmapIntoIter takes over finalizing the iteration
+        std::mem::forget(self);
         CmapIntoIter {
-            iter_handle: self.iter_handle,
-            cmap_handle: self.cmap_handle,
+            iter_handle,
+            cmap_handle,
+            finished: false,
+            buf: Vec::new(),
         }
     }
 }
@@ -0,0 +1,473 @@
+// Typed view of the corosync configuration
+// Copyright (c) 2021 Red Hat, Inc.
+//
+// All rights reserved.
+//
+// Author: Christine Caulfield (ccaulfi@redhat.com)
+//
+
+//! corosync loads corosync.conf into the icmap map, under `totem.`, `nodelist.`, `quorum.`
+//! and `logging.`. The structs here read those keys into something easier to use than
+//! a pile of strings.
+//!
+//! Nearly everything in corosync.conf is optional, so most fields are Options. Keys that
+//! aren't known here, or that have a value that can't be understood as the expected type,
+//! are not an error; they are kept in the `other` map of the struct they were found in,
+//! with the struct's prefix taken off the key name. Numbers are accepted as any integer
+//! type that fits or as a string, and yes/no settings as a number, yes/no, on/off or true/false,
+//! as corosync itself does.
+//!
+//! Each struct can be read straight from cmap or built from a
+//! [crate::cmap::snapshot::CmapSnapshot], which makes it possible to look at the
+//! configuration in a snapshot attached to a bug report.
+
+use std::collections::BTreeMap;
+
+use crate::cmap::snapshot::CmapSnapshot;
+use crate::cmap::{Data, Handle};
+use crate::Result;
+
+/// The `totem.` section
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TotemConfig {
+    pub cluster_name: Option<String>,
+    pub version: Option<u32>,
+    pub transport: Option<String>,
+    pub ip_version: Option<String>,
+    pub token: Option<u32>,
+    pub token_retransmits_before_loss_const: Option<u32>,
+    pub consensus: Option<u32>,
+    pub join: Option<u32>,
+    pub crypto_cipher: Option<String>,
+    pub crypto_hash: Option<String>,
+    /// `totem.interface.N.` sections by link number
+    pub interfaces: BTreeMap<u32, InterfaceConfig>,
+    pub other: BTreeMap<String, Data>,
+}
+
+/// A `totem.interface.N.` section
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InterfaceConfig {
+    pub bindnetaddr: Option<String>,
+    pub mcastaddr: Option<String>,
+    pub mcastport: Option<u16>,
+    pub ttl: Option<u8>,
+    pub knet_link_priority: Option<u8>,
+    pub knet_transport: Option<String>,
+    pub other: BTreeMap<String, Data>,
+}
+
+/// The `nodelist.` section
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Nodelist {
+    /// The nodes in the order they appear in corosync.conf
+    pub nodes: Vec<Node>,
+    /// The N in this node's `nodelist.node.N.` keys, if corosync has worked it out.
+    /// This is [Node::pos], not an index into `nodes`, which can differ if there are gaps
+    pub local_node_pos: Option<u32>,
+    pub other: BTreeMap<String, Data>,
+}
+
+/// A `nodelist.node.N.` section
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Node {
+    /// The N in `nodelist.node.N.`
+    pub pos: u32,
+    pub nodeid: Option<u32>,
+    pub name: Option<String>,
+    /// The `ringX_addr` addresses, by link number
+    pub ring_addrs: BTreeMap<u32, String>,
+    pub other: BTreeMap<String, Data>,
+}
+
+/// The `quorum.` section
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QuorumConfig {
+    pub provider: Option<String>,
+    pub expected_votes: Option<u32>,
+    pub votes: Option<u32>,
+    pub two_node: Option<bool>,
+    pub wait_for_all: Option<bool>,
+    pub last_man_standing: Option<bool>,
+    pub last_man_standing_window: Option<u32>,
+    pub auto_tie_breaker: Option<bool>,
+    pub auto_tie_breaker_node: Option<String>,
+    pub allow_downscale: Option<bool>,
+    pub other: BTreeMap<String, Data>,
+}
+
+/// The `logging.` section
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoggingConfig {
+    pub to_syslog: Option<bool>,
+    pub to_stderr: Option<bool>,
+    pub to_logfile: Option<bool>,
+    pub logfile: Option<String>,
+    pub syslog_facility: Option<String>,
+    pub syslog_priority: Option<String>,
+    pub logfile_priority: Option<String>,
+    /// off, on or trace
+    pub debug: Option<String>,
+    /// off, on or hires
+    pub timestamp: Option<String>,
+    pub fileline: Option<bool>,
+    pub function_name: Option<bool>,
+    /// `logging.logger_subsys.N.` sections, in order
+    pub logger_subsys: Vec<LoggerSubsysConfig>,
+    pub other: BTreeMap<String, Data>,
+}
+
+/// A `logging.logger_subsys.N.` section
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoggerSubsysConfig {
+    pub subsys: Option<String>,
+    pub debug: Option<String>,
+    pub other: BTreeMap<String, Data>,
+}
+
+/// All of the above
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    pub totem: TotemConfig,
+    pub nodelist: Nodelist,
+    pub quorum: QuorumConfig,
+    pub logging: LoggingConfig,
+}
+
+fn as_int<T: TryFrom<i128>>(data: &Data) -> Option<T> {
+    let n: i128 = match data {
+        Data::Int8(v) => (*v).into(),
+        Data::UInt8(v) => (*v).into(),
+        Data::Int16(v) => (*v).into(),
+        Data::UInt16(v) => (*v).into(),
+        Data::Int32(v) => (*v).into(),
+        Data::UInt32(v) => (*v).into(),
+        Data::Int64(v) => (*v).into(),
+        Data::UInt64(v) => (*v).into(),
+        Data::String(s) => s.trim().parse().ok()?,
+        _ => return None,
+    };
+    T::try_from(n).ok()
+}
+
+fn as_bool(data: &Data) -> Option<bool> {
+    if let Some(s) = data.as_str() {
+        return match s.trim().to_ascii_lowercase().as_str() {
+            "yes" | "on" | "true" | "1" => Some(true),
+            "no" | "off" | "false" | "0" => Some(false),
+            _ => None,
+        };
+    }
+    as_int::<i128>(data).map(|n| n != 0)
+}
+
+fn as_string(data: &Data) -> Option<String> {
+    data.as_str().map(|s| s.to_string())
+}
+
+// Set a field if the value could be converted, otherwise keep it with the unknown keys
+fn set_field<T>(
+    field: &mut Option<T>,
+    value: Option<T>,
+    key: &str,
+    data: &Data,
+    other: &mut BTreeMap<String, Data>,
+) {
+    match value {
+        Some(v) => *field = Some(v),
+        None => {
+            other.insert(key.to_string(), data.clone());
+        }
+    }
+}
+
+// The keys under `prefix.`, with the prefix taken off
+fn section<'a>(
+    snapshot: &'a CmapSnapshot,
+    prefix: &'a str,
+) -> impl Iterator<Item = (&'a str, &'a Data)> {
+    snapshot.iter().filter_map(move |(k, v)| {
+        k.strip_prefix(prefix)
+            .and_then(|k| k.strip_prefix('.'))
+            .map(|k| (k, v))
+    })
+}
+
+// Split `N.rest` into N and rest
+fn split_index(key: &str) -> Option<(u32, &str)> {
+    let (n, rest) = key.split_once('.')?;
+    Some((n.parse().ok()?, rest))
+}
+
+impl InterfaceConfig {
+    fn set(&mut self, key: &str, data: &Data) {
+        let other = &mut self.other;
+        match key {
+            "bindnetaddr" => set_field(&mut self.bindnetaddr, as_string(data), key, data, other),
+            "mcastaddr" => set_field(&mut self.mcastaddr, as_string(data), key, data, other),
+            "mcastport" => set_field(&mut self.mcastport, as_int(data), key, data, other),
+            "ttl" => set_field(&mut self.ttl, as_int(data), key, data, other),
+            "knet_link_priority" => {
+                set_field(&mut self.knet_link_priority, as_int(data), key, data, other)
+            }
+            "knet_transport" => {
+                set_field(&mut self.knet_transport, as_string(data), key, data, other)
+            }
+            _ => {
+                other.insert(key.to_string(), data.clone());
+            }
+        }
+    }
+}
+
+impl TotemConfig {
+    /// Read the `totem.` keys from cmap
+    pub fn read(handle: Handle) -> Result<TotemConfig> {
+        Ok(TotemConfig::from_snapshot(&CmapSnapshot::capture(
+            handle, "totem.",
+        )?))
+    }
+
+    /// Build from the `totem.` keys in a snapshot
+    pub fn from_snapshot(snapshot: &CmapSnapshot) -> TotemConfig {
+        let mut c = TotemConfig::default();
+        for (key, data) in section(snapshot, "totem") {
+            if let Some((n, rest)) = key.strip_prefix("interface.").and_then(split_index) {
+                c.interfaces.entry(n).or_default().set(rest, data);
+                continue;
+            }
+            let other = &mut c.other;
+            match key {
+                "cluster_name" => set_field(&mut c.cluster_name, as_string(data), key, data, other),
+                "version" => set_field(&mut c.version, as_int(data), key, data, other),
+                "transport" => set_field(&mut c.transport, as_string(data), key, data, other),
+                "ip_version" => set_field(&mut c.ip_version, as_string(data), key, data, other),
+                "token" => set_field(&mut c.token, as_int(data), key, data, other),
+                "token_retransmits_before_loss_const" => set_field(
+                    &mut c.token_retransmits_before_loss_const,
+                    as_int(data),
+                    key,
+                    data,
+                    other,
+                ),
+                "consensus" => set_field(&mut c.consensus, as_int(data), key, data, other),
+                "join" => set_field(&mut c.join, as_int(data), key, data, other),
+                "crypto_cipher" => {
+                    set_field(&mut c.crypto_cipher, as_string(data), key, data, other)
+                }
+                "crypto_hash" => set_field(&mut c.crypto_hash, as_string(data), key, data, other),
+                _ => {
+                    other.insert(key.to_string(), data.clone());
+                }
+            }
+        }
+        c
+    }
+}
+
+impl Node {
+    fn set(&mut self, key: &str, data: &Data) {
+        // ringX_addr
+        let ring = key
+            .strip_prefix("ring")
+            .and_then(|k| k.strip_suffix("_addr"))
+            .and_then(|n| n.parse::<u32>().ok());
+        let other = &mut self.other;
+        match (key, ring) {
+            (_, Some(n)) => match as_string(data) {
+                Some(addr) => {
+                    self.ring_addrs.insert(n, addr);
+                }
+                None => {
+                    other.insert(key.to_string(), data.clone());
+                }
+            },
+            ("nodeid", _) => set_field(&mut self.nodeid, as_int(data), key, data, other),
+            ("name", _) => set_field(&mut self.name, as_string(data), key, data, other),
+            _ => {
+                other.insert(key.to_string(), data.clone());
+            }
+        }
+    }
+}
+
+impl Nodelist {
+    /// Read the `nodelist.` keys from cmap
+    pub fn read(handle: Handle) -> Result<Nodelist> {
+        Ok(Nodelist::from_snapshot(&CmapSnapshot::capture(
+            handle,
+            "nodelist.",
+        )?))
+    }
+
+    /// Build from the `nodelist.` keys in a snapshot
+    pub fn from_snapshot(snapshot: &CmapSnapshot) -> Nodelist {
+        let mut c = Nodelist::default();
+        let mut nodes: BTreeMap<u32, Node> = BTreeMap::new();
+        for (key, data) in section(snapshot, "nodelist") {
+            if let Some((n, rest)) = key.strip_prefix("node.").and_then(split_index) {
+                nodes.entry(n).or_default().set(rest, data);
+                continue;
+            }
+            match key {
+                "local_node_pos" => {
+                    set_field(&mut c.local_node_pos, as_int(data), key, data, &mut c.other)
+                }
+                _ => {
+                    c.other.insert(key.to_string(), data.clone());
+                }
+            }
+        }
+        c.nodes = nodes
+            .into_iter()
+            .map(|(pos, node)| Node { pos, ..node })
+            .collect();
+        c
+    }
+
+    /// The node with this nodeid
+    pub fn node(&self, nodeid: u32) -> Option<&Node> {
+        self.nodes.iter().find(|n| n.nodeid == Some(nodeid))
+    }
+
+    /// The local node, if corosync has said which one it is
+    pub fn local_node(&self) -> Option<&Node> {
+        let pos = self.local_node_pos?;
+        self.nodes.iter().find(|n| n.pos == pos)
+    }
+}
+
+impl QuorumConfig {
+    /// Read the `quorum.` keys from cmap
+    pub fn read(handle: Handle) -> Result<QuorumConfig> {
+        Ok(QuorumConfig::from_snapshot(&CmapSnapshot::capture(
+            handle, "quorum.",
+        )?))
+    }
+
+    /// Build from the `quorum.` keys in a snapshot
+    pub fn from_snapshot(snapshot: &CmapSnapshot) -> QuorumConfig {
+        let mut c = QuorumConfig::default();
+        for (key, data) in section(snapshot, "quorum") {
+            let other = &mut c.other;
+            match key {
+                "provider" => set_field(&mut c.provider, as_string(data), key, data, other),
+                "expected_votes" => {
+                    set_field(&mut c.expected_votes, as_int(data), key, data, other)
+                }
+                "votes" => set_field(&mut c.votes, as_int(data), key, data, other),
+                "two_node" => set_field(&mut c.two_node, as_bool(data), key, data, other),
+                "wait_for_all" => set_field(&mut c.wait_for_all, as_bool(data), key, data, other),
+                "last_man_standing" => {
+                    set_field(&mut c.last_man_standing, as_bool(data), key, data, other)
+                }
+                "last_man_standing_window" => set_field(
+                    &mut c.last_man_standing_window,
+                    as_int(data),
+                    key,
+                    data,
+                    other,
+                ),
+                "auto_tie_breaker" => {
+                    set_field(&mut c.auto_tie_breaker, as_bool(data), key, data, other)
+                }
+                // Can be a list of nodeids or "lowest"/"highest", so left as a string
+                "auto_tie_breaker_node" => set_field(
+                    &mut c.auto_tie_breaker_node,
+                    as_string(data),
+                    key,
+                    data,
+                    other,
+                ),
+                "allow_downscale" => {
+                    set_field(&mut c.allow_downscale, as_bool(data), key, data, other)
+                }
+                _ => {
+                    other.insert(key.to_string(), data.clone());
+                }
+            }
+        }
+        c
+    }
+}
+
+impl LoggerSubsysConfig {
+    fn set(&mut self, key: &str, data: &Data) {
+        let other = &mut self.other;
+        match key {
+            "subsys" => set_field(&mut self.subsys, as_string(data), key, data, other),
+            "debug" => set_field(&mut self.debug, as_string(data), key, data, other),
+            _ => {
+                other.insert(key.to_string(), data.clone());
+            }
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// Read the `logging.` keys from cmap
+    pub fn read(handle: Handle) -> Result<LoggingConfig> {
+        Ok(LoggingConfig::from_snapshot(&CmapSnapshot::capture(
+            handle, "logging.",
+        )?))
+    }
+
+    /// Build from the `logging.` keys in a snapshot
+    pub fn from_snapshot(snapshot: &CmapSnapshot) -> LoggingConfig {
+        let mut c = LoggingConfig::default();
+        let mut subsys: BTreeMap<u32, LoggerSubsysConfig> = BTreeMap::new();
+        for (key, data) in section(snapshot, "logging") {
+            if let Some((n, rest)) = key.strip_prefix("logger_subsys.").and_then(split_index) {
+                subsys.entry(n).or_default().set(rest, data);
+                continue;
+            }
+            let other = &mut c.other;
+            match key {
+                "to_syslog" => set_field(&mut c.to_syslog, as_bool(data), key, data, other),
+                "to_stderr" => set_field(&mut c.to_stderr, as_bool(data), key, data, other),
+                "to_logfile" => set_field(&mut c.to_logfile, as_bool(data), key, data, other),
+                "logfile" => set_field(&mut c.logfile, as_string(data), key, data, other),
+                "syslog_facility" => {
+                    set_field(&mut c.syslog_facility, as_string(data), key, data, other)
+                }
+                "syslog_priority" => {
+                    set_field(&mut c.syslog_priority, as_string(data), key, data, other)
+                }
+                "logfile_priority" => {
+                    set_field(&mut c.logfile_priority, as_string(data), key, data, other)
+                }
+                "debug" => set_field(&mut c.debug, as_string(data), key, data, other),
+                "timestamp" => set_field(&mut c.timestamp, as_string(data), key, data, other),
+                "fileline" => set_field(&mut c.fileline, as_bool(data), key, data, other),
+                "function_name" => set_field(&mut c.function_name, as_bool(data), key, data, other),
+                _ => {
+                    other.insert(key.to_string(), data.clone());
+                }
+            }
+        }
+        c.logger_subsys = subsys.into_values().collect();
+        c
+    }
+}
+
+impl Config {
+    /// Read all of the configuration sections from cmap
+    pub fn read(handle: Handle) -> Result<Config> {
+        Ok(Config {
+            totem: TotemConfig::read(handle)?,
+            nodelist: Nodelist::read(handle)?,
+            quorum: QuorumConfig::read(handle)?,
+            logging: LoggingConfig::read(handle)?,
+        })
+    }
+
+    /// Build from a snapshot, keys outside the configuration sections are ignored
+    pub fn from_snapshot(snapshot: &CmapSnapshot) -> Config {
+        Config {
+            totem: TotemConfig::from_snapshot(snapshot),
+            nodelist: Nodelist::from_snapshot(snapshot),
+            quorum: QuorumConfig::from_snapshot(snapshot),
+            logging: LoggingConfig::from_snapshot(snapshot),
+        }
+    }
+}
@@ -0,0 +1,198 @@
+// In-process copy of a cmap subtree
+// Copyright (c) 2021 Red Hat, Inc.
+//
+// All rights reserved.
+//
+// Author: Christine Caulfield (ccaulfi@redhat.com)
+//
+
+//! A [crate::cmap::mirror::CmapMirror] loads every key under a prefix and then keeps its copy
+//! up to date with a prefix tracker, so code that looks at `nodelist.*` or `quorum.*` all the
+//! time can read it without going to corosync each time.
+//!
+//! The copy is published as an [std::sync::Arc] that is replaced on every change, so reads
+//! never wait for IPC or for an update to finish: they only hold a lock for long enough to
+//! clone the pointer, and [crate::cmap::mirror::CmapMirror::snapshot] costs no more than
+//! [crate::cmap::mirror::CmapMirror::get]. The price is paid by updates, each of which copies
+//! the whole subtree, so a mirror suits subtrees that are read far more often than they
+//! change, like `nodelist.` or `quorum.`.
+//!
+//! Like any tracker, updates only arrive when [crate::cmap::dispatch] is called on the handle.
+//! If notifications might have been lost (eg the dispatch thread fell over)
+//! [crate::cmap::mirror::CmapMirror::resync] reloads the whole subtree and tells subscribers
+//! what was different.
+
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::cmap::snapshot::CmapSnapshot;
+use crate::cmap::{track_add, Data, Handle, TrackEvent, TrackType, Tracker};
+use crate::Result;
+
+struct MirrorInner {
+    // Never changed in place, only replaced
+    map: RwLock<Arc<BTreeMap<String, Data>>>,
+    // While resyncing, events are saved here as well so they can be
+    // replayed on top of the freshly loaded copy. Held while the map is
+    // being updated, so that updates happen one at a time
+    pending: Mutex<Option<Vec<TrackEvent>>>,
+    subscribers: Mutex<Vec<Sender<TrackEvent>>>,
+}
+
+impl MirrorInner {
+    fn notify(&self, ev: TrackEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|s| s.send(ev.clone()).is_ok());
+    }
+
+    fn tracker_event(&self, ev: TrackEvent) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(p) = pending.as_mut() {
+            p.push(ev.clone());
+        }
+        let current = self.map.read().unwrap().clone();
+        let mut map = (*current).clone();
+        apply_event(&mut map, &ev);
+        *self.map.write().unwrap() = Arc::new(map);
+        drop(pending);
+        self.notify(ev);
+    }
+}
+
+fn apply_event(map: &mut BTreeMap<String, Data>, ev: &TrackEvent) {
+    if ev.event.contains(TrackType::DELETE) {
+        map.remove(&ev.key_name);
+    } else {
+        map.insert(ev.key_name.clone(), ev.new_value.clone());
+    }
+}
+
+/// A copy of all the cmap keys under a prefix, kept up to date by a tracker
+pub struct CmapMirror {
+    handle: Handle,
+    prefix: String,
+    inner: Arc<MirrorInner>,
+    // Only here to be dropped with the mirror
+    _tracker: Tracker,
+}
+
+impl CmapMirror {
+    /// Start mirroring every key under `prefix` ("" for the whole map)
+    pub fn new(handle: Handle, prefix: &(impl AsRef<str> + ?Sized)) -> Result<CmapMirror> {
+        let prefix = prefix.as_ref();
+        let inner = Arc::new(MirrorInner {
+            map: RwLock::new(Arc::new(BTreeMap::new())),
+            pending: Mutex::new(None),
+            subscribers: Mutex::new(Vec::new()),
+        });
+
+        // Track before loading so nothing can change unseen in between
+        let track_inner = inner.clone();
+        let tracker = track_add(
+            handle,
+            prefix,
+            TrackType::ADD | TrackType::MODIFY | TrackType::DELETE | TrackType::PREFIX,
+            move |event, key_name, old, new| {
+                track_inner.tracker_event(TrackEvent {
+                    event,
+                    key_name: key_name.to_string(),
+                    old_value: old.clone(),
+                    new_value: new.clone(),
+                })
+            },
+        )?;
+        let mirror = CmapMirror {
+            handle,
+            prefix: prefix.to_string(),
+            inner,
+            _tracker: tracker,
+        };
+        mirror.load()?;
+        Ok(mirror)
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// The current value of a key, if it exists
+    pub fn get(&self, key_name: &(impl AsRef<str> + ?Sized)) -> Option<Data> {
+        let key_name = key_name.as_ref();
+        self.snapshot().get(key_name).cloned()
+    }
+
+    /// The whole subtree as it is now. This doesn't change as the mirror is updated,
+    /// call it again to see later changes.
+    pub fn snapshot(&self) -> Arc<BTreeMap<String, Data>> {
+        self.inner.map.read().unwrap().clone()
+    }
+
+    /// Returns a channel that receives every change made to the mirror, after it has been made.
+    /// Changes found by [CmapMirror::resync] are reported as ADD, MODIFY or DELETE events with
+    /// Data::Unknown for the missing old or new value.
+    pub fn subscribe(&self) -> Receiver<TrackEvent> {
+        let (tx, rx) = channel();
+        self.inner.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Reload the whole subtree from cmap, in case tracker notifications have been lost
+    pub fn resync(&self) -> Result<()> {
+        self.load()
+    }
+
+    fn load(&self) -> Result<()> {
+        *self.inner.pending.lock().unwrap() = Some(Vec::new());
+        let mut fresh: BTreeMap<String, Data> =
+            match CmapSnapshot::capture(self.handle, &self.prefix) {
+                Ok(s) => s.into(),
+                Err(e) => {
+                    *self.inner.pending.lock().unwrap() = None;
+                    return Err(e);
+                }
+            };
+
+        // Anything that changed while we were loading happened after (or while)
+        // we read it, so replaying it on top gives the current state. Those events
+        // have already been applied to the old copy and sent to subscribers, so
+        // they don't show up again in the diff.
+        let mut pending = self.inner.pending.lock().unwrap();
+        for ev in pending.take().unwrap_or_default() {
+            apply_event(&mut fresh, &ev);
+        }
+        let new = CmapSnapshot::from(fresh.clone());
+        let old = std::mem::replace(&mut *self.inner.map.write().unwrap(), Arc::new(fresh));
+        drop(pending);
+
+        let old = CmapSnapshot::from(Arc::try_unwrap(old).unwrap_or_else(|m| (*m).clone()));
+        let diff = old.diff(&new);
+        for (key_name, new_value) in diff.added {
+            self.inner.notify(TrackEvent {
+                event: TrackType::ADD,
+                key_name,
+                old_value: Data::Unknown,
+                new_value,
+            });
+        }
+        for (key_name, (old_value, new_value)) in diff.changed {
+            self.inner.notify(TrackEvent {
+                event: TrackType::MODIFY,
+                key_name,
+                old_value,
+                new_value,
+            });
+        }
+        for (key_name, old_value) in diff.removed {
+            self.inner.notify(TrackEvent {
+                event: TrackType::DELETE,
+                key_name,
+                old_value,
+                new_value: Data::Unknown,
+            });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+// Pushing a set of changes into cmap
+// Copyright (c) 2021 Red Hat, Inc.
+//
+// All rights reserved.
+//
+// Author: Christine Caulfield (ccaulfi@redhat.com)
+//
+
+//! A [crate::cmap::patch::Patch] is an ordered list of keys to set and delete, applied with
+//! [crate::cmap::apply]. Before anything is changed every entry is checked against what is
+//! in cmap at the time, so a bad key name or a value of the wrong type stops the whole patch
+//! rather than leaving it half applied. With [crate::cmap::patch::ApplyOptions::dry_run] set
+//! nothing is changed at all and the report shows what would have been done.
+//!
+//! A patch can be made from a [crate::cmap::snapshot::CmapSnapshot], to put all of its keys
+//! back, or from a [crate::cmap::snapshot::SnapshotDiff] between the current contents of
+//! cmap and a desired snapshot.
+
+use std::collections::HashMap;
+
+use crate::cmap::snapshot::{CmapSnapshot, SnapshotDiff};
+use crate::cmap::{delete, get_data, set_data, validate_key_name, Data, Handle};
+use crate::{CsError, Result};
+
+/// One entry in a [Patch]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    Set(Data),
+    Delete,
+}
+
+/// An ordered list of changes to cmap keys
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Patch {
+    changes: Vec<(String, Change)>,
+}
+
+impl Patch {
+    pub fn new() -> Patch {
+        Patch::default()
+    }
+
+    /// Add a key to set
+    pub fn set<D: Into<Data>>(
+        &mut self,
+        key_name: &(impl AsRef<str> + ?Sized),
+        value: D,
+    ) -> &mut Patch {
+        let key_name = key_name.as_ref();
+        self.changes
+            .push((key_name.to_string(), Change::Set(value.into())));
+        self
+    }
+
+    /// Add a key to delete
+    pub fn delete(&mut self, key_name: &(impl AsRef<str> + ?Sized)) -> &mut Patch {
+        let key_name = key_name.as_ref();
+        self.changes.push((key_name.to_string(), Change::Delete));
+        self
+    }
+
+    /// The changes, in the order they will be applied
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Change)> {
+        self.changes.iter().map(|(k, c)| (k.as_str(), c))
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+/// Set every key in the snapshot
+impl From<&CmapSnapshot> for Patch {
+    fn from(snapshot: &CmapSnapshot) -> Patch {
+        let mut patch = Patch::new();
+        for (k, v) in snapshot.iter() {
+            patch.set(k, v.clone());
+        }
+        patch
+    }
+}
+
+/// Turn the older snapshot of the diff into the newer one: added and changed
+/// keys are set to their new values, removed keys are deleted.
+impl From<&SnapshotDiff> for Patch {
+    fn from(diff: &SnapshotDiff) -> Patch {
+        let mut patch = Patch::new();
+        for k in diff.removed.keys() {
+            patch.delete(k);
+        }
+        for (k, (_, new)) in &diff.changed {
+            patch.set(k, new.clone());
+        }
+        for (k, v) in &diff.added {
+            patch.set(k, v.clone());
+        }
+        patch
+    }
+}
+
+/// Options for [crate::cmap::apply]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ApplyOptions {
+    /// Work out and validate the changes, but don't make them
+    pub dry_run: bool,
+    /// Allow a key to be set to a value of a different type to the one it has now
+    pub allow_type_change: bool,
+    /// Carry on with the rest of the patch after a change fails, rather than stopping
+    pub continue_on_error: bool,
+}
+
+/// What applying one entry of a [Patch] does to cmap
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// A new key
+    Add(Data),
+    /// A key that is already there gets a new value
+    Modify { old: Data, new: Data },
+    /// A key that is there is deleted
+    Delete(Data),
+    /// The key already has that value, or is already not there
+    Unchanged,
+}
+
+/// One entry of an [ApplyReport]
+#[derive(Clone, Debug)]
+pub struct KeyChange {
+    pub key_name: String,
+    pub action: Action,
+    /// The result of making the change. None if it wasn't attempted, because it was
+    /// a dry run, the patch was invalid, an earlier change failed, or there was nothing to do
+    pub result: Option<Result<()>>,
+}
+
+/// Returned from [crate::cmap::apply]
+#[derive(Clone, Debug, Default)]
+pub struct ApplyReport {
+    /// Every valid entry in the patch, in order
+    pub changes: Vec<KeyChange>,
+    /// Entries that failed validation, if there are any then nothing was changed
+    pub invalid: Vec<(String, CsError)>,
+}
+
+impl ApplyReport {
+    /// Changes that were made successfully
+    pub fn succeeded(&self) -> impl Iterator<Item = &KeyChange> {
+        self.changes
+            .iter()
+            .filter(|c| matches!(c.result, Some(Ok(()))))
+    }
+
+    /// Changes that were attempted and failed
+    pub fn failed(&self) -> impl Iterator<Item = (&KeyChange, CsError)> {
+        self.changes.iter().filter_map(|c| match c.result {
+            Some(Err(e)) => Some((c, e)),
+            _ => None,
+        })
+    }
+
+    /// Returns true if the patch was valid and every change that needed making was made
+    pub fn is_complete(&self) -> bool {
+        self.invalid.is_empty()
+            && self
+                .changes
+                .iter()
+                .all(|c| c.action == Action::Unchanged || matches!(c.result, Some(Ok(()))))
+    }
+}
+
+// Work out what one entry will do, given what will be in cmap by the time we get to it
+fn plan(current: &Option<Data>, change: &Change, options: &ApplyOptions) -> Result<Action> {
+    match (change, current) {
+        (Change::Set(Data::Unknown), _) => Err(CsError::CsErrInvalidParam),
+        (Change::Set(new), None) => Ok(Action::Add(new.clone())),
+        (Change::Set(new), Some(old)) if new == old => Ok(Action::Unchanged),
+        (Change::Set(new), Some(old)) => {
+            if old.data_type() != new.data_type() && !options.allow_type_change {
+                Err(CsError::CsErrInvalidParam)
+            } else {
+                Ok(Action::Modify {
+                    old: old.clone(),
+                    new: new.clone(),
+                })
+            }
+        }
+        (Change::Delete, None) => Ok(Action::Unchanged),
+        (Change::Delete, Some(old)) => Ok(Action::Delete(old.clone())),
+    }
+}
+
+pub(crate) fn apply(handle: Handle, patch: &Patch, options: &ApplyOptions) -> Result<ApplyReport> {
+    let mut report = ApplyReport::default();
+
+    // What each key will hold once the earlier entries have been applied
+    let mut values: HashMap<&str, Option<Data>> = HashMap::new();
+    for (key_name, change) in &patch.changes {
+        if let Err(e) = validate_key_name(key_name) {
+            report.invalid.push((key_name.clone(), e));
+            continue;
+        }
+        if !values.contains_key(key_name.as_str()) {
+            let current = match get_data(handle, key_name) {
+                Ok(d) => Some(d),
+                Err(CsError::CsErrNotExist) => None,
+                Err(e) => return Err(e),
+            };
+            values.insert(key_name, current);
+        }
+        let current = values.get_mut(key_name.as_str()).unwrap();
+        match plan(current, change, options) {
+            Ok(action) => {
+                *current = match change {
+                    Change::Set(d) => Some(d.clone()),
+                    Change::Delete => None,
+                };
+                report.changes.push(KeyChange {
+                    key_name: key_name.clone(),
+                    action,
+                    result: None,
+                });
+            }
+            Err(e) => report.invalid.push((key_name.clone(), e)),
+        }
+    }
+
+    if options.dry_run || !report.invalid.is_empty() {
+        return Ok(report);
+    }
+
+    for change in &mut report.changes {
+        let res = match &change.action {
+            Action::Add(new) | Action::Modify { new, .. } => {
+                set_data(handle, &change.key_name, new)
+            }
+            Action::Delete(_) => delete(handle, &change.key_name),
+            Action::Unchanged => continue,
+        };
+        let failed = res.is_err();
+        change.result = Some(res);
+        if failed && !options.continue_on_error {
+            break;
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,584 @@
+// Snapshots of a whole cmap map
+// Copyright (c) 2021 Red Hat, Inc.
+//
+// All rights reserved.
+//
+// Author: Christine Caulfield (ccaulfi@redhat.com)
+//
+
+//! A [crate::cmap::snapshot::CmapSnapshot] holds every key under a prefix (or the whole map)
+//! with its typed value, so it can be attached to a bug report and compared with another
+//! one later.
+//!
+//! Snapshots can be written and read back in two formats. The corosync-cmapctl text format,
+//! one `key (type) = value` line per key, is what people are used to seeing and can be
+//! compared with the output of corosync-cmapctl itself. JSON keeps the type of each value
+//! alongside it:
+//! ```text
+//! {
+//!   "totem.cluster_name": {"type": "str", "value": "mycluster"},
+//!   "totem.version": {"type": "u32", "value": 2}
+//! }
+//! ```
+//! Binary values are written as a string of hex digits in both formats.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Write;
+
+use crate::cmap::{CmapIterStart, Data, DataType, Handle};
+use crate::{CsError, Result};
+
+/// An ordered copy of cmap keys and their values
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CmapSnapshot {
+    entries: BTreeMap<String, Data>,
+}
+
+/// The differences between two snapshots, as returned by [CmapSnapshot::diff]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SnapshotDiff {
+    /// Keys only in the newer snapshot
+    pub added: BTreeMap<String, Data>,
+    /// Keys only in the older snapshot
+    pub removed: BTreeMap<String, Data>,
+    /// Keys in both whose value (or type) has changed, with the old and new values
+    pub changed: BTreeMap<String, (Data, Data)>,
+}
+
+// The type names used by corosync-cmapctl
+fn type_name(dtype: DataType) -> &'static str {
+    match dtype {
+        DataType::Int8 => "i8",
+        DataType::UInt8 => "u8",
+        DataType::Int16 => "i16",
+        DataType::UInt16 => "u16",
+        DataType::Int32 => "i32",
+        DataType::UInt32 => "u32",
+        DataType::Int64 => "i64",
+        DataType::UInt64 => "u64",
+        DataType::Float => "flt",
+        DataType::Double => "dbl",
+        DataType::String => "str",
+        DataType::Binary => "bin",
+        DataType::Unknown => "unknown",
+    }
+}
+
+fn parse_type(name: &str) -> Result<DataType> {
+    if name == "unknown" {
+        Ok(DataType::Unknown)
+    } else {
+        name.parse()
+    }
+}
+
+// The value as corosync-cmapctl prints it, without the type
+fn value_text(data: &Data) -> String {
+    match data {
+        Data::Int8(v) => v.to_string(),
+        Data::UInt8(v) => v.to_string(),
+        Data::Int16(v) => v.to_string(),
+        Data::UInt16(v) => v.to_string(),
+        Data::Int32(v) => v.to_string(),
+        Data::UInt32(v) => v.to_string(),
+        Data::Int64(v) => v.to_string(),
+        Data::UInt64(v) => v.to_string(),
+        Data::Float(v) => v.to_string(),
+        Data::Double(v) => v.to_string(),
+        Data::String(v) => v.clone(),
+        Data::Binary(v) => v.iter().map(|b| format!("{:02x}", b)).collect(),
+        Data::Unknown => String::new(),
+    }
+}
+
+fn parse_value(dtype: DataType, text: &str) -> Result<Data> {
+    match dtype {
+        DataType::Unknown => Ok(Data::Unknown),
+        _ => Data::parse(dtype, text),
+    }
+}
+
+fn write_line(out: &mut String, prefix: &str, key: &str, data: &Data) {
+    let _ = writeln!(
+        out,
+        "{}{} ({}) = {}",
+        prefix,
+        key,
+        type_name(data.data_type()),
+        value_text(data)
+    );
+}
+
+impl CmapSnapshot {
+    /// An empty snapshot
+    pub fn new() -> CmapSnapshot {
+        CmapSnapshot::default()
+    }
+
+    /// Read every key starting with `prefix` ("" for the whole map).
+    /// Keys that are deleted while the snapshot is being taken are left out.
+    pub fn capture(handle: Handle, prefix: &(impl AsRef<str> + ?Sized)) -> Result<CmapSnapshot> {
+        let prefix = prefix.as_ref();
+        let mut entries = BTreeMap::new();
+        for key in CmapIterStart::new(handle, prefix)?.keys() {
+            let key = key?;
+            match key.value() {
+                Ok(data) => {
+                    entries.insert(key.key_name().to_string(), data);
+                }
+                Err(CsError::CsErrNotExist) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(CmapSnapshot { entries })
+    }
+
+    pub fn get(&self, key_name: &(impl AsRef<str> + ?Sized)) -> Option<&Data> {
+        let key_name = key_name.as_ref();
+        self.entries.get(key_name)
+    }
+
+    /// Add or replace a key, returning the old value if there was one
+    pub fn insert(&mut self, key_name: &(impl AsRef<str> + ?Sized), data: Data) -> Option<Data> {
+        let key_name = key_name.as_ref();
+        self.entries.insert(key_name.to_string(), data)
+    }
+
+    pub fn remove(&mut self, key_name: &(impl AsRef<str> + ?Sized)) -> Option<Data> {
+        let key_name = key_name.as_ref();
+        self.entries.remove(key_name)
+    }
+
+    /// The keys and values, in key order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Data)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Compare with a later snapshot. Keys that are only in `newer` are added,
+    /// keys that are only in `self` are removed.
+    pub fn diff(&self, newer: &CmapSnapshot) -> SnapshotDiff {
+        let mut diff = SnapshotDiff::default();
+        for (k, old) in &self.entries {
+            match newer.entries.get(k) {
+                None => {
+                    diff.removed.insert(k.clone(), old.clone());
+                }
+                Some(new) if new != old => {
+                    diff.changed.insert(k.clone(), (old.clone(), new.clone()));
+                }
+                Some(_) => {}
+            }
+        }
+        for (k, new) in &newer.entries {
+            if !self.entries.contains_key(k) {
+                diff.added.insert(k.clone(), new.clone());
+            }
+        }
+        diff
+    }
+
+    /// Write in the corosync-cmapctl format, one `key (type) = value` line per key.
+    /// The format has no escapes, so a key containing " (" or a line break, or a string
+    /// value containing a line break, can't be read back and returns CsErrInvalidParam.
+    /// Use [crate::cmap::snapshot::CmapSnapshot::to_json] for those.
+    pub fn to_text(&self) -> Result<String> {
+        let mut out = String::new();
+        for (k, v) in &self.entries {
+            let bad_value = match v {
+                Data::String(s) => s.contains(['\n', '\r']),
+                _ => false,
+            };
+            if k.contains(" (") || k.contains(['\n', '\r']) || bad_value {
+                return Err(CsError::CsErrInvalidParam);
+            }
+            write_line(&mut out, "", k, v);
+        }
+        Ok(out)
+    }
+
+    /// Read the corosync-cmapctl format. Blank lines are ignored, anything else
+    /// that isn't `key (type) = value` returns CsErrInvalidParam.
+    pub fn from_text(text: &str) -> Result<CmapSnapshot> {
+        let mut entries = BTreeMap::new();
+        for line in text.lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (key, rest) = line.split_once(" (").ok_or(CsError::CsErrInvalidParam)?;
+            let (dtype, rest) = rest.split_once(')').ok_or(CsError::CsErrInvalidParam)?;
+            // corosync-cmapctl doesn't print anything after the = for an empty string
+            let value = match rest.strip_prefix(" = ") {
+                Some(v) => v,
+                None if rest == " =" => "",
+                None => return Err(CsError::CsErrInvalidParam),
+            };
+            let data = parse_value(parse_type(dtype)?, value)?;
+            entries.insert(key.to_string(), data);
+        }
+        Ok(CmapSnapshot { entries })
+    }
+
+    /// Write as JSON, an object of key name to `{"type": type, "value": value}`
+    pub fn to_json(&self) -> String {
+        if self.entries.is_empty() {
+            return "{}\n".to_string();
+        }
+        let mut out = String::from("{\n");
+        for (i, (k, v)) in self.entries.iter().enumerate() {
+            out.push_str("  ");
+            json_string(&mut out, k);
+            out.push_str(": {\"type\": ");
+            json_string(&mut out, type_name(v.data_type()));
+            out.push_str(", \"value\": ");
+            json_value(&mut out, v);
+            out.push('}');
+            if i + 1 < self.entries.len() {
+                out.push(',');
+            }
+            out.push('\n');
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Read JSON written by [CmapSnapshot::to_json]. Returns CsErrInvalidParam
+    /// if the JSON is malformed or a value doesn't match its type.
+    pub fn from_json(json: &str) -> Result<CmapSnapshot> {
+        let mut p = JsonParser {
+            text: json.as_bytes(),
+            pos: 0,
+            depth: 0,
+        };
+        let top = p.value()?;
+        p.skip_ws();
+        if p.pos != p.text.len() {
+            return Err(CsError::CsErrInvalidParam);
+        }
+
+        let mut entries = BTreeMap::new();
+        for (key, entry) in top.into_object()? {
+            let mut dtype = None;
+            let mut value = None;
+            for (field, v) in entry.into_object()? {
+                match field.as_str() {
+                    "type" => dtype = Some(parse_type(&v.into_string()?)?),
+                    "value" => value = Some(v),
+                    _ => {}
+                }
+            }
+            let dtype = dtype.ok_or(CsError::CsErrInvalidParam)?;
+            let data = match (dtype, value) {
+                (DataType::Unknown, _) => Data::Unknown,
+                (_, Some(Json::Number(n))) | (_, Some(Json::String(n))) => parse_value(dtype, &n)?,
+                _ => return Err(CsError::CsErrInvalidParam),
+            };
+            entries.insert(key, data);
+        }
+        Ok(CmapSnapshot { entries })
+    }
+}
+
+impl FromIterator<(String, Data)> for CmapSnapshot {
+    fn from_iter<I: IntoIterator<Item = (String, Data)>>(iter: I) -> CmapSnapshot {
+        CmapSnapshot {
+            entries: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl From<BTreeMap<String, Data>> for CmapSnapshot {
+    fn from(entries: BTreeMap<String, Data>) -> CmapSnapshot {
+        CmapSnapshot { entries }
+    }
+}
+
+impl From<CmapSnapshot> for BTreeMap<String, Data> {
+    fn from(snapshot: CmapSnapshot) -> BTreeMap<String, Data> {
+        snapshot.entries
+    }
+}
+
+impl SnapshotDiff {
+    /// Returns true if the two snapshots were the same
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// One line per difference in key order, in the cmapctl format with `+` for added keys,
+/// `-` for removed keys and `-`/`+` pairs for the old and new values of changed keys.
+impl fmt::Display for SnapshotDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut keys: Vec<&String> = self
+            .added
+            .keys()
+            .chain(self.removed.keys())
+            .chain(self.changed.keys())
+            .collect();
+        keys.sort();
+        let mut out = String::new();
+        for k in keys {
+            if let Some(v) = self.removed.get(k) {
+                write_line(&mut out, "-", k, v);
+            }
+            if let Some((old, new)) = self.changed.get(k) {
+                write_line(&mut out, "-", k, old);
+                write_line(&mut out, "+", k, new);
+            }
+            if let Some(v) = self.added.get(k) {
+                write_line(&mut out, "+", k, v);
+            }
+        }
+        f.write_str(&out)
+    }
+}
+
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn json_value(out: &mut String, data: &Data) {
+    match data {
+        // JSON has no NaN or infinity, so those go as strings
+        Data::Float(v) if !v.is_finite() => json_string(out, &value_text(data)),
+        Data::Double(v) if !v.is_finite() => json_string(out, &value_text(data)),
+        Data::String(_) | Data::Binary(_) => json_string(out, &value_text(data)),
+        Data::Unknown => out.push_str("null"),
+        _ => out.push_str(&value_text(data)),
+    }
+}
+
+// Just enough JSON to read back what to_json() writes, and anything
+// equivalent that has been through another tool.
+enum Json {
+    Null,
+    Bool,
+    // Kept as text so that 64 bit integers don't go through a float
+    Number(String),
+    String(String),
+    Array,
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn into_object(self) -> Result<Vec<(String, Json)>> {
+        match self {
+            Json::Object(o) => Ok(o),
+            _ => Err(CsError::CsErrInvalidParam),
+        }
+    }
+
+    fn into_string(self) -> Result<String> {
+        match self {
+            Json::String(s) => Ok(s),
+            _ => Err(CsError::CsErrInvalidParam),
+        }
+    }
+}
+
+// Snapshots only need two levels, anything much deeper is not one of ours and
+// could otherwise run us out of stack
+const JSON_MAX_DEPTH: usize = 32;
+
+struct JsonParser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl JsonParser<'_> {
+    fn skip_ws(&mut self) {
+        while self.pos < self.text.len() && self.text[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_ws();
+        self.text.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<()> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(CsError::CsErrInvalidParam)
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json> {
+        if self.text[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            Err(CsError::CsErrInvalidParam)
+        }
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        match self.peek() {
+            Some(b'{') => self.nested(Self::object),
+            Some(b'[') => self.nested(Self::array),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b't') => self.literal("true", Json::Bool),
+            Some(b'f') => self.literal("false", Json::Bool),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(c) if c == b'-' || c.is_ascii_digit() => self.number(),
+            _ => Err(CsError::CsErrInvalidParam),
+        }
+    }
+
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Json>) -> Result<Json> {
+        if self.depth >= JSON_MAX_DEPTH {
+            return Err(CsError::CsErrInvalidParam);
+        }
+        self.depth += 1;
+        let res = parse(self);
+        self.depth -= 1;
+        res
+    }
+
+    fn object(&mut self) -> Result<Json> {
+        self.expect(b'{')?;
+        let mut members = Vec::new();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(members));
+        }
+        loop {
+            if self.peek() != Some(b'"') {
+                return Err(CsError::CsErrInvalidParam);
+            }
+            let key = self.string()?;
+            self.expect(b':')?;
+            members.push((key, self.value()?));
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Json::Object(members));
+                }
+                _ => return Err(CsError::CsErrInvalidParam),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json> {
+        self.expect(b'[')?;
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Json::Array);
+        }
+        loop {
+            self.value()?;
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Json::Array);
+                }
+                _ => return Err(CsError::CsErrInvalidParam),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json> {
+        let start = self.pos;
+        while self.pos < self.text.len()
+            && matches!(
+                self.text[self.pos],
+                b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9'
+            )
+        {
+            self.pos += 1;
+        }
+        // Only ASCII was consumed
+        Ok(Json::Number(
+            String::from_utf8_lossy(&self.text[start..self.pos]).into_owned(),
+        ))
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        // Exactly four hex digits, from_str_radix() would also take a sign
+        let digits = self
+            .text
+            .get(self.pos..self.pos + 4)
+            .ok_or(CsError::CsErrInvalidParam)?;
+        self.pos += 4;
+        digits.iter().try_fold(0, |n, d| {
+            (*d as char)
+                .to_digit(16)
+                .map(|d| (n << 4) | d)
+                .ok_or(CsError::CsErrInvalidParam)
+        })
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            let c = *self.text.get(self.pos).ok_or(CsError::CsErrInvalidParam)?;
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let e = *self.text.get(self.pos).ok_or(CsError::CsErrInvalidParam)?;
+                    self.pos += 1;
+                    let ch = match e {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut cp = self.hex4()?;
+                            if (0xd800..0xdc00).contains(&cp)
+                                && self.text[self.pos..].starts_with(b"\\u")
+                            {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(CsError::CsErrInvalidParam);
+                                }
+                                cp = 0x10000 + ((cp - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            char::from_u32(cp).ok_or(CsError::CsErrInvalidParam)?
+                        }
+                        _ => return Err(CsError::CsErrInvalidParam),
+                    };
+                    let mut buf = [0u8; 4];
+                    bytes.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+                }
+                c => bytes.push(c),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| CsError::CsErrInvalidParam)
+    }
+}
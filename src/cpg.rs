@@ -13,24 +13,27 @@
 // For the code generated by bindgen
 use crate::sys::cpg as ffi;
 
-use std::collections::HashMap;
-use std::ffi::{CStr, CString};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
-use std::os::raw::{c_int, c_void};
-use std::ptr::copy_nonoverlapping;
+use std::os::raw::{c_char, c_int, c_void};
 use std::slice;
 use std::string::String;
-use std::sync::Mutex;
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 // General corosync things
-use crate::string_from_bytes;
 use crate::{CsError, DispatchFlags, NodeId, Result};
 
 const CPG_NAMELEN_MAX: usize = 128;
-const CPG_MEMBERS_MAX: usize = 128;
+// PROCESSOR_COUNT_MAX in corosync, the most members a membership_get reply can hold
+const CPG_MEMBERS_MAX: usize = 384;
+const VIEW_HISTORY_MAX: usize = 100;
 
 /// RingId returned by totem_confchg_fn
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct RingId {
     pub nodeid: NodeId,
     pub seq: u64,
@@ -73,7 +76,7 @@ pub enum Model1Flags {
 }
 
 /// Reason for cpg item callback
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Reason {
     Undefined = 0,
     Join = 1,
@@ -111,6 +114,7 @@ impl fmt::Display for Reason {
 }
 
 /// A CPG address entry returned in the callbacks
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub struct Address {
     pub nodeid: NodeId,
     pub pid: u32,
@@ -126,6 +130,141 @@ impl fmt::Debug for Address {
     }
 }
 
+/// The name of a CPG group. Names are up to 128 bytes long and, as in the C API, are
+/// delimited by their length rather than a NUL so they may contain any bytes at all,
+/// although most programs just use UTF-8 strings.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CpgName {
+    bytes: Vec<u8>,
+}
+
+impl CpgName {
+    /// Make a name from raw bytes, returns CsErrNameTooLong if there are more than 128
+    pub fn new(bytes: &[u8]) -> Result<CpgName> {
+        if bytes.len() > CPG_NAMELEN_MAX {
+            return Err(CsError::CsErrNameTooLong);
+        }
+        Ok(CpgName {
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// The name as a str, if it is valid UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.bytes).ok()
+    }
+
+    /// The name as a string, with any invalid UTF-8 replaced
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.bytes)
+    }
+
+    fn to_c(&self) -> Result<ffi::cpg_name> {
+        if self.bytes.len() > CPG_NAMELEN_MAX {
+            return Err(CsError::CsErrNameTooLong);
+        }
+        let mut c_group = ffi::cpg_name {
+            length: self.bytes.len() as u32,
+            value: [0; CPG_NAMELEN_MAX],
+        };
+        for (c, b) in c_group.value.iter_mut().zip(&self.bytes) {
+            *c = *b as c_char;
+        }
+        Ok(c_group)
+    }
+
+    fn from_c(c_group: &ffi::cpg_name) -> CpgName {
+        // Don't trust the length to be sane
+        let len = std::cmp::min(c_group.length as usize, CPG_NAMELEN_MAX);
+        CpgName {
+            bytes: c_group.value[..len].iter().map(|c| *c as u8).collect(),
+        }
+    }
+}
+
+impl TryFrom<&str> for CpgName {
+    type Error = CsError;
+
+    fn try_from(name: &str) -> Result<CpgName> {
+        CpgName::new(name.as_bytes())
+    }
+}
+
+/// The length is not checked until the name is used, longer than 128 bytes
+/// gets CsErrNameTooLong then.
+impl From<&[u8]> for CpgName {
+    fn from(bytes: &[u8]) -> CpgName {
+        CpgName {
+            bytes: bytes.to_vec(),
+        }
+    }
+}
+
+impl PartialEq<str> for CpgName {
+    fn eq(&self, other: &str) -> bool {
+        self.bytes == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for CpgName {
+    fn eq(&self, other: &&str) -> bool {
+        self.bytes == other.as_bytes()
+    }
+}
+
+impl fmt::Display for CpgName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_string_lossy())
+    }
+}
+
+impl fmt::Debug for CpgName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\"", self.bytes.escape_ascii())
+    }
+}
+
+/// Anything that can be used as the name of a CPG group: [CpgName], str, String or [u8]
+pub trait AsCpgName {
+    fn to_cpg_name(&self) -> Result<CpgName>;
+}
+
+impl AsCpgName for CpgName {
+    fn to_cpg_name(&self) -> Result<CpgName> {
+        Ok(self.clone())
+    }
+}
+
+impl AsCpgName for str {
+    fn to_cpg_name(&self) -> Result<CpgName> {
+        CpgName::try_from(self)
+    }
+}
+
+impl AsCpgName for String {
+    fn to_cpg_name(&self) -> Result<CpgName> {
+        CpgName::try_from(self.as_str())
+    }
+}
+
+impl AsCpgName for [u8] {
+    fn to_cpg_name(&self) -> Result<CpgName> {
+        CpgName::new(self)
+    }
+}
+
 /// Data for model1 [initialize]
 #[derive(Copy, Clone)]
 pub struct Model1Data {
@@ -133,7 +272,7 @@ pub struct Model1Data {
     pub deliver_fn: Option<
         fn(
             handle: &Handle,
-            group_name: String,
+            group_name: CpgName,
             nodeid: NodeId,
             pid: u32,
             msg: &[u8],
@@ -143,7 +282,7 @@ pub struct Model1Data {
     pub confchg_fn: Option<
         fn(
             handle: &Handle,
-            group_name: &str,
+            group_name: &CpgName,
             member_list: Vec<Address>,
             left_list: Vec<Address>,
             joined_list: Vec<Address>,
@@ -162,36 +301,193 @@ pub enum ModelData {
 /// A handle into the cpg library. Returned from [initialize] and needed for all other calls
 #[derive(Copy, Clone)]
 pub struct Handle {
-    cpg_handle: u64, // Corosync library handle
+    pub(crate) cpg_handle: u64, // Corosync library handle
     model_data: ModelData,
 }
 
+impl Handle {
+    // A Handle that is not connected to corosync, only good for calling callbacks with
+    pub(crate) fn detached(model_data: ModelData) -> Handle {
+        Handle {
+            cpg_handle: 0,
+            model_data,
+        }
+    }
+
+    // Call this handle's callbacks as if the event had come from corosync
+    pub(crate) fn call_callbacks(&self, event: CpgEvent) {
+        let md = match self.model_data {
+            ModelData::ModelV1(md) => md,
+            _ => return,
+        };
+        match event {
+            CpgEvent::Message {
+                group,
+                sender,
+                payload,
+            } => {
+                if let Some(cb) = md.deliver_fn {
+                    (cb)(
+                        self,
+                        group,
+                        sender.nodeid,
+                        sender.pid,
+                        &payload,
+                        payload.len(),
+                    );
+                }
+            }
+            CpgEvent::ConfChg {
+                group,
+                members,
+                left,
+                joined,
+            } => {
+                if let Some(cb) = md.confchg_fn {
+                    (cb)(self, &group, members, left, joined);
+                }
+            }
+            CpgEvent::TotemConfChg { ring_id, members } => {
+                if let Some(cb) = md.totem_confchg_fn {
+                    (cb)(self, ring_id, members);
+                }
+            }
+        }
+    }
+}
+
+/// One entry in the membership history of a [GroupView]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MembershipEvent {
+    pub address: Address,
+    /// true if the process joined, false if it left
+    pub joined: bool,
+    /// The totem ring that was current when the change happened, if known
+    pub ring_id: Option<RingId>,
+}
+
+/// The differences between two [GroupView]s, returned from [GroupView::diff]
+#[derive(Clone, Debug, Default)]
+pub struct ViewDiff {
+    pub joined: Vec<Address>,
+    pub left: Vec<Address>,
+}
+
+impl ViewDiff {
+    pub fn is_empty(&self) -> bool {
+        self.joined.is_empty() && self.left.is_empty()
+    }
+}
+
+/// The membership of a joined CPG group, kept up to date from the confchg callbacks.
+/// Returned from [group_view], it is a snapshot and does not change once returned.
+#[derive(Clone, Debug)]
+pub struct GroupView {
+    pub group: CpgName,
+    pub members: Vec<Address>,
+    /// The current totem ring, if a totem_confchg has been seen
+    pub ring_id: Option<RingId>,
+    /// The most recent joins and leaves, oldest first
+    pub history: Vec<MembershipEvent>,
+    /// Incremented on every confchg for this group
+    pub generation: u64,
+}
+
+impl GroupView {
+    fn new(group: &CpgName) -> GroupView {
+        GroupView {
+            group: group.clone(),
+            members: Vec::new(),
+            ring_id: None,
+            history: Vec::new(),
+            generation: 0,
+        }
+    }
+
+    /// Returns true if the process nodeid/pid is a member of the group
+    pub fn is_member(&self, nodeid: NodeId, pid: u32) -> bool {
+        self.members
+            .iter()
+            .any(|m| m.nodeid == nodeid && m.pid == pid)
+    }
+
+    /// Returns the nodes that have at least one process in the group, in order
+    pub fn nodes(&self) -> Vec<NodeId> {
+        let mut nodes: Vec<NodeId> = self.members.iter().map(|m| m.nodeid).collect();
+        nodes.sort();
+        nodes.dedup();
+        nodes
+    }
+
+    /// Work out which processes have joined and left between this view and a later one
+    pub fn diff(&self, newer: &GroupView) -> ViewDiff {
+        let contains = |list: &[Address], a: &Address| {
+            list.iter().any(|m| m.nodeid == a.nodeid && m.pid == a.pid)
+        };
+        ViewDiff {
+            joined: newer
+                .members
+                .iter()
+                .filter(|m| !contains(&self.members, m))
+                .copied()
+                .collect(),
+            left: self
+                .members
+                .iter()
+                .filter(|m| !contains(&newer.members, m))
+                .copied()
+                .collect(),
+        }
+    }
+
+    fn update(&mut self, members: &[Address], left: &[Address], joined: &[Address]) {
+        self.members = members.to_vec();
+        self.generation += 1;
+        for (list, is_join) in [(left, false), (joined, true)] {
+            for a in list {
+                self.history.push(MembershipEvent {
+                    address: *a,
+                    joined: is_join,
+                    ring_id: self.ring_id,
+                });
+            }
+        }
+        if self.history.len() > VIEW_HISTORY_MAX {
+            let excess = self.history.len() - VIEW_HISTORY_MAX;
+            self.history.drain(0..excess);
+        }
+    }
+}
+
 // Used to convert a CPG handle into one of ours
 lazy_static! {
     static ref HANDLE_HASH: Mutex<HashMap<u64, Handle>> = Mutex::new(HashMap::new());
+    static ref VIEW_HASH: Mutex<HashMap<u64, HashMap<CpgName, GroupView>>> =
+        Mutex::new(HashMap::new());
+    static ref CHANNEL_HASH: Mutex<HashMap<u64, SyncSender<CpgEvent>>> = Mutex::new(HashMap::new());
+    static ref HOOK_HASH: Mutex<HashMap<u64, EventHook>> = Mutex::new(HashMap::new());
 }
 
-// Convert a Rust String into a cpg_name struct for libcpg
-fn string_to_cpg_name(group: &str) -> Result<ffi::cpg_name> {
-    if group.len() > CPG_NAMELEN_MAX - 1 {
-        return Err(CsError::CsErrInvalidParam);
-    }
+// Called with every event on a handle before its callbacks, used by the recorder
+pub(crate) type EventHook = Arc<dyn Fn(&CpgEvent) + Send + Sync>;
 
-    let c_name = match CString::new(group) {
-        Ok(n) => n,
-        Err(_) => return Err(CsError::CsErrLibrary),
-    };
-    let mut c_group = ffi::cpg_name {
-        length: group.len() as u32,
-        value: [0; CPG_NAMELEN_MAX],
+pub(crate) fn set_event_hook(handle: Handle, hook: Option<EventHook>) {
+    let mut hooks = HOOK_HASH.lock().unwrap();
+    match hook {
+        Some(h) => hooks.insert(handle.cpg_handle, h),
+        None => hooks.remove(&handle.cpg_handle),
     };
+}
 
-    unsafe {
-        // NOTE param order is 'wrong-way round' from C
-        copy_nonoverlapping(c_name.as_ptr(), c_group.value.as_mut_ptr(), group.len());
+// Remove `hook` from the handle, unless it has already been replaced by another one
+pub(crate) fn clear_event_hook(handle: Handle, hook: &EventHook) {
+    let mut hooks = HOOK_HASH.lock().unwrap();
+    if hooks
+        .get(&handle.cpg_handle)
+        .is_some_and(|h| Arc::ptr_eq(h, hook))
+    {
+        hooks.remove(&handle.cpg_handle);
     }
-
-    Ok(c_group)
 }
 
 // Convert an array of cpg_addresses to a Vec<cpg::Address> - used in callbacks
@@ -219,16 +515,29 @@ extern "C" fn rust_deliver_fn(
     msg: *mut ::std::os::raw::c_void,
     msg_len: usize,
 ) {
+    let tx = CHANNEL_HASH.lock().unwrap().get(&handle).cloned();
+    let hook = HOOK_HASH.lock().unwrap().get(&handle).cloned();
+    let mut event = None;
     if let Some(h) = HANDLE_HASH.lock().unwrap().get(&handle) {
-        // Convert group_name into a Rust str.
-        let r_group_name = unsafe {
-            CStr::from_ptr(&(*group_name).value[0])
-                .to_string_lossy()
-                .into_owned()
-        };
+        let r_group_name = unsafe { CpgName::from_c(&*group_name) };
 
         let data: &[u8] = unsafe { std::slice::from_raw_parts(msg as *const u8, msg_len) };
 
+        if tx.is_some() || hook.is_some() {
+            event = Some(CpgEvent::Message {
+                group: r_group_name.clone(),
+                sender: Address {
+                    nodeid: NodeId::from(nodeid),
+                    pid,
+                    reason: Reason::Undefined,
+                },
+                payload: data.to_vec(),
+            });
+        }
+        if let (Some(hook), Some(ev)) = (&hook, &event) {
+            (hook)(ev);
+        }
+
         match h.model_data {
             ModelData::ModelV1(md) => {
                 if let Some(cb) = md.deliver_fn {
@@ -238,6 +547,10 @@ extern "C" fn rust_deliver_fn(
             _ => {}
         }
     }
+    // Sent once HANDLE_HASH is unlocked, as this blocks while the channel is full
+    if let (Some(tx), Some(ev)) = (tx, event) {
+        let _ = tx.send(ev);
+    }
 }
 
 // Called from CPG callback function - munge params back to Rust from C
@@ -251,16 +564,38 @@ extern "C" fn rust_confchg_fn(
     joined_list: *const ffi::cpg_address,
     joined_list_entries: usize,
 ) {
+    let tx = CHANNEL_HASH.lock().unwrap().get(&handle).cloned();
+    let hook = HOOK_HASH.lock().unwrap().get(&handle).cloned();
+    let mut event = None;
     if let Some(h) = HANDLE_HASH.lock().unwrap().get(&handle) {
-        let r_group_name = unsafe {
-            CStr::from_ptr(&(*group_name).value[0])
-                .to_string_lossy()
-                .into_owned()
-        };
+        let r_group_name = unsafe { CpgName::from_c(&*group_name) };
         let r_member_list = cpg_array_to_vec(member_list, member_list_entries);
         let r_left_list = cpg_array_to_vec(left_list, left_list_entries);
         let r_joined_list = cpg_array_to_vec(joined_list, joined_list_entries);
 
+        // Views are created by join() and removed by leave(), so the confchg
+        // for our own leave doesn't bring a view back
+        if let Some(view) = VIEW_HASH
+            .lock()
+            .unwrap()
+            .get_mut(&handle)
+            .and_then(|views| views.get_mut(&r_group_name))
+        {
+            view.update(&r_member_list, &r_left_list, &r_joined_list);
+        }
+
+        if tx.is_some() || hook.is_some() {
+            event = Some(CpgEvent::ConfChg {
+                group: r_group_name.clone(),
+                members: r_member_list.clone(),
+                left: r_left_list.clone(),
+                joined: r_joined_list.clone(),
+            });
+        }
+        if let (Some(hook), Some(ev)) = (&hook, &event) {
+            (hook)(ev);
+        }
+
         match h.model_data {
             ModelData::ModelV1(md) => {
                 if let Some(cb) = md.confchg_fn {
@@ -270,6 +605,10 @@ extern "C" fn rust_confchg_fn(
             _ => {}
         }
     }
+    // Sent once HANDLE_HASH is unlocked, as this blocks while the channel is full
+    if let (Some(tx), Some(ev)) = (tx, event) {
+        let _ = tx.send(ev);
+    }
 }
 
 // Called from CPG callback function - munge params back to Rust from C
@@ -279,6 +618,9 @@ extern "C" fn rust_totem_confchg_fn(
     member_list_entries: u32,
     member_list: *const u32,
 ) {
+    let tx = CHANNEL_HASH.lock().unwrap().get(&handle).cloned();
+    let hook = HOOK_HASH.lock().unwrap().get(&handle).cloned();
+    let mut event = None;
     if let Some(h) = HANDLE_HASH.lock().unwrap().get(&handle) {
         let r_ring_id = RingId {
             nodeid: NodeId::from(ring_id.nodeid),
@@ -291,6 +633,22 @@ extern "C" fn rust_totem_confchg_fn(
             r_member_list.push(NodeId::from(temp_members[i]));
         }
 
+        if let Some(views) = VIEW_HASH.lock().unwrap().get_mut(&handle) {
+            for v in views.values_mut() {
+                v.ring_id = Some(r_ring_id);
+            }
+        }
+
+        if tx.is_some() || hook.is_some() {
+            event = Some(CpgEvent::TotemConfChg {
+                ring_id: r_ring_id,
+                members: r_member_list.clone(),
+            });
+        }
+        if let (Some(hook), Some(ev)) = (&hook, &event) {
+            (hook)(ev);
+        }
+
         match h.model_data {
             ModelData::ModelV1(md) => {
                 if let Some(cb) = md.totem_confchg_fn {
@@ -300,6 +658,10 @@ extern "C" fn rust_totem_confchg_fn(
             _ => {}
         }
     }
+    // Sent once HANDLE_HASH is unlocked, as this blocks while the channel is full
+    if let (Some(tx), Some(ev)) = (tx, event) {
+        let _ = tx.send(ev);
+    }
 }
 
 /// Initialize a connection to the cpg library. You must call this before doing anything
@@ -338,11 +700,55 @@ pub fn initialize(model_data: &ModelData, context: u64) -> Result<Handle> {
     }
 }
 
+/// An event from a [Handle] created with [initialize_channel]
+#[derive(Clone, Debug)]
+pub enum CpgEvent {
+    /// A message delivered to a joined group. The reason in sender is always Undefined
+    Message {
+        group: CpgName,
+        sender: Address,
+        payload: Vec<u8>,
+    },
+    /// The membership of a joined group changed
+    ConfChg {
+        group: CpgName,
+        members: Vec<Address>,
+        left: Vec<Address>,
+        joined: Vec<Address>,
+    },
+    /// The totem ring changed
+    TotemConfChg {
+        ring_id: RingId,
+        members: Vec<NodeId>,
+    },
+}
+
+/// Initialize a connection to the cpg library that delivers callbacks as [CpgEvent]s
+/// down a channel rather than calling functions, so they can be handled on another thread.
+/// [dispatch] must still be called to receive events; when the channel already
+/// holds `bound` events it blocks until the receiver catches up, so nothing is lost and
+/// ordering is kept. The channel is closed by [finalize].
+pub fn initialize_channel(bound: usize, context: u64) -> Result<(Handle, Receiver<CpgEvent>)> {
+    let md = ModelData::ModelV1(Model1Data {
+        flags: Model1Flags::None,
+        deliver_fn: None,
+        confchg_fn: None,
+        totem_confchg_fn: None,
+    });
+    let handle = initialize(&md, context)?;
+    let (tx, rx) = sync_channel(bound);
+    CHANNEL_HASH.lock().unwrap().insert(handle.cpg_handle, tx);
+    Ok((handle, rx))
+}
+
 /// Finish with a connection to corosync
 pub fn finalize(handle: Handle) -> Result<()> {
     let res = unsafe { ffi::cpg_finalize(handle.cpg_handle) };
     if res == ffi::CS_OK {
         HANDLE_HASH.lock().unwrap().remove(&handle.cpg_handle);
+        VIEW_HASH.lock().unwrap().remove(&handle.cpg_handle);
+        CHANNEL_HASH.lock().unwrap().remove(&handle.cpg_handle);
+        HOOK_HASH.lock().unwrap().remove(&handle.cpg_handle);
         Ok(())
     } else {
         Err(CsError::from_c(res))
@@ -371,13 +777,46 @@ pub fn dispatch(handle: Handle, flags: DispatchFlags) -> Result<()> {
     }
 }
 
+// Used by the modules layered on top of CPG (dlm etc) which need to wait for their
+// own messages to come back around in agreed order. Dispatches callbacks until
+// `done` returns true (Ok(true)) or the deadline passes (Ok(false)).
+pub(crate) fn dispatch_until<F: FnMut() -> bool>(
+    handle: Handle,
+    deadline: Option<Instant>,
+    mut done: F,
+) -> Result<bool> {
+    loop {
+        if done() {
+            return Ok(true);
+        }
+        if let Some(d) = deadline {
+            if Instant::now() >= d {
+                return Ok(false);
+            }
+        }
+        match dispatch(handle, DispatchFlags::OneNonblocking) {
+            Ok(()) => {}
+            // Nothing waiting to be dispatched
+            Err(CsError::CsErrTryAgain) => thread::sleep(Duration::from_millis(1)),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 /// Joins a CPG group for sending and receiving messages
-pub fn join(handle: Handle, group: &str) -> Result<()> {
+pub fn join<N: AsCpgName + ?Sized>(handle: Handle, group: &N) -> Result<()> {
+    let group = group.to_cpg_name()?;
     let res = unsafe {
-        let c_group = string_to_cpg_name(group)?;
+        let c_group = group.to_c()?;
         ffi::cpg_join(handle.cpg_handle, &c_group)
     };
     if res == ffi::CS_OK {
+        VIEW_HASH
+            .lock()
+            .unwrap()
+            .entry(handle.cpg_handle)
+            .or_default()
+            .insert(group.clone(), GroupView::new(&group));
         Ok(())
     } else {
         Err(CsError::from_c(res))
@@ -386,12 +825,16 @@ pub fn join(handle: Handle, group: &str) -> Result<()> {
 
 /// Leave the currently joined CPG group, another group can now be joined on
 /// the same [Handle] or [finalize] can be called to finish using CPG
-pub fn leave(handle: Handle, group: &str) -> Result<()> {
+pub fn leave<N: AsCpgName + ?Sized>(handle: Handle, group: &N) -> Result<()> {
+    let group = group.to_cpg_name()?;
     let res = unsafe {
-        let c_group = string_to_cpg_name(group)?;
+        let c_group = group.to_c()?;
         ffi::cpg_leave(handle.cpg_handle, &c_group)
     };
     if res == ffi::CS_OK {
+        if let Some(views) = VIEW_HASH.lock().unwrap().get_mut(&handle.cpg_handle) {
+            views.remove(&group);
+        }
         Ok(())
     } else {
         Err(CsError::from_c(res))
@@ -409,31 +852,85 @@ pub fn local_get(handle: Handle) -> Result<NodeId> {
     }
 }
 
-/// Get a list of members of a CPG group as a vector of [Address] structs
-pub fn membership_get(handle: Handle, group: &str) -> Result<Vec<Address>> {
-    let mut member_list_entries: i32 = 0;
-    let member_list = [ffi::cpg_address {
-        nodeid: 0,
-        pid: 0,
-        reason: 0,
-    }; CPG_MEMBERS_MAX];
+// Get the members of a group by iterating over it, this has no limit on the number
+// of members returned.
+fn membership_iterate(handle: Handle, group: &CpgName) -> Result<Vec<Address>> {
+    let mut r_vec = Vec::<Address>::new();
+    for i in CpgIterStart::new(handle, group, CpgIterType::OneGroup)? {
+        let i = i?;
+        r_vec.push(Address {
+            nodeid: i.nodeid,
+            pid: i.pid,
+            reason: Reason::Undefined,
+        });
+    }
+    Ok(r_vec)
+}
+
+/// Get a list of members of a CPG group as a vector of [Address] structs.
+/// Groups too large to be returned in one go by corosync are fetched
+/// using a [CpgIterStart] instead. If corosync can't do that then CsErrTooBig
+/// is returned rather than a partial list.
+pub fn membership_get<N: AsCpgName + ?Sized>(handle: Handle, group: &N) -> Result<Vec<Address>> {
+    let group = group.to_cpg_name()?;
+    // libcpg copies however many members the daemon sends back without checking
+    // the size of our buffer, so it must always be big enough for the daemon's maximum.
+    let mut member_list = vec![
+        ffi::cpg_address {
+            nodeid: 0,
+            pid: 0,
+            reason: 0,
+        };
+        CPG_MEMBERS_MAX
+    ];
+    let mut member_list_entries: i32 = member_list.len() as i32;
     let res = unsafe {
-        let mut c_group = string_to_cpg_name(group)?;
-        let c_memlist = member_list.as_ptr() as *mut ffi::cpg_address;
+        let mut c_group = group.to_c()?;
         ffi::cpg_membership_get(
             handle.cpg_handle,
             &mut c_group,
-            &mut *c_memlist,
+            member_list.as_mut_ptr(),
             &mut member_list_entries,
         )
     };
-    if res == ffi::CS_OK {
-        Ok(cpg_array_to_vec(
-            member_list.as_ptr(),
-            member_list_entries as usize,
-        ))
-    } else {
-        Err(CsError::from_c(res))
+    if res != ffi::CS_OK {
+        return Err(CsError::from_c(res));
+    }
+
+    let entries = member_list_entries as usize;
+    if entries < CPG_MEMBERS_MAX {
+        return Ok(cpg_array_to_vec(member_list.as_ptr(), entries));
+    }
+
+    // A full buffer means the daemon may have left some out
+    match membership_iterate(handle, &group) {
+        Ok(members) => Ok(members),
+        // All we have is the list that might be missing some
+        Err(CsError::CsErrNotSupported) => Err(CsError::CsErrTooBig),
+        Err(e) => Err(e),
+    }
+}
+
+/// Get the membership of a joined group as last reported by the confchg callback,
+/// without asking corosync. Returns None if no confchg has been seen for the group yet
+/// (confchg callbacks only arrive when [dispatch] is called).
+pub fn group_view<N: AsCpgName + ?Sized>(handle: Handle, group: &N) -> Option<GroupView> {
+    let group = group.to_cpg_name().ok()?;
+    match VIEW_HASH.lock().unwrap().get(&handle.cpg_handle) {
+        Some(views) => views.get(&group).filter(|v| v.generation > 0).cloned(),
+        None => None,
+    }
+}
+
+/// Get [GroupView]s for all the groups joined on this [Handle]
+pub fn group_views(handle: Handle) -> Vec<GroupView> {
+    match VIEW_HASH.lock().unwrap().get(&handle.cpg_handle) {
+        Some(views) => views
+            .values()
+            .filter(|v| v.generation > 0)
+            .cloned()
+            .collect(),
+        None => Vec::new(),
     }
 }
 
@@ -512,6 +1009,22 @@ pub fn mcast_joined(handle: Handle, guarantee: Guarantee, msg: &[u8]) -> Result<
     }
 }
 
+// Internal protocol messages can't just be dropped when corosync applies flow
+// control, but they may be sent from inside a callback where we can't dispatch,
+// so just have a few goes before giving up.
+pub(crate) fn mcast_joined_retry(handle: Handle, guarantee: Guarantee, msg: &[u8]) -> Result<()> {
+    let mut tries = 0;
+    loop {
+        match mcast_joined(handle, guarantee, msg) {
+            Err(CsError::CsErrTryAgain) if tries < 100 => {
+                tries += 1;
+                thread::sleep(Duration::from_millis(10));
+            }
+            r => return r,
+        }
+    }
+}
+
 /// Type of iteration for [CpgIterStart]
 #[derive(Copy, Clone)]
 pub enum CpgIterType {
@@ -523,20 +1036,24 @@ pub enum CpgIterType {
 // Iterator based on information on this page. thank you!
 // https://stackoverflow.com/questions/30218886/how-to-implement-iterator-and-intoiterator-for-a-simple-struct
 // Object to iterate over
-/// An object to iterate over a list of CPG groups, create one of these and then use 'for' over it
+/// An object to iterate over a list of CPG groups, create one of these and then use 'for' over it.
+/// Each item is a Result, as errors can occur part way through iterating.
+/// The iteration is finished with corosync when this (or the iterator made from it) is dropped,
+/// so it is fine to stop early.
 pub struct CpgIterStart {
     iter_handle: u64,
 }
 
 /// struct returned from iterating over a [CpgIterStart]
 pub struct CpgIter {
-    pub group: String,
+    pub group: CpgName,
     pub nodeid: NodeId,
     pub pid: u32,
 }
 
 pub struct CpgIntoIter {
     iter_handle: u64,
+    finished: bool,
 }
 
 impl fmt::Debug for CpgIter {
@@ -549,10 +1066,25 @@ impl fmt::Debug for CpgIter {
     }
 }
 
+impl CpgIntoIter {
+    fn finish(&mut self) {
+        if !self.finished {
+            self.finished = true;
+            unsafe {
+                // Yeah, we don't check this return code. There's nowhere to report it.
+                ffi::cpg_iteration_finalize(self.iter_handle)
+            };
+        }
+    }
+}
+
 impl Iterator for CpgIntoIter {
-    type Item = CpgIter;
+    type Item = Result<CpgIter>;
 
-    fn next(&mut self) -> Option<CpgIter> {
+    fn next(&mut self) -> Option<Result<CpgIter>> {
+        if self.finished {
+            return None;
+        }
         let mut c_iter_description = ffi::cpg_iteration_description_t {
             nodeid: 0,
             pid: 0,
@@ -564,35 +1096,39 @@ impl Iterator for CpgIntoIter {
         let res = unsafe { ffi::cpg_iteration_next(self.iter_handle, &mut c_iter_description) };
 
         if res == ffi::CS_OK {
-            let r_group =
-                match string_from_bytes(c_iter_description.group.value.as_ptr(), CPG_NAMELEN_MAX) {
-                    Ok(groupname) => groupname,
-                    Err(_) => return None,
-                };
-            Some(CpgIter {
-                group: r_group,
+            Some(Ok(CpgIter {
+                group: CpgName::from_c(&c_iter_description.group),
                 nodeid: NodeId::from(c_iter_description.nodeid),
                 pid: c_iter_description.pid,
-            })
+            }))
         } else if res == ffi::CS_ERR_NO_SECTIONS {
             // End of list
-            unsafe {
-                // Yeah, we don't check this return code. There's nowhere to report it.
-                ffi::cpg_iteration_finalize(self.iter_handle)
-            };
+            self.finish();
             None
         } else {
-            None
+            // Report the error once, then stop
+            self.finish();
+            Some(Err(CsError::from_c(res)))
         }
     }
 }
 
+impl Drop for CpgIntoIter {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
 impl CpgIterStart {
     /// Create a new [CpgIterStart] object for iterating over a list of active CPG groups
-    pub fn new(cpg_handle: Handle, group: &str, iter_type: CpgIterType) -> Result<CpgIterStart> {
+    pub fn new<N: AsCpgName + ?Sized>(
+        cpg_handle: Handle,
+        group: &N,
+        iter_type: CpgIterType,
+    ) -> Result<CpgIterStart> {
         let mut iter_handle: u64 = 0;
         let res = unsafe {
-            let mut c_group = string_to_cpg_name(group)?;
+            let mut c_group = group.to_cpg_name()?.to_c()?;
             let c_itertype = iter_type as u32;
             // IterType 'All' requires that the group pointer is passed in as NULL
             let c_group_ptr = {
@@ -614,15 +1150,186 @@ impl CpgIterStart {
             Err(CsError::from_c(res))
         }
     }
+
+    /// Iterate over everything, returning the first error if there is one
+    pub fn collect_all(self) -> Result<Vec<CpgIter>> {
+        self.into_iter().collect()
+    }
+}
+
+impl Drop for CpgIterStart {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::cpg_iteration_finalize(self.iter_handle);
+        }
+    }
 }
 
 impl IntoIterator for CpgIterStart {
-    type Item = CpgIter;
+    type Item = Result<CpgIter>;
     type IntoIter = CpgIntoIter;
 
     fn into_iter(self) -> Self::IntoIter {
+        let iter_handle = self.iter_handle;
+        // The CpgIntoIter takes over finalizing the iteration
+        std::mem::forget(self);
         CpgIntoIter {
-            iter_handle: self.iter_handle,
+            iter_handle,
+            finished: false,
+        }
+    }
+}
+
+/// Get all the CPG groups in the cluster and their members, using a [CpgIterStart].
+/// The reason field of the returned [Address]es is always Undefined.
+pub fn cluster_groups(handle: Handle) -> Result<BTreeMap<CpgName, Vec<Address>>> {
+    let mut groups = BTreeMap::<CpgName, Vec<Address>>::new();
+    for i in CpgIterStart::new(handle, "", CpgIterType::All)? {
+        let i = i?;
+        groups.entry(i.group).or_default().push(Address {
+            nodeid: i.nodeid,
+            pid: i.pid,
+            reason: Reason::Undefined,
+        });
+    }
+    Ok(groups)
+}
+
+/// A change in the cluster's CPG groups, reported by [GroupWatcher]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GroupEvent {
+    GroupCreated(CpgName),
+    GroupEmptied(CpgName),
+    ProcessJoined {
+        group: CpgName,
+        nodeid: NodeId,
+        pid: u32,
+    },
+    ProcessLeft {
+        group: CpgName,
+        nodeid: NodeId,
+        pid: u32,
+    },
+}
+
+/// Watches all the CPG groups in the cluster by taking regular snapshots with
+/// [cluster_groups] and reporting the differences. Changes that happen and are
+/// reversed between two snapshots will not be seen.
+pub struct GroupWatcher {
+    handle: Handle,
+    groups: BTreeMap<CpgName, Vec<Address>>,
+}
+
+fn group_diff(
+    old: &BTreeMap<CpgName, Vec<Address>>,
+    new: &BTreeMap<CpgName, Vec<Address>>,
+) -> Vec<GroupEvent> {
+    let contains =
+        |list: &[Address], a: &Address| list.iter().any(|m| m.nodeid == a.nodeid && m.pid == a.pid);
+    let empty = Vec::new();
+    let mut events = Vec::new();
+
+    for (group, members) in new {
+        let old_members = old.get(group).unwrap_or(&empty);
+        if !old.contains_key(group) {
+            events.push(GroupEvent::GroupCreated(group.clone()));
+        }
+        for m in members.iter().filter(|m| !contains(old_members, m)) {
+            events.push(GroupEvent::ProcessJoined {
+                group: group.clone(),
+                nodeid: m.nodeid,
+                pid: m.pid,
+            });
+        }
+    }
+    for (group, members) in old {
+        let new_members = new.get(group).unwrap_or(&empty);
+        for m in members.iter().filter(|m| !contains(new_members, m)) {
+            events.push(GroupEvent::ProcessLeft {
+                group: group.clone(),
+                nodeid: m.nodeid,
+                pid: m.pid,
+            });
+        }
+        if !new.contains_key(group) {
+            events.push(GroupEvent::GroupEmptied(group.clone()));
+        }
+    }
+    events
+}
+
+impl GroupWatcher {
+    /// Create a watcher and take the first snapshot. No events are reported for
+    /// groups that exist at this point, see [GroupWatcher::groups]
+    pub fn new(handle: Handle) -> Result<GroupWatcher> {
+        Ok(GroupWatcher {
+            handle,
+            groups: cluster_groups(handle)?,
+        })
+    }
+
+    /// The groups as of the last snapshot
+    pub fn groups(&self) -> &BTreeMap<CpgName, Vec<Address>> {
+        &self.groups
+    }
+
+    /// Take a new snapshot and return what has changed since the last one
+    pub fn poll(&mut self) -> Result<Vec<GroupEvent>> {
+        let new = cluster_groups(self.handle)?;
+        let events = group_diff(&self.groups, &new);
+        self.groups = new;
+        Ok(events)
+    }
+
+    /// Poll every `interval` on a new thread, sending the events (or errors) down the
+    /// channel in the returned [GroupWatch]. The thread is stopped when that is dropped.
+    pub fn watch(mut self, interval: Duration) -> GroupWatch {
+        let (tx, rx) = channel();
+        let (stop_tx, stop_rx) = channel::<()>();
+        let thread = thread::spawn(move || loop {
+            // Nothing is ever sent, this just waits for the sender to go
+            match stop_rx.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => return,
+            }
+            let events = match self.poll() {
+                Ok(events) => events.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            };
+            for e in events {
+                if tx.send(e).is_err() {
+                    return;
+                }
+            }
+        });
+        GroupWatch {
+            events: rx,
+            stop: Some(stop_tx),
+            thread: Some(thread),
+        }
+    }
+}
+
+/// A [GroupWatcher] polling on its own thread, returned by [GroupWatcher::watch]
+pub struct GroupWatch {
+    events: Receiver<Result<GroupEvent>>,
+    stop: Option<Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl GroupWatch {
+    /// The changes found by each poll, or the error if a poll failed
+    pub fn events(&self) -> &Receiver<Result<GroupEvent>> {
+        &self.events
+    }
+}
+
+impl Drop for GroupWatch {
+    fn drop(&mut self) {
+        // Wakes the thread up, it finishes any poll it is in the middle of first
+        drop(self.stop.take());
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
         }
     }
 }
@@ -0,0 +1,551 @@
+// Distributed lock manager built on CPG
+// Copyright (c) 2021 Red Hat, Inc.
+//
+// All rights reserved.
+//
+// Author: Christine Caulfield (ccaulfi@redhat.com)
+//
+
+//! Every member of the group keeps an identical copy of the lock table and grants
+//! requests independently, no master is needed.
+//!
+//! Locks are owned by a process (nodeid/pid) and are released automatically when that
+//! process leaves the group, whether cleanly or because it or its node died.
+//!
+//! When a process joins a group that already has members, the existing member with the lowest
+//! nodeid (then pid) sends it a copy of the lock table. Requests arriving in the meantime are
+//! queued and replayed on top of it.
+//!
+//! Blocking calls dispatch CPG callbacks on the lock manager's handle themselves, so
+//! no separate dispatch thread is needed. When idle, call [crate::dlm::LockManager::dispatch]
+//! now and again so that other members' requests and membership changes are processed.
+//!
+//! No attempt is made to handle a partitioned cluster, each partition will carry
+//! on granting locks. Use quorum to prevent that if it matters.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::cpg;
+use crate::transfer::{Action, Owner, Transfer};
+use crate::wire::{Reader, Writer};
+use crate::{CsError, DispatchFlags, NodeId, Result};
+
+/// The type of lock to take
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LockMode {
+    /// Any number of processes can hold a Shared lock at the same time
+    Shared,
+    /// Only one process can hold an Exclusive lock, and no Shared locks will be granted
+    Exclusive,
+}
+
+impl fmt::Display for LockMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LockMode::Shared => write!(f, "Shared"),
+            LockMode::Exclusive => write!(f, "Exclusive"),
+        }
+    }
+}
+
+/// A process holding a lock, returned from [LockManager::holders]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Holder {
+    pub nodeid: NodeId,
+    pub pid: u32,
+    pub mode: LockMode,
+}
+
+// Message types
+const MSG_LOCK: u8 = 1;
+const MSG_UNLOCK: u8 = 2;
+const MSG_CANCEL: u8 = 3;
+const MSG_STATE: u8 = 4;
+
+#[derive(Copy, Clone)]
+struct Request {
+    owner: Owner,
+    mode: LockMode,
+    id: u64,
+}
+
+#[derive(Default)]
+struct Lock {
+    holders: Vec<Request>,
+    waiters: VecDeque<Request>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Outcome {
+    Granted,
+    Denied,
+    Exists,
+}
+
+// Things that change the lock table, queued up while we wait for the
+// initial copy of it.
+enum Event {
+    Message(Owner, Vec<u8>),
+    Left(Vec<Owner>),
+}
+
+struct State {
+    xfer: Transfer,
+    locks: BTreeMap<String, Lock>,
+    pending: Vec<Event>,
+    next_id: u64,
+    // Results of our own requests, keyed by request id
+    outcomes: HashMap<u64, Outcome>,
+}
+
+lazy_static! {
+    static ref MANAGER_HASH: Mutex<HashMap<u64, Arc<Mutex<State>>>> = Mutex::new(HashMap::new());
+}
+
+fn mode_to_u8(mode: LockMode) -> u8 {
+    match mode {
+        LockMode::Shared => 0,
+        LockMode::Exclusive => 1,
+    }
+}
+
+fn mode_from_u8(mode: u8) -> LockMode {
+    match mode {
+        0 => LockMode::Shared,
+        _ => LockMode::Exclusive,
+    }
+}
+
+impl Lock {
+    fn compatible(&self, mode: LockMode) -> bool {
+        match mode {
+            LockMode::Exclusive => self.holders.is_empty(),
+            LockMode::Shared => self.holders.iter().all(|h| h.mode == LockMode::Shared),
+        }
+    }
+
+    fn involves(&self, owner: Owner) -> bool {
+        self.holders.iter().any(|r| r.owner == owner)
+            || self.waiters.iter().any(|r| r.owner == owner)
+    }
+}
+
+impl State {
+    fn outcome(&mut self, owner: Owner, id: u64, outcome: Outcome) {
+        if owner == self.xfer.me {
+            self.outcomes.insert(id, outcome);
+        }
+    }
+
+    // Grant waiting requests in strict FIFO order, so that a stream of
+    // Shared requests can't starve an Exclusive one
+    fn grant(&mut self, name: &str) {
+        let mut granted = Vec::new();
+        if let Some(lock) = self.locks.get_mut(name) {
+            while let Some(req) = lock.waiters.front() {
+                if !lock.compatible(req.mode) {
+                    break;
+                }
+                let req = lock.waiters.pop_front().unwrap();
+                lock.holders.push(req);
+                granted.push(req);
+            }
+            if lock.holders.is_empty() && lock.waiters.is_empty() {
+                self.locks.remove(name);
+            }
+        }
+        for req in granted {
+            self.outcome(req.owner, req.id, Outcome::Granted);
+        }
+    }
+
+    fn apply_message(&mut self, from: Owner, msg: &[u8]) -> Result<()> {
+        let mut r = Reader::new(msg);
+        match r.get_u8()? {
+            MSG_LOCK => {
+                let name = r.get_str()?;
+                let req = Request {
+                    owner: from,
+                    mode: mode_from_u8(r.get_u8()?),
+                    id: r.get_u64()?,
+                };
+                let try_only = r.get_bool()?;
+                let lock = self.locks.entry(name.clone()).or_default();
+                if lock.involves(from) {
+                    self.outcome(from, req.id, Outcome::Exists);
+                } else if try_only && !(lock.waiters.is_empty() && lock.compatible(req.mode)) {
+                    self.outcome(from, req.id, Outcome::Denied);
+                } else {
+                    lock.waiters.push_back(req);
+                }
+                self.grant(&name);
+            }
+            MSG_UNLOCK => {
+                let name = r.get_str()?;
+                if let Some(lock) = self.locks.get_mut(&name) {
+                    lock.holders.retain(|h| h.owner != from);
+                }
+                self.grant(&name);
+            }
+            MSG_CANCEL => {
+                // If the request was granted before the cancel arrived then
+                // this acts as an unlock.
+                let name = r.get_str()?;
+                let id = r.get_u64()?;
+                if let Some(lock) = self.locks.get_mut(&name) {
+                    lock.waiters.retain(|w| !(w.owner == from && w.id == id));
+                    lock.holders.retain(|h| !(h.owner == from && h.id == id));
+                }
+                if from == self.xfer.me {
+                    self.outcomes.remove(&id);
+                }
+                self.grant(&name);
+            }
+            _ => return Err(CsError::CsErrMessageError),
+        }
+        Ok(())
+    }
+
+    fn apply_left(&mut self, left: &[Owner]) {
+        let names: Vec<String> = self.locks.keys().cloned().collect();
+        for name in names {
+            if let Some(lock) = self.locks.get_mut(&name) {
+                lock.holders.retain(|h| !left.contains(&h.owner));
+                lock.waiters.retain(|w| !left.contains(&w.owner));
+            }
+            self.grant(&name);
+        }
+    }
+
+    fn encode_table(&self, recipients: &BTreeSet<Owner>) -> Writer {
+        let mut w = Transfer::state_message(MSG_STATE, recipients);
+        w.put_u32(self.locks.len() as u32);
+        for (name, lock) in &self.locks {
+            w.put_str(name);
+            put_requests(&mut w, lock.holders.iter());
+            put_requests(&mut w, lock.waiters.iter());
+        }
+        w
+    }
+
+    fn install_table(&mut self, r: &mut Reader) -> Result<()> {
+        let mut locks = BTreeMap::new();
+        for _ in 0..r.get_u32()? {
+            let name = r.get_str()?;
+            let mut lock = Lock::default();
+            for list in 0..2 {
+                for _ in 0..r.get_u32()? {
+                    let req = Request {
+                        owner: (r.get_nodeid()?, r.get_u32()?),
+                        mode: mode_from_u8(r.get_u8()?),
+                        id: r.get_u64()?,
+                    };
+                    if list == 0 {
+                        lock.holders.push(req);
+                    } else {
+                        lock.waiters.push_back(req);
+                    }
+                }
+            }
+            locks.insert(name, lock);
+        }
+        self.locks = locks;
+        Ok(())
+    }
+
+    fn apply_state(&mut self, msg: &[u8]) -> Result<()> {
+        let mut r = Reader::new(msg);
+        if self.xfer.state_received(&mut r)? {
+            self.install_table(&mut r)?;
+            self.set_synced();
+        }
+        Ok(())
+    }
+
+    fn set_synced(&mut self) {
+        self.xfer.synced = true;
+        for ev in std::mem::take(&mut self.pending) {
+            match ev {
+                Event::Message(from, msg) => {
+                    let _ = self.apply_message(from, &msg);
+                }
+                Event::Left(left) => self.apply_left(&left),
+            }
+        }
+    }
+}
+
+fn put_requests<'a, I: ExactSizeIterator<Item = &'a Request>>(w: &mut Writer, list: I) {
+    w.put_u32(list.len() as u32);
+    for req in list {
+        w.put_nodeid(req.owner.0)
+            .put_u32(req.owner.1)
+            .put_u8(mode_to_u8(req.mode))
+            .put_u64(req.id);
+    }
+}
+
+fn to_owners(list: &[cpg::Address]) -> Vec<Owner> {
+    list.iter().map(|a| (a.nodeid, a.pid)).collect()
+}
+
+fn find_state(handle: &cpg::Handle) -> Option<Arc<Mutex<State>>> {
+    MANAGER_HASH
+        .lock()
+        .unwrap()
+        .get(&handle.cpg_handle)
+        .cloned()
+}
+
+fn dlm_deliver_fn(
+    handle: &cpg::Handle,
+    _group_name: cpg::CpgName,
+    nodeid: NodeId,
+    pid: u32,
+    msg: &[u8],
+    _msg_len: usize,
+) {
+    if let Some(state) = find_state(handle) {
+        let mut state = state.lock().unwrap();
+        if !msg.is_empty() && msg[0] == MSG_STATE {
+            let _ = state.apply_state(msg);
+        } else if state.xfer.synced {
+            // Nowhere to report a garbled message, and it will be garbled for everyone
+            let _ = state.apply_message((nodeid, pid), msg);
+        } else {
+            state
+                .pending
+                .push(Event::Message((nodeid, pid), msg.to_vec()));
+        }
+    }
+}
+
+fn dlm_confchg_fn(
+    handle: &cpg::Handle,
+    _group_name: &cpg::CpgName,
+    member_list: Vec<cpg::Address>,
+    left_list: Vec<cpg::Address>,
+    joined_list: Vec<cpg::Address>,
+) {
+    if let Some(state) = find_state(handle) {
+        let table = {
+            let mut state = state.lock().unwrap();
+            let left = to_owners(&left_list);
+            let joined = to_owners(&joined_list);
+
+            let action = state.xfer.confchg(&to_owners(&member_list), &left, &joined);
+
+            if state.xfer.synced {
+                state.apply_left(&left);
+            } else {
+                state.pending.push(Event::Left(left));
+            }
+
+            match action {
+                Action::SendState(recipients) => Some(state.encode_table(&recipients)),
+                Action::Initial(recipients) => {
+                    // Nobody left who has the lock table, so everyone starts from empty.
+                    if !state.xfer.synced {
+                        state.set_synced();
+                    }
+                    if recipients.is_empty() {
+                        None
+                    } else {
+                        Some(state.encode_table(&recipients))
+                    }
+                }
+                Action::Nothing => None,
+            }
+        };
+        // Sent once the state is unlocked, as this can sleep while corosync applies flow control.
+        // Nothing else is delivered until we return, so the table is still current.
+        if let Some(w) = table {
+            let _ = cpg::mcast_joined_retry(*handle, cpg::Guarantee::TypeAgreed, w.as_slice());
+        }
+    }
+}
+
+/// A connection to a distributed lock manager group, created with [LockManager::new].
+/// The group is left, releasing any locks we hold, when this is dropped or
+/// explicitly with [LockManager::finalize]
+pub struct LockManager {
+    handle: cpg::Handle,
+    group: String,
+    state: Arc<Mutex<State>>,
+    closed: bool,
+}
+
+impl LockManager {
+    /// Join the CPG group `group` and start taking part in lock management for it.
+    /// All processes sharing locks must use the same group name.
+    pub fn new(group: &str) -> Result<LockManager> {
+        let md = cpg::ModelData::ModelV1(cpg::Model1Data {
+            flags: cpg::Model1Flags::None,
+            deliver_fn: Some(dlm_deliver_fn),
+            confchg_fn: Some(dlm_confchg_fn),
+            totem_confchg_fn: None,
+        });
+        let handle = cpg::initialize(&md, 0)?;
+        let nodeid = match cpg::local_get(handle) {
+            Ok(n) => n,
+            Err(e) => {
+                let _ = cpg::finalize(handle);
+                return Err(e);
+            }
+        };
+        let state = Arc::new(Mutex::new(State {
+            xfer: Transfer::new((nodeid, std::process::id())),
+            locks: BTreeMap::new(),
+            pending: Vec::new(),
+            next_id: 1,
+            outcomes: HashMap::new(),
+        }));
+        MANAGER_HASH
+            .lock()
+            .unwrap()
+            .insert(handle.cpg_handle, state.clone());
+
+        if let Err(e) = cpg::join(handle, group) {
+            MANAGER_HASH.lock().unwrap().remove(&handle.cpg_handle);
+            let _ = cpg::finalize(handle);
+            return Err(e);
+        }
+        Ok(LockManager {
+            handle,
+            group: group.to_string(),
+            state,
+            closed: false,
+        })
+    }
+
+    /// The CPG [cpg::Handle] used by this lock manager
+    pub fn handle(&self) -> cpg::Handle {
+        self.handle
+    }
+
+    /// Process incoming lock traffic, see [cpg::dispatch]
+    pub fn dispatch(&self, flags: DispatchFlags) -> Result<()> {
+        cpg::dispatch(self.handle, flags)
+    }
+
+    fn send_request(&self, name: &str, mode: LockMode, try_only: bool) -> Result<u64> {
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            state.next_id += 1;
+            state.next_id
+        };
+        let mut w = Writer::new(MSG_LOCK);
+        w.put_str(name)
+            .put_u8(mode_to_u8(mode))
+            .put_u64(id)
+            .put_bool(try_only);
+        cpg::mcast_joined_retry(self.handle, cpg::Guarantee::TypeAgreed, w.as_slice())?;
+        Ok(id)
+    }
+
+    // Wait for the result of one of our requests to come back
+    fn wait_outcome(&self, id: u64, deadline: Option<Instant>) -> Result<Option<Outcome>> {
+        let mut outcome = None;
+        cpg::dispatch_until(self.handle, deadline, || {
+            outcome = self.state.lock().unwrap().outcomes.remove(&id);
+            outcome.is_some()
+        })?;
+        Ok(outcome)
+    }
+
+    // Withdraw a request we have given up waiting for. If it gets granted before
+    // the cancel arrives then the cancel releases it again.
+    fn cancel(&self, name: &str, id: u64) -> Result<()> {
+        let mut w = Writer::new(MSG_CANCEL);
+        w.put_str(name).put_u64(id);
+        cpg::mcast_joined_retry(self.handle, cpg::Guarantee::TypeAgreed, w.as_slice())
+    }
+
+    /// Try to take the lock `name` without queueing for it. Returns Ok(false) if the
+    /// lock is held in an incompatible mode or other processes are already waiting for it.
+    /// `timeout` limits how long we wait for the request to go round the group, if it
+    /// expires CsErrTimeout is returned.
+    pub fn try_lock(&self, name: &str, mode: LockMode, timeout: Duration) -> Result<bool> {
+        let id = self.send_request(name, mode, true)?;
+        match self.wait_outcome(id, Some(Instant::now() + timeout))? {
+            Some(Outcome::Granted) => Ok(true),
+            Some(Outcome::Exists) => Err(CsError::CsErrExist),
+            Some(Outcome::Denied) => Ok(false),
+            None => {
+                self.cancel(name, id)?;
+                Err(CsError::CsErrTimeout)
+            }
+        }
+    }
+
+    /// Take the lock `name`, waiting for up to `timeout` for it to become available.
+    /// Returns CsErrTimeout if the lock could not be granted in time, or
+    /// CsErrExist if we already hold, or are waiting for, this lock.
+    pub fn lock(&self, name: &str, mode: LockMode, timeout: Duration) -> Result<()> {
+        let id = self.send_request(name, mode, false)?;
+        match self.wait_outcome(id, Some(Instant::now() + timeout))? {
+            Some(Outcome::Granted) => Ok(()),
+            Some(Outcome::Exists) => Err(CsError::CsErrExist),
+            Some(Outcome::Denied) => Err(CsError::CsErrFailedOperation),
+            None => {
+                self.cancel(name, id)?;
+                Err(CsError::CsErrTimeout)
+            }
+        }
+    }
+
+    /// Release a lock we hold
+    pub fn unlock(&self, name: &str) -> Result<()> {
+        {
+            let state = self.state.lock().unwrap();
+            let me = state.xfer.me;
+            match state.locks.get(name) {
+                Some(lock) if lock.holders.iter().any(|h| h.owner == me) => {}
+                _ => return Err(CsError::CsErrNotExist),
+            }
+        }
+        let mut w = Writer::new(MSG_UNLOCK);
+        w.put_str(name);
+        cpg::mcast_joined_retry(self.handle, cpg::Guarantee::TypeAgreed, w.as_slice())
+    }
+
+    /// Return the current holders of a lock as seen by this member
+    pub fn holders(&self, name: &str) -> Vec<Holder> {
+        match self.state.lock().unwrap().locks.get(name) {
+            Some(lock) => lock
+                .holders
+                .iter()
+                .map(|h| Holder {
+                    nodeid: h.owner.0,
+                    pid: h.owner.1,
+                    mode: h.mode,
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.closed = true;
+        let _ = cpg::leave(self.handle, &self.group);
+        MANAGER_HASH.lock().unwrap().remove(&self.handle.cpg_handle);
+        cpg::finalize(self.handle)
+    }
+
+    /// Leave the group and close the CPG connection. Any locks we hold are released
+    pub fn finalize(mut self) -> Result<()> {
+        self.close()
+    }
+}
+
+impl Drop for LockManager {
+    fn drop(&mut self) {
+        if !self.closed {
+            // Nowhere to report an error
+            let _ = self.close();
+        }
+    }
+}
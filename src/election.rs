@@ -0,0 +1,334 @@
+// Leader election over a CPG group
+// Copyright (c) 2021 Red Hat, Inc.
+//
+// All rights reserved.
+//
+// Author: Christine Caulfield (ccaulfi@redhat.com)
+//
+
+//! Each member broadcasts its priority when it joins, and existing members re-broadcast
+//! theirs (along with the current leader) whenever someone new arrives. The leader is the
+//! member with the highest priority, ties going to the lowest nodeid then pid.
+//!
+//! With [crate::election::Policy::Sticky] a leader stays leader for as long as it is in the
+//! group, even if a member with a higher priority joins. This stops leadership bouncing around
+//! while nodes come and go during totem ring changes. When two partitions merge each side
+//! brings its own leader; every member sees both announced, and the better of them by the
+//! usual priority/nodeid/pid order keeps the job. [crate::election::Policy::Preemptive]
+//! always picks the best member.
+//!
+//! A member does not report any leader until it knows the priority of every member, so a
+//! newly joined process never briefly thinks it is the leader.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::cpg;
+use crate::wire::{Reader, Writer};
+use crate::{CsError, DispatchFlags, NodeId, Result};
+
+/// How to treat a running leader when a better candidate joins
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Keep the current leader until it leaves the group
+    Sticky,
+    /// Always elect the member with the best priority
+    Preemptive,
+}
+
+/// The process that has been elected leader
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Leader {
+    pub nodeid: NodeId,
+    pub pid: u32,
+}
+
+impl fmt::Display for Leader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.nodeid, self.pid)
+    }
+}
+
+const MSG_ANNOUNCE: u8 = 1;
+
+type Owner = (NodeId, u32);
+
+struct State {
+    me: Owner,
+    priority: u32,
+    policy: Policy,
+    members: BTreeSet<Owner>,
+    priorities: HashMap<Owner, u32>,
+    leader: Option<Owner>,
+    // Leaders announced by other members and not yet resolved against ours
+    claims: BTreeSet<Owner>,
+    listeners: Vec<Sender<Option<Leader>>>,
+}
+
+lazy_static! {
+    static ref ELECTION_HASH: Mutex<HashMap<u64, Arc<Mutex<State>>>> = Mutex::new(HashMap::new());
+}
+
+fn to_leader(owner: Option<Owner>) -> Option<Leader> {
+    owner.map(|(nodeid, pid)| Leader { nodeid, pid })
+}
+
+impl State {
+    fn announcement(&self) -> Writer {
+        let mut w = Writer::new(MSG_ANNOUNCE);
+        w.put_u32(self.priority);
+        match self.leader {
+            Some((nodeid, pid)) => w.put_bool(true).put_nodeid(nodeid).put_u32(pid),
+            None => w.put_bool(false).put_nodeid(NodeId::from(0)).put_u32(0),
+        };
+        w
+    }
+
+    // Best of some members: highest priority, then lowest nodeid/pid
+    fn best<'a>(&self, owners: impl Iterator<Item = &'a Owner>) -> Option<Owner> {
+        owners
+            .max_by(|a, b| {
+                self.priorities[a]
+                    .cmp(&self.priorities[b])
+                    .then_with(|| b.cmp(a))
+            })
+            .copied()
+    }
+
+    fn candidate(&self) -> Option<Owner> {
+        self.best(self.members.iter())
+    }
+
+    fn recalculate(&mut self) {
+        let old = self.leader;
+        let incumbent = self.leader.filter(|l| self.members.contains(l));
+        let all_known = self.members.iter().all(|m| self.priorities.contains_key(m));
+
+        self.leader = if !all_known {
+            incumbent
+        } else {
+            // Every member sees the same claims in the same order, so they all
+            // settle on the same incumbent
+            let claims = std::mem::take(&mut self.claims);
+            let incumbent = self.best(
+                claims
+                    .iter()
+                    .chain(incumbent.iter())
+                    .filter(|l| self.members.contains(l)),
+            );
+            match (self.policy, incumbent) {
+                (Policy::Sticky, Some(l)) => Some(l),
+                _ => self.candidate(),
+            }
+        };
+
+        if self.leader != old {
+            let new = to_leader(self.leader);
+            self.listeners.retain(|l| l.send(new).is_ok());
+        }
+    }
+
+    fn apply_announce(&mut self, from: Owner, msg: &[u8]) -> Result<()> {
+        let mut r = Reader::new(msg);
+        if r.get_u8()? != MSG_ANNOUNCE {
+            return Err(CsError::CsErrMessageError);
+        }
+        let priority = r.get_u32()?;
+        let has_leader = r.get_bool()?;
+        let leader = (r.get_nodeid()?, r.get_u32()?);
+
+        if self.members.contains(&from) {
+            self.priorities.insert(from, priority);
+        }
+        // A newcomer learns who the incumbent is from the existing members, and after a
+        // merge each side learns about the other's leader
+        if self.policy == Policy::Sticky && has_leader && self.members.contains(&leader) {
+            self.claims.insert(leader);
+        }
+        self.recalculate();
+        Ok(())
+    }
+}
+
+fn find_state(handle: &cpg::Handle) -> Option<Arc<Mutex<State>>> {
+    ELECTION_HASH
+        .lock()
+        .unwrap()
+        .get(&handle.cpg_handle)
+        .cloned()
+}
+
+fn election_deliver_fn(
+    handle: &cpg::Handle,
+    _group_name: cpg::CpgName,
+    nodeid: NodeId,
+    pid: u32,
+    msg: &[u8],
+    _msg_len: usize,
+) {
+    if let Some(state) = find_state(handle) {
+        let _ = state.lock().unwrap().apply_announce((nodeid, pid), msg);
+    }
+}
+
+fn election_confchg_fn(
+    handle: &cpg::Handle,
+    _group_name: &cpg::CpgName,
+    member_list: Vec<cpg::Address>,
+    left_list: Vec<cpg::Address>,
+    joined_list: Vec<cpg::Address>,
+) {
+    if let Some(state) = find_state(handle) {
+        let announcement = {
+            let mut state = state.lock().unwrap();
+            state.members = member_list.iter().map(|a| (a.nodeid, a.pid)).collect();
+            for a in &left_list {
+                state.priorities.remove(&(a.nodeid, a.pid));
+            }
+            state.recalculate();
+
+            // Let new members know who we are, and who we think is in charge
+            if joined_list.is_empty() {
+                None
+            } else {
+                Some(state.announcement())
+            }
+        };
+        // Sent once the state is unlocked, as this can sleep while corosync applies flow control
+        if let Some(w) = announcement {
+            let _ = cpg::mcast_joined_retry(*handle, cpg::Guarantee::TypeAgreed, w.as_slice());
+        }
+    }
+}
+
+/// Membership of a leader election group, created with [Election::new].
+/// The group is left when this is dropped or explicitly with [Election::finalize]
+pub struct Election {
+    handle: cpg::Handle,
+    group: String,
+    state: Arc<Mutex<State>>,
+    closed: bool,
+}
+
+impl Election {
+    /// Join the election group `group` with the given priority, higher priorities
+    /// are preferred as leader
+    pub fn new(group: &str, priority: u32, policy: Policy) -> Result<Election> {
+        let md = cpg::ModelData::ModelV1(cpg::Model1Data {
+            flags: cpg::Model1Flags::None,
+            deliver_fn: Some(election_deliver_fn),
+            confchg_fn: Some(election_confchg_fn),
+            totem_confchg_fn: None,
+        });
+        let handle = cpg::initialize(&md, 0)?;
+        let nodeid = match cpg::local_get(handle) {
+            Ok(n) => n,
+            Err(e) => {
+                let _ = cpg::finalize(handle);
+                return Err(e);
+            }
+        };
+        let state = Arc::new(Mutex::new(State {
+            me: (nodeid, std::process::id()),
+            priority,
+            policy,
+            members: BTreeSet::new(),
+            priorities: HashMap::new(),
+            leader: None,
+            claims: BTreeSet::new(),
+            listeners: Vec::new(),
+        }));
+        ELECTION_HASH
+            .lock()
+            .unwrap()
+            .insert(handle.cpg_handle, state.clone());
+
+        if let Err(e) = cpg::join(handle, group) {
+            ELECTION_HASH.lock().unwrap().remove(&handle.cpg_handle);
+            let _ = cpg::finalize(handle);
+            return Err(e);
+        }
+        Ok(Election {
+            handle,
+            group: group.to_string(),
+            state,
+            closed: false,
+        })
+    }
+
+    /// The CPG [cpg::Handle] used by this election
+    pub fn handle(&self) -> cpg::Handle {
+        self.handle
+    }
+
+    /// Process incoming election traffic, see [cpg::dispatch]
+    pub fn dispatch(&self, flags: DispatchFlags) -> Result<()> {
+        cpg::dispatch(self.handle, flags)
+    }
+
+    /// The current leader, if one has been elected
+    pub fn leader(&self) -> Option<Leader> {
+        to_leader(self.state.lock().unwrap().leader)
+    }
+
+    /// Returns true if this process is the current leader
+    pub fn is_leader(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.leader == Some(state.me)
+    }
+
+    /// Returns a channel that receives the new leader every time it changes.
+    /// None is sent if the leader left and no replacement is known yet.
+    pub fn subscribe(&self) -> Receiver<Option<Leader>> {
+        let (tx, rx) = channel();
+        self.state.lock().unwrap().listeners.push(tx);
+        rx
+    }
+
+    /// Dispatch callbacks until a leader has been elected or `timeout` expires
+    pub fn wait_for_leader(&self, timeout: Duration) -> Result<Leader> {
+        let mut leader = None;
+        cpg::dispatch_until(self.handle, Some(Instant::now() + timeout), || {
+            leader = self.leader();
+            leader.is_some()
+        })?;
+        leader.ok_or(CsError::CsErrTimeout)
+    }
+
+    /// Change our priority and tell the other members about it
+    pub fn set_priority(&self, priority: u32) -> Result<()> {
+        let w = {
+            let mut state = self.state.lock().unwrap();
+            state.priority = priority;
+            state.announcement()
+        };
+        cpg::mcast_joined_retry(self.handle, cpg::Guarantee::TypeAgreed, w.as_slice())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.closed = true;
+        let _ = cpg::leave(self.handle, &self.group);
+        ELECTION_HASH
+            .lock()
+            .unwrap()
+            .remove(&self.handle.cpg_handle);
+        cpg::finalize(self.handle)
+    }
+
+    /// Leave the election group and close the CPG connection
+    pub fn finalize(mut self) -> Result<()> {
+        self.close()
+    }
+}
+
+impl Drop for Election {
+    fn drop(&mut self) {
+        if !self.closed {
+            // Nowhere to report an error
+            let _ = self.close();
+        }
+    }
+}
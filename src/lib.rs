@@ -38,8 +38,8 @@
 //!         }
 //!     };
 //!
-//!     // Get a value - this will be a Data struct
-//!     match cmap::get(handle, "test.test_uint32")
+//!     // Get a value - as a Data struct this could be any type
+//!     match cmap::get::<cmap::Data>(handle, "test.test_uint32")
 //!     {
 //!         Ok(v) => {
 //!             println!("GOT value {}", v);
@@ -79,6 +79,10 @@ extern crate lazy_static;
 #[macro_use]
 extern crate bitflags;
 
+/// barrier lets the members of a CPG group wait for each other to reach the same point.
+/// Arrivals are sent with agreed ordering so every member decides the same way when
+/// a barrier is complete, even if members leave part way through.
+pub mod barrier;
 /// cfg is the internal configuration and information library for corosync, it is
 /// mainly used by internal tools but may also contain API calls useful to some applications
 /// that need detailed information about or control of the operation of corosync and the cluster.
@@ -92,14 +96,41 @@ pub mod cmap;
 /// messages around the cluster. All processes using CPG belong to a named group (whose members
 /// they can query) and all messages are sent with delivery guarantees.
 pub mod cpg;
+/// dlm is a simple distributed lock manager built on a CPG group. Because all lock requests
+/// are sent with agreed ordering every member sees the same sequence of requests and so
+/// can grant them without any further negotiation.
+pub mod dlm;
+/// election picks a single leader from the members of a CPG group, using priorities
+/// broadcast by each member. All members agree on the result.
+pub mod election;
+/// partition interprets totem configuration changes and quorum state, telling
+/// node failures apart from network partitions and spotting when a partition heals.
+pub mod partition;
+/// pubsub carries many named topics over a single CPG group. Members only receive the
+/// topics they subscribe to, and nothing is sent for topics that nobody wants.
+pub mod pubsub;
 /// Quorum provides basic information about the quorate state of the cluster with callbacks
 /// when nodelists change.
 pub mod quorum;
+/// record writes the events delivered on a CPG handle to a file, and replays them
+/// through the same callbacks later so that problems can be reproduced.
+pub mod record;
+/// replicated keeps a copy of some application state on every member of a CPG group,
+/// applying operations in agreed order and transferring the state to new members as they join.
+pub mod replicated;
+/// sender provides a buffered CPG sender that queues messages while corosync is applying
+/// flow control, rather than making the caller retry.
+pub mod sender;
+/// sharding divides keys between the members of a CPG group using a consistent hash ring,
+/// so every member agrees on who owns each key and membership changes move as few keys as possible.
+pub mod sharding;
 ///votequorum is the main quorum provider for corosync, using this API, users can query the state
 /// of nodes in the cluster, request callbacks when the nodelists change, and set up a quorum device.
 pub mod votequorum;
 
 mod sys;
+mod transfer;
+mod wire;
 
 use num_enum::TryFromPrimitive;
 use std::convert::TryFrom;
@@ -230,7 +261,7 @@ pub enum TrackFlags {
 }
 
 /// A corosync nodeid
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct NodeId {
     id: u32,
 }
@@ -0,0 +1,247 @@
+// Partition and merge detection from totem configuration changes
+// Copyright (c) 2021 Red Hat, Inc.
+//
+// All rights reserved.
+//
+// Author: Christine Caulfield (ccaulfi@redhat.com)
+//
+
+//! A [crate::partition::PartitionTracker] is fed the totem configurations from a CPG
+//! totem_confchg callback, and the quorum state from the quorum or votequorum callbacks, and
+//! works out what each change means for the application.
+//!
+//! Nodes that disappear while we stay quorate are a [crate::partition::Transition::NodeLoss].
+//! If we lose quorum as well then we have been partitioned from them
+//! ([crate::partition::Transition::Partition]), and they may have carried on working without
+//! us. When any of those nodes come back a [crate::partition::Transition::Merge] is reported
+//! so that the application can reconcile whatever was changed on both sides while the
+//! cluster was split.
+//!
+//! If quorum state is never supplied then losing half or more of the largest
+//! membership seen so far is counted as a partition.
+//!
+//! The tracker does no I/O, it is up to the caller to pass events in from whatever
+//! callbacks or channels it is already using.
+
+use std::collections::BTreeSet;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::cpg::RingId;
+use crate::NodeId;
+
+/// What a totem configuration change (or quorum change) meant
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Transition {
+    /// The first configuration seen
+    Initial {
+        ring_id: RingId,
+        members: Vec<NodeId>,
+    },
+    /// New nodes joined that we had not been partitioned from
+    NodesJoined {
+        ring_id: RingId,
+        joined: Vec<NodeId>,
+    },
+    /// Nodes left while we stayed quorate
+    NodeLoss { ring_id: RingId, lost: Vec<NodeId> },
+    /// Nodes left and we are no longer quorate, so they may be running on without us
+    Partition { ring_id: RingId, lost: Vec<NodeId> },
+    /// Nodes we were partitioned from have come back. Anything changed since the
+    /// ring `since` may have been changed on both sides.
+    Merge {
+        ring_id: RingId,
+        rejoined: Vec<NodeId>,
+        since: RingId,
+    },
+    /// The new ring id is older than the previous one, which should never happen
+    RingIdRegression { previous: RingId, current: RingId },
+    /// A new ring formed with the same members
+    NoChange { ring_id: RingId },
+}
+
+/// Classifies totem membership changes, create one with [PartitionTracker::new]
+pub struct PartitionTracker {
+    ring_id: Option<RingId>,
+    members: BTreeSet<NodeId>,
+    largest: usize,
+    quorate: Option<bool>,
+    // Nodes lost in the most recent change, in case quorum goes after the ring changes
+    last_lost: Vec<NodeId>,
+    // Nodes we are partitioned from, and the ring in which that started
+    partitioned_from: BTreeSet<NodeId>,
+    partitioned_since: Option<RingId>,
+    listeners: Vec<Sender<Transition>>,
+}
+
+impl Default for PartitionTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PartitionTracker {
+    pub fn new() -> PartitionTracker {
+        PartitionTracker {
+            ring_id: None,
+            members: BTreeSet::new(),
+            largest: 0,
+            quorate: None,
+            last_lost: Vec::new(),
+            partitioned_from: BTreeSet::new(),
+            partitioned_since: None,
+            listeners: Vec::new(),
+        }
+    }
+
+    /// Returns a channel that receives every [Transition] as it is classified
+    pub fn subscribe(&mut self) -> Receiver<Transition> {
+        let (tx, rx) = channel();
+        self.listeners.push(tx);
+        rx
+    }
+
+    /// The current members, as of the last totem configuration
+    pub fn members(&self) -> Vec<NodeId> {
+        self.members.iter().copied().collect()
+    }
+
+    /// The last ring id seen
+    pub fn ring_id(&self) -> Option<RingId> {
+        self.ring_id
+    }
+
+    /// Returns true if there are nodes that we have been partitioned from and
+    /// that have not come back yet
+    pub fn is_partitioned(&self) -> bool {
+        !self.partitioned_from.is_empty()
+    }
+
+    /// The nodes we have been partitioned from
+    pub fn partitioned_from(&self) -> Vec<NodeId> {
+        self.partitioned_from.iter().copied().collect()
+    }
+
+    fn emit(&mut self, transitions: &[Transition]) {
+        for t in transitions {
+            self.listeners.retain(|l| l.send(t.clone()).is_ok());
+        }
+    }
+
+    fn partition(&mut self, ring_id: RingId, lost: Vec<NodeId>) -> Transition {
+        if self.partitioned_from.is_empty() {
+            self.partitioned_since = Some(ring_id);
+        }
+        self.partitioned_from.extend(lost.iter().copied());
+        Transition::Partition { ring_id, lost }
+    }
+
+    fn lost_majority(&self, lost: usize) -> bool {
+        match self.quorate {
+            Some(q) => !q,
+            None => lost * 2 >= self.largest,
+        }
+    }
+
+    /// Pass in a totem configuration change, from a cpg totem_confchg_fn.
+    /// Returns what it meant; one change can mean several things, eg some nodes
+    /// coming back after a partition while others leave.
+    pub fn totem_confchg(&mut self, ring_id: RingId, members: &[NodeId]) -> Vec<Transition> {
+        let new: BTreeSet<NodeId> = members.iter().copied().collect();
+        let mut transitions = Vec::new();
+
+        let previous = match self.ring_id {
+            Some(p) => p,
+            None => {
+                self.ring_id = Some(ring_id);
+                self.largest = new.len();
+                self.members = new;
+                transitions.push(Transition::Initial {
+                    ring_id,
+                    members: members.to_vec(),
+                });
+                self.emit(&transitions);
+                return transitions;
+            }
+        };
+        if previous == ring_id {
+            // Seen this one already
+            return transitions;
+        }
+        if ring_id.seq < previous.seq {
+            transitions.push(Transition::RingIdRegression {
+                previous,
+                current: ring_id,
+            });
+        }
+
+        let lost: Vec<NodeId> = self.members.difference(&new).copied().collect();
+        let joined: Vec<NodeId> = new.difference(&self.members).copied().collect();
+
+        let (rejoined, fresh): (Vec<NodeId>, Vec<NodeId>) = joined
+            .into_iter()
+            .partition(|n| self.partitioned_from.contains(n));
+        if !rejoined.is_empty() {
+            for n in &rejoined {
+                self.partitioned_from.remove(n);
+            }
+            // partitioned_since is always set while partitioned_from is not empty
+            let since = self.partitioned_since.unwrap_or(previous);
+            if self.partitioned_from.is_empty() {
+                self.partitioned_since = None;
+            }
+            transitions.push(Transition::Merge {
+                ring_id,
+                rejoined,
+                since,
+            });
+        }
+        if !fresh.is_empty() {
+            transitions.push(Transition::NodesJoined {
+                ring_id,
+                joined: fresh,
+            });
+        }
+        if !lost.is_empty() {
+            if self.lost_majority(lost.len()) {
+                let t = self.partition(ring_id, lost.clone());
+                transitions.push(t);
+            } else {
+                transitions.push(Transition::NodeLoss {
+                    ring_id,
+                    lost: lost.clone(),
+                });
+            }
+        }
+        if transitions.is_empty() {
+            transitions.push(Transition::NoChange { ring_id });
+        }
+
+        self.last_lost = lost;
+        self.ring_id = Some(ring_id);
+        self.largest = std::cmp::max(self.largest, new.len());
+        self.members = new;
+        self.emit(&transitions);
+        transitions
+    }
+
+    /// Pass in the quorum state, from a quorum or votequorum notification callback.
+    /// If quorum is lost just after nodes left then that loss is reclassified
+    /// as a [Transition::Partition], which is returned.
+    pub fn set_quorate(&mut self, quorate: bool) -> Option<Transition> {
+        let was_quorate = self.quorate.replace(quorate);
+        if quorate || was_quorate != Some(true) {
+            return None;
+        }
+        let ring_id = self.ring_id?;
+        let lost: Vec<NodeId> = std::mem::take(&mut self.last_lost)
+            .into_iter()
+            .filter(|n| !self.partitioned_from.contains(n))
+            .collect();
+        if lost.is_empty() {
+            return None;
+        }
+        let t = self.partition(ring_id, lost);
+        self.emit(std::slice::from_ref(&t));
+        Some(t)
+    }
+}
@@ -0,0 +1,303 @@
+// Topic based publish/subscribe over a single CPG group
+// Copyright (c) 2021 Red Hat, Inc.
+//
+// All rights reserved.
+//
+// Author: Christine Caulfield (ccaulfi@redhat.com)
+//
+
+//! Many named topics share one CPG group, so an application with lots of logical
+//! streams only needs one join.
+//!
+//! Subscriptions are broadcast to the whole group, and re-broadcast by every member when
+//! someone joins, so each member knows which topics anyone is interested in.
+//! [crate::pubsub::PubSub::publish] doesn't send anything at all for a topic that has no
+//! subscribers. Bear in mind that a subscription only counts once its message has been
+//! delivered, so anything published before then by other members will not be seen.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::cpg;
+use crate::wire::{Reader, Writer};
+use crate::{CsError, DispatchFlags, NodeId, Result};
+
+/// Called for every message published on a subscribed topic.
+/// The arguments are the topic, nodeid and pid of the publisher, and the payload.
+pub type TopicHandler = fn(topic: &str, nodeid: NodeId, pid: u32, payload: &[u8]);
+
+const MSG_SUBSCRIBE: u8 = 1;
+const MSG_UNSUBSCRIBE: u8 = 2;
+const MSG_PUBLISH: u8 = 3;
+
+type Owner = (NodeId, u32);
+
+struct State {
+    handlers: HashMap<String, TopicHandler>,
+    guarantees: HashMap<String, cpg::Guarantee>,
+    // Who is subscribed to each topic, across the whole group
+    subscribers: HashMap<String, BTreeSet<Owner>>,
+}
+
+lazy_static! {
+    static ref PUBSUB_HASH: Mutex<HashMap<u64, Arc<Mutex<State>>>> = Mutex::new(HashMap::new());
+}
+
+impl State {
+    fn subscriptions(&self) -> Writer {
+        let mut w = Writer::new(MSG_SUBSCRIBE);
+        w.put_u32(self.handlers.len() as u32);
+        for topic in self.handlers.keys() {
+            w.put_str(topic);
+        }
+        w
+    }
+
+    // Returns the handler to call, if it's a publication we're interested in
+    fn apply_message(
+        &mut self,
+        from: Owner,
+        msg: &[u8],
+    ) -> Result<Option<(TopicHandler, String, Vec<u8>)>> {
+        let mut r = Reader::new(msg);
+        match r.get_u8()? {
+            MSG_SUBSCRIBE => {
+                for _ in 0..r.get_u32()? {
+                    self.subscribers
+                        .entry(r.get_str()?)
+                        .or_default()
+                        .insert(from);
+                }
+            }
+            MSG_UNSUBSCRIBE => {
+                let topic = r.get_str()?;
+                if let Some(s) = self.subscribers.get_mut(&topic) {
+                    s.remove(&from);
+                    if s.is_empty() {
+                        self.subscribers.remove(&topic);
+                    }
+                }
+            }
+            MSG_PUBLISH => {
+                let topic = r.get_str()?;
+                if let Some(handler) = self.handlers.get(&topic) {
+                    return Ok(Some((*handler, topic, r.get_bytes()?.to_vec())));
+                }
+            }
+            _ => return Err(CsError::CsErrMessageError),
+        }
+        Ok(None)
+    }
+}
+
+fn find_state(handle: &cpg::Handle) -> Option<Arc<Mutex<State>>> {
+    PUBSUB_HASH.lock().unwrap().get(&handle.cpg_handle).cloned()
+}
+
+fn pubsub_deliver_fn(
+    handle: &cpg::Handle,
+    _group_name: cpg::CpgName,
+    nodeid: NodeId,
+    pid: u32,
+    msg: &[u8],
+    _msg_len: usize,
+) {
+    if let Some(state) = find_state(handle) {
+        let res = state.lock().unwrap().apply_message((nodeid, pid), msg);
+        // Handlers are called without the lock held so they can publish
+        if let Ok(Some((handler, topic, payload))) = res {
+            (handler)(&topic, nodeid, pid, &payload);
+        }
+    }
+}
+
+fn pubsub_confchg_fn(
+    handle: &cpg::Handle,
+    _group_name: &cpg::CpgName,
+    _member_list: Vec<cpg::Address>,
+    left_list: Vec<cpg::Address>,
+    joined_list: Vec<cpg::Address>,
+) {
+    if let Some(state) = find_state(handle) {
+        let subscriptions = {
+            let mut state = state.lock().unwrap();
+            for a in &left_list {
+                let owner = (a.nodeid, a.pid);
+                for s in state.subscribers.values_mut() {
+                    s.remove(&owner);
+                }
+            }
+            state.subscribers.retain(|_, s| !s.is_empty());
+
+            // Tell new members what we are subscribed to
+            if !joined_list.is_empty() && !state.handlers.is_empty() {
+                Some(state.subscriptions())
+            } else {
+                None
+            }
+        };
+        // Sent once the state is unlocked, as this can sleep while corosync applies flow control
+        if let Some(w) = subscriptions {
+            let _ = cpg::mcast_joined_retry(*handle, cpg::Guarantee::TypeAgreed, w.as_slice());
+        }
+    }
+}
+
+/// A set of topics multiplexed over one CPG group, created with [PubSub::new].
+/// The group is left when this is dropped or explicitly with [PubSub::finalize]
+pub struct PubSub {
+    handle: cpg::Handle,
+    group: String,
+    state: Arc<Mutex<State>>,
+    closed: bool,
+}
+
+impl PubSub {
+    /// Join the CPG group `group` that carries the topics
+    pub fn new(group: &str) -> Result<PubSub> {
+        let md = cpg::ModelData::ModelV1(cpg::Model1Data {
+            flags: cpg::Model1Flags::None,
+            deliver_fn: Some(pubsub_deliver_fn),
+            confchg_fn: Some(pubsub_confchg_fn),
+            totem_confchg_fn: None,
+        });
+        let handle = cpg::initialize(&md, 0)?;
+        let state = Arc::new(Mutex::new(State {
+            handlers: HashMap::new(),
+            guarantees: HashMap::new(),
+            subscribers: HashMap::new(),
+        }));
+        PUBSUB_HASH
+            .lock()
+            .unwrap()
+            .insert(handle.cpg_handle, state.clone());
+
+        if let Err(e) = cpg::join(handle, group) {
+            PUBSUB_HASH.lock().unwrap().remove(&handle.cpg_handle);
+            let _ = cpg::finalize(handle);
+            return Err(e);
+        }
+        Ok(PubSub {
+            handle,
+            group: group.to_string(),
+            state,
+            closed: false,
+        })
+    }
+
+    /// The CPG [cpg::Handle] carrying the topics
+    pub fn handle(&self) -> cpg::Handle {
+        self.handle
+    }
+
+    /// Process incoming messages, calling topic handlers as needed. See [cpg::dispatch]
+    pub fn dispatch(&self, flags: DispatchFlags) -> Result<()> {
+        cpg::dispatch(self.handle, flags)
+    }
+
+    /// Subscribe to `topic`, calling `handler` for every message published on it.
+    /// Subscribing again to the same topic just replaces the handler.
+    pub fn subscribe(&self, topic: &str, handler: TopicHandler) -> Result<()> {
+        let old = self
+            .state
+            .lock()
+            .unwrap()
+            .handlers
+            .insert(topic.to_string(), handler);
+        if old.is_none() {
+            let mut w = Writer::new(MSG_SUBSCRIBE);
+            w.put_u32(1).put_str(topic);
+            if let Err(e) =
+                cpg::mcast_joined_retry(self.handle, cpg::Guarantee::TypeAgreed, w.as_slice())
+            {
+                self.state.lock().unwrap().handlers.remove(topic);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop receiving messages on `topic`.
+    /// Returns CsErrNotExist if we are not subscribed to it
+    pub fn unsubscribe(&self, topic: &str) -> Result<()> {
+        if self.state.lock().unwrap().handlers.remove(topic).is_none() {
+            return Err(CsError::CsErrNotExist);
+        }
+        let mut w = Writer::new(MSG_UNSUBSCRIBE);
+        w.put_str(topic);
+        cpg::mcast_joined_retry(self.handle, cpg::Guarantee::TypeAgreed, w.as_slice())
+    }
+
+    /// Set the delivery guarantee used when publishing on `topic`.
+    /// The default is [cpg::Guarantee::TypeAgreed]
+    pub fn set_guarantee(&self, topic: &str, guarantee: cpg::Guarantee) {
+        self.state
+            .lock()
+            .unwrap()
+            .guarantees
+            .insert(topic.to_string(), guarantee);
+    }
+
+    /// Publish `payload` on `topic`. Returns false, without sending anything,
+    /// if nobody in the group is subscribed to the topic
+    pub fn publish(&self, topic: &str, payload: &[u8]) -> Result<bool> {
+        let guarantee = {
+            let state = self.state.lock().unwrap();
+            if !state.subscribers.contains_key(topic) {
+                return Ok(false);
+            }
+            state
+                .guarantees
+                .get(topic)
+                .copied()
+                .unwrap_or(cpg::Guarantee::TypeAgreed)
+        };
+        let mut w = Writer::new(MSG_PUBLISH);
+        w.put_str(topic).put_bytes(payload);
+        cpg::mcast_joined_retry(self.handle, guarantee, w.as_slice())?;
+        Ok(true)
+    }
+
+    /// The processes subscribed to `topic`, as (nodeid, pid)
+    pub fn subscribers(&self, topic: &str) -> Vec<(NodeId, u32)> {
+        match self.state.lock().unwrap().subscribers.get(topic) {
+            Some(s) => s.iter().copied().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// All topics that have at least one subscriber in the group
+    pub fn topics(&self) -> Vec<String> {
+        let mut topics: Vec<String> = self
+            .state
+            .lock()
+            .unwrap()
+            .subscribers
+            .keys()
+            .cloned()
+            .collect();
+        topics.sort();
+        topics
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.closed = true;
+        let _ = cpg::leave(self.handle, &self.group);
+        PUBSUB_HASH.lock().unwrap().remove(&self.handle.cpg_handle);
+        cpg::finalize(self.handle)
+    }
+
+    /// Leave the group and close the CPG connection
+    pub fn finalize(mut self) -> Result<()> {
+        self.close()
+    }
+}
+
+impl Drop for PubSub {
+    fn drop(&mut self) {
+        if !self.closed {
+            // Nowhere to report an error
+            let _ = self.close();
+        }
+    }
+}
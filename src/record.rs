@@ -0,0 +1,340 @@
+// Record and replay of CPG traffic
+// Copyright (c) 2021 Red Hat, Inc.
+//
+// All rights reserved.
+//
+// Author: Christine Caulfield (ccaulfi@redhat.com)
+//
+
+//! A [crate::record::Recorder] attached to a [crate::cpg::Handle] writes every event delivered
+//! on that handle to a file, just before the handle's own callbacks are called.
+//! [crate::record::Replay] reads the file back, either as a sequence of
+//! [crate::record::Record]s or by calling a set of callbacks in exactly the same order, so a
+//! problem seen on a live cluster can be reproduced under a debugger.
+//!
+//! The file starts with an 8 byte header, b"CPGREC" followed by a little-endian u16 version.
+//! Each record after that is a little-endian u32 length followed by the record itself.
+//! Records are flushed as they are written so the file is usable even if the process dies.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cpg;
+use crate::wire::{Reader, Writer};
+use crate::{CsError, NodeId, Result};
+
+const MAGIC: &[u8; 6] = b"CPGREC";
+const VERSION: u16 = 1;
+
+const REC_MESSAGE: u8 = 1;
+const REC_CONFCHG: u8 = 2;
+const REC_TOTEM_CONFCHG: u8 = 3;
+
+/// One recorded event
+#[derive(Clone, Debug)]
+pub struct Record {
+    /// When the event was delivered
+    pub timestamp: SystemTime,
+    /// The totem ring that was current at the time, if one had been seen
+    pub ring_id: Option<cpg::RingId>,
+    pub event: cpg::CpgEvent,
+}
+
+fn io_error(e: io::Error) -> CsError {
+    match e.kind() {
+        io::ErrorKind::NotFound => CsError::CsErrNotExist,
+        io::ErrorKind::PermissionDenied => CsError::CsErrAccess,
+        io::ErrorKind::UnexpectedEof => CsError::CsErrMessageError,
+        _ => CsError::CsErrLibrary,
+    }
+}
+
+fn put_addresses(w: &mut Writer, list: &[cpg::Address]) {
+    w.put_u32(list.len() as u32);
+    for a in list {
+        w.put_nodeid(a.nodeid)
+            .put_u32(a.pid)
+            .put_u32(a.reason as u32);
+    }
+}
+
+fn get_addresses(r: &mut Reader) -> Result<Vec<cpg::Address>> {
+    let mut list = Vec::new();
+    for _ in 0..r.get_u32()? {
+        list.push(cpg::Address {
+            nodeid: r.get_nodeid()?,
+            pid: r.get_u32()?,
+            reason: cpg::Reason::new(r.get_u32()?),
+        });
+    }
+    Ok(list)
+}
+
+fn encode(timestamp: SystemTime, ring_id: Option<cpg::RingId>, event: &cpg::CpgEvent) -> Writer {
+    let tag = match event {
+        cpg::CpgEvent::Message { .. } => REC_MESSAGE,
+        cpg::CpgEvent::ConfChg { .. } => REC_CONFCHG,
+        cpg::CpgEvent::TotemConfChg { .. } => REC_TOTEM_CONFCHG,
+    };
+    let micros = timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let mut w = Writer::new(tag);
+    w.put_u64(micros);
+    match ring_id {
+        Some(r) => w.put_bool(true).put_nodeid(r.nodeid).put_u64(r.seq),
+        None => w.put_bool(false).put_nodeid(NodeId::from(0)).put_u64(0),
+    };
+    match event {
+        cpg::CpgEvent::Message {
+            group,
+            sender,
+            payload,
+        } => {
+            w.put_bytes(group.as_bytes())
+                .put_nodeid(sender.nodeid)
+                .put_u32(sender.pid)
+                .put_bytes(payload);
+        }
+        cpg::CpgEvent::ConfChg {
+            group,
+            members,
+            left,
+            joined,
+        } => {
+            w.put_bytes(group.as_bytes());
+            put_addresses(&mut w, members);
+            put_addresses(&mut w, left);
+            put_addresses(&mut w, joined);
+        }
+        cpg::CpgEvent::TotemConfChg { ring_id, members } => {
+            w.put_nodeid(ring_id.nodeid).put_u64(ring_id.seq);
+            w.put_u32(members.len() as u32);
+            for m in members {
+                w.put_nodeid(*m);
+            }
+        }
+    }
+    w
+}
+
+fn decode(buf: &[u8]) -> Result<Record> {
+    let mut r = Reader::new(buf);
+    let tag = r.get_u8()?;
+    let timestamp = UNIX_EPOCH + Duration::from_micros(r.get_u64()?);
+    let has_ring = r.get_bool()?;
+    let ring = cpg::RingId {
+        nodeid: r.get_nodeid()?,
+        seq: r.get_u64()?,
+    };
+    let ring_id = if has_ring { Some(ring) } else { None };
+
+    let event = match tag {
+        REC_MESSAGE => cpg::CpgEvent::Message {
+            group: cpg::CpgName::from(r.get_bytes()?),
+            sender: cpg::Address {
+                nodeid: r.get_nodeid()?,
+                pid: r.get_u32()?,
+                reason: cpg::Reason::Undefined,
+            },
+            payload: r.get_bytes()?.to_vec(),
+        },
+        REC_CONFCHG => cpg::CpgEvent::ConfChg {
+            group: cpg::CpgName::from(r.get_bytes()?),
+            members: get_addresses(&mut r)?,
+            left: get_addresses(&mut r)?,
+            joined: get_addresses(&mut r)?,
+        },
+        REC_TOTEM_CONFCHG => {
+            let ring_id = cpg::RingId {
+                nodeid: r.get_nodeid()?,
+                seq: r.get_u64()?,
+            };
+            let mut members = Vec::new();
+            for _ in 0..r.get_u32()? {
+                members.push(r.get_nodeid()?);
+            }
+            cpg::CpgEvent::TotemConfChg { ring_id, members }
+        }
+        _ => return Err(CsError::CsErrMessageError),
+    };
+    Ok(Record {
+        timestamp,
+        ring_id,
+        event,
+    })
+}
+
+struct RecorderInner {
+    file: BufWriter<File>,
+    ring_id: Option<cpg::RingId>,
+    records: u64,
+    // The first write error, recording stops after one
+    error: Option<CsError>,
+}
+
+impl RecorderInner {
+    fn record(&mut self, event: &cpg::CpgEvent) {
+        if self.error.is_some() {
+            return;
+        }
+        if let cpg::CpgEvent::TotemConfChg { ring_id, .. } = event {
+            self.ring_id = Some(*ring_id);
+        }
+        let w = encode(SystemTime::now(), self.ring_id, event);
+        let body = w.as_slice();
+        let res = self
+            .file
+            .write_all(&(body.len() as u32).to_le_bytes())
+            .and_then(|_| self.file.write_all(body))
+            .and_then(|_| self.file.flush());
+        match res {
+            Ok(()) => self.records += 1,
+            Err(e) => self.error = Some(io_error(e)),
+        }
+    }
+}
+
+/// Writes all events on a [cpg::Handle] to a file, created with [Recorder::start].
+/// Recording stops when the Recorder is stopped or dropped, or the handle is finalized.
+pub struct Recorder {
+    handle: cpg::Handle,
+    inner: Arc<Mutex<RecorderInner>>,
+    // So that we only remove our own hook, not one installed since
+    hook: cpg::EventHook,
+}
+
+impl Recorder {
+    /// Start recording events on `handle` to a new file at `path`, replacing
+    /// any file that is already there
+    pub fn start<P: AsRef<Path>>(handle: cpg::Handle, path: P) -> Result<Recorder> {
+        let mut file = BufWriter::new(File::create(path).map_err(io_error)?);
+        file.write_all(MAGIC).map_err(io_error)?;
+        file.write_all(&VERSION.to_le_bytes()).map_err(io_error)?;
+        file.flush().map_err(io_error)?;
+
+        let inner = Arc::new(Mutex::new(RecorderInner {
+            file,
+            ring_id: None,
+            records: 0,
+            error: None,
+        }));
+        let hook_inner = inner.clone();
+        let hook: cpg::EventHook =
+            Arc::new(move |ev: &cpg::CpgEvent| hook_inner.lock().unwrap().record(ev));
+        cpg::set_event_hook(handle, Some(hook.clone()));
+        Ok(Recorder {
+            handle,
+            inner,
+            hook,
+        })
+    }
+
+    /// Number of events written so far
+    pub fn records(&self) -> u64 {
+        self.inner.lock().unwrap().records
+    }
+
+    /// Stop recording. Returns the first error hit while writing the file, if any
+    pub fn stop(self) -> Result<()> {
+        cpg::clear_event_hook(self.handle, &self.hook);
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(e) = inner.error {
+            return Err(e);
+        }
+        inner.file.flush().map_err(io_error)
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        cpg::clear_event_hook(self.handle, &self.hook);
+    }
+}
+
+/// Reads a file written by a [Recorder]. Iterating over it returns each [Record] in turn,
+/// a damaged or truncated file ends with an Err.
+pub struct Replay {
+    file: BufReader<File>,
+    finished: bool,
+}
+
+impl Replay {
+    /// Open a recording
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Replay> {
+        let mut file = BufReader::new(File::open(path).map_err(io_error)?);
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header).map_err(io_error)?;
+        if &header[0..6] != MAGIC || u16::from_le_bytes([header[6], header[7]]) != VERSION {
+            return Err(CsError::CsErrMessageError);
+        }
+        Ok(Replay {
+            file,
+            finished: false,
+        })
+    }
+
+    /// Call the callbacks in `model_data` for every event in the recording, in order.
+    /// The [cpg::Handle] passed to the callbacks is not connected to corosync.
+    /// Returns the number of events replayed
+    pub fn run(self, model_data: &cpg::ModelData) -> Result<usize> {
+        self.run_on(&cpg::Handle::detached(*model_data))
+    }
+
+    /// Call the callbacks of an existing `handle` for every event in the recording, in order.
+    /// Returns the number of events replayed
+    pub fn run_on(self, handle: &cpg::Handle) -> Result<usize> {
+        let mut count = 0;
+        for rec in self {
+            handle.call_callbacks(rec?.event);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn read_record(&mut self) -> Result<Option<Record>> {
+        let mut len = [0u8; 4];
+        // A clean end of file can only come between records
+        match self.file.read(&mut len[..1]) {
+            Ok(0) => return Ok(None),
+            Ok(_) => {}
+            Err(e) => return Err(io_error(e)),
+        }
+        self.file.read_exact(&mut len[1..]).map_err(io_error)?;
+        let len = u32::from_le_bytes(len) as u64;
+        // Don't trust the length from a damaged file enough to allocate it
+        let pos = self.file.stream_position().map_err(io_error)?;
+        let size = self.file.get_ref().metadata().map_err(io_error)?.len();
+        if len > size.saturating_sub(pos) {
+            return Err(CsError::CsErrMessageError);
+        }
+        let mut buf = vec![0u8; len as usize];
+        self.file.read_exact(&mut buf).map_err(io_error)?;
+        decode(&buf).map(Some)
+    }
+}
+
+impl Iterator for Replay {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Result<Record>> {
+        if self.finished {
+            return None;
+        }
+        match self.read_record() {
+            Ok(Some(r)) => Some(Ok(r)),
+            Ok(None) => {
+                self.finished = true;
+                None
+            }
+            Err(e) => {
+                self.finished = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
@@ -0,0 +1,369 @@
+// Replicated state machine over CPG
+// Copyright (c) 2021 Red Hat, Inc.
+//
+// All rights reserved.
+//
+// Author: Christine Caulfield (ccaulfi@redhat.com)
+//
+
+//! Operations are multicast with [crate::cpg::Guarantee::TypeAgreed] and applied by every
+//! member in the same order, so all copies of the state stay identical.
+//!
+//! When processes join a group that already has members, the existing member with the lowest
+//! nodeid (then pid) is the "donor", and multicasts a snapshot of the state as it was at the
+//! moment of the join. The joiners queue any operations that arrive before the snapshot does
+//! and replay them on top of it, so they end up with exactly the same state as everyone else.
+//! If the donor leaves before its snapshot arrives, the next member in line sends one instead.
+//!
+//! Snapshots are sent as a single CPG message, so they are limited in size to whatever
+//! corosync will accept.
+//!
+//! If a joiner can't restore the snapshot it is sent, or queues too many operations while
+//! it waits for one, it gives up: it leaves the group so that it can't hold up anyone else,
+//! and [crate::replicated::ReplicatedState::error] returns the reason. Create a new
+//! [crate::replicated::ReplicatedState] to try again.
+//!
+//! A network partition is not detected. Each side carries on applying its own operations,
+//! and when the partition heals the members on both sides are already synced, so nobody
+//! sends or accepts a snapshot and the copies stay different. Use quorum to stop the
+//! minority side submitting operations while it is cut off.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::cpg;
+use crate::transfer::{Action, Owner, Transfer};
+use crate::wire::{Reader, Writer};
+use crate::{CsError, DispatchFlags, NodeId, Result};
+
+/// The state that is being replicated. Operations and snapshots are opaque byte
+/// strings, encode them however suits the application.
+pub trait StateMachine: Send + 'static {
+    /// Apply an operation. This is called in the same order on every member so
+    /// it must be deterministic.
+    fn apply(&mut self, op: &[u8]);
+    /// Return a copy of the whole state, to be sent to joining members
+    fn snapshot(&self) -> Vec<u8>;
+    /// Replace the whole state with a snapshot taken by [StateMachine::snapshot]
+    fn restore(&mut self, snapshot: &[u8]) -> Result<()>;
+}
+
+const MSG_OP: u8 = 1;
+const MSG_STATE: u8 = 2;
+
+// Operations a joiner will queue while it waits for a snapshot
+const PENDING_MAX: usize = 10000;
+
+struct Inner<S: StateMachine> {
+    machine: S,
+    xfer: Transfer,
+    // Operations received before our snapshot arrived
+    pending: Vec<(Owner, Vec<u8>)>,
+    next_seq: u64,
+    // The last of our own operations to be applied
+    applied_seq: u64,
+    // Why we gave up waiting for a snapshot
+    failed: Option<CsError>,
+}
+
+// The CPG callbacks don't know about S, so they go through this
+trait Replica: Send + Sync {
+    fn deliver(&self, handle: &cpg::Handle, group: &cpg::CpgName, from: Owner, msg: &[u8]);
+    fn confchg(&self, handle: &cpg::Handle, members: &[Owner], left: &[Owner], joined: &[Owner]);
+}
+
+lazy_static! {
+    static ref REPLICA_HASH: Mutex<HashMap<u64, Arc<dyn Replica>>> = Mutex::new(HashMap::new());
+}
+
+impl<S: StateMachine> Inner<S> {
+    fn apply_op(&mut self, from: Owner, msg: &[u8]) -> Result<()> {
+        let mut r = Reader::new(msg);
+        r.get_u8()?;
+        let seq = r.get_u64()?;
+        self.machine.apply(r.get_bytes()?);
+        if from == self.xfer.me {
+            self.applied_seq = seq;
+        }
+        Ok(())
+    }
+
+    fn apply_state(&mut self, msg: &[u8]) -> Result<()> {
+        let mut r = Reader::new(msg);
+        if self.xfer.state_received(&mut r)? {
+            self.machine.restore(r.get_bytes()?)?;
+            self.set_synced();
+        }
+        Ok(())
+    }
+
+    fn set_synced(&mut self) {
+        self.xfer.synced = true;
+        for (from, msg) in std::mem::take(&mut self.pending) {
+            let _ = self.apply_op(from, &msg);
+        }
+    }
+}
+
+impl<S: StateMachine> Replica for Mutex<Inner<S>> {
+    fn deliver(&self, handle: &cpg::Handle, group: &cpg::CpgName, from: Owner, msg: &[u8]) {
+        let failed = {
+            let mut inner = self.lock().unwrap();
+            if inner.failed.is_some() {
+                return;
+            }
+            let res = match msg.first() {
+                Some(&MSG_STATE) => inner.apply_state(msg),
+                Some(&MSG_OP) => {
+                    if inner.xfer.synced {
+                        // Nowhere to report a garbled message, and it will be garbled for everyone
+                        let _ = inner.apply_op(from, msg);
+                        Ok(())
+                    } else if inner.pending.len() >= PENDING_MAX {
+                        Err(CsError::CsErrNoSpace)
+                    } else {
+                        inner.pending.push((from, msg.to_vec()));
+                        Ok(())
+                    }
+                }
+                _ => Ok(()),
+            };
+            match res {
+                Err(e) if !inner.xfer.synced => {
+                    inner.failed = Some(e);
+                    inner.pending.clear();
+                    true
+                }
+                _ => false,
+            }
+        };
+        // Otherwise the others could pick us to send the state to later joiners
+        if failed {
+            let _ = cpg::leave(*handle, group);
+        }
+    }
+
+    fn confchg(&self, handle: &cpg::Handle, members: &[Owner], left: &[Owner], joined: &[Owner]) {
+        let snapshot = {
+            let mut inner = self.lock().unwrap();
+            if inner.failed.is_some() {
+                return;
+            }
+            match inner.xfer.confchg(members, left, joined) {
+                Action::SendState(recipients) => {
+                    let mut w = Transfer::state_message(MSG_STATE, &recipients);
+                    w.put_bytes(&inner.machine.snapshot());
+                    Some(w)
+                }
+                Action::Initial(recipients) => {
+                    if !inner.xfer.synced {
+                        inner.set_synced();
+                    }
+                    if recipients.is_empty() {
+                        None
+                    } else {
+                        let mut w = Transfer::state_message(MSG_STATE, &recipients);
+                        w.put_bytes(&inner.machine.snapshot());
+                        Some(w)
+                    }
+                }
+                Action::Nothing => None,
+            }
+        };
+        // Sent once the state is unlocked, as this can sleep while corosync applies flow control.
+        // Nothing else is delivered until we return, so the snapshot is still current.
+        if let Some(w) = snapshot {
+            let _ = cpg::mcast_joined_retry(*handle, cpg::Guarantee::TypeAgreed, w.as_slice());
+        }
+    }
+}
+
+fn find_replica(handle: &cpg::Handle) -> Option<Arc<dyn Replica>> {
+    REPLICA_HASH
+        .lock()
+        .unwrap()
+        .get(&handle.cpg_handle)
+        .cloned()
+}
+
+fn replica_deliver_fn(
+    handle: &cpg::Handle,
+    group_name: cpg::CpgName,
+    nodeid: NodeId,
+    pid: u32,
+    msg: &[u8],
+    _msg_len: usize,
+) {
+    if let Some(r) = find_replica(handle) {
+        r.deliver(handle, &group_name, (nodeid, pid), msg);
+    }
+}
+
+fn replica_confchg_fn(
+    handle: &cpg::Handle,
+    _group_name: &cpg::CpgName,
+    member_list: Vec<cpg::Address>,
+    left_list: Vec<cpg::Address>,
+    joined_list: Vec<cpg::Address>,
+) {
+    let owners =
+        |l: &[cpg::Address]| -> Vec<Owner> { l.iter().map(|a| (a.nodeid, a.pid)).collect() };
+    if let Some(r) = find_replica(handle) {
+        r.confchg(
+            handle,
+            &owners(&member_list),
+            &owners(&left_list),
+            &owners(&joined_list),
+        );
+    }
+}
+
+/// A [StateMachine] replicated across all members of a CPG group. The group is left
+/// when this is dropped or explicitly with [ReplicatedState::finalize]
+pub struct ReplicatedState<S: StateMachine> {
+    handle: cpg::Handle,
+    group: String,
+    inner: Arc<Mutex<Inner<S>>>,
+    closed: bool,
+}
+
+impl<S: StateMachine> ReplicatedState<S> {
+    /// Join the CPG group `group`. `initial` is only used if there are no other
+    /// members, otherwise it is replaced by a snapshot from an existing member.
+    pub fn new(group: &str, initial: S) -> Result<ReplicatedState<S>> {
+        let md = cpg::ModelData::ModelV1(cpg::Model1Data {
+            flags: cpg::Model1Flags::None,
+            deliver_fn: Some(replica_deliver_fn),
+            confchg_fn: Some(replica_confchg_fn),
+            totem_confchg_fn: None,
+        });
+        let handle = cpg::initialize(&md, 0)?;
+        let nodeid = match cpg::local_get(handle) {
+            Ok(n) => n,
+            Err(e) => {
+                let _ = cpg::finalize(handle);
+                return Err(e);
+            }
+        };
+        let inner = Arc::new(Mutex::new(Inner {
+            machine: initial,
+            xfer: Transfer::new((nodeid, std::process::id())),
+            pending: Vec::new(),
+            next_seq: 0,
+            applied_seq: 0,
+            failed: None,
+        }));
+        REPLICA_HASH
+            .lock()
+            .unwrap()
+            .insert(handle.cpg_handle, inner.clone());
+
+        if let Err(e) = cpg::join(handle, group) {
+            REPLICA_HASH.lock().unwrap().remove(&handle.cpg_handle);
+            let _ = cpg::finalize(handle);
+            return Err(e);
+        }
+        Ok(ReplicatedState {
+            handle,
+            group: group.to_string(),
+            inner,
+            closed: false,
+        })
+    }
+
+    /// The CPG [cpg::Handle] used for replication
+    pub fn handle(&self) -> cpg::Handle {
+        self.handle
+    }
+
+    /// Process incoming operations and membership changes, see [cpg::dispatch]
+    pub fn dispatch(&self, flags: DispatchFlags) -> Result<()> {
+        cpg::dispatch(self.handle, flags)
+    }
+
+    /// Returns true once we have a complete copy of the state
+    pub fn is_synced(&self) -> bool {
+        self.inner.lock().unwrap().xfer.synced
+    }
+
+    /// Why we gave up trying to get a copy of the state, if we have. This is the error
+    /// from [StateMachine::restore], or CsErrNoSpace if too many operations arrived while
+    /// waiting for a snapshot.
+    pub fn error(&self) -> Option<CsError> {
+        self.inner.lock().unwrap().failed
+    }
+
+    /// Dispatch callbacks until we have a complete copy of the state. Returns the error
+    /// from [ReplicatedState::error] if we give up on getting one.
+    pub fn wait_synced(&self, timeout: Duration) -> Result<()> {
+        if cpg::dispatch_until(self.handle, Some(Instant::now() + timeout), || {
+            self.is_synced() || self.error().is_some()
+        })? {
+            match self.error() {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        } else {
+            Err(CsError::CsErrTimeout)
+        }
+    }
+
+    /// Send an operation to all members, including ourself. Returns the sequence
+    /// number of the operation, which can be passed to [ReplicatedState::wait_applied]
+    pub fn submit(&self, op: &[u8]) -> Result<u64> {
+        let seq = {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(e) = inner.failed {
+                return Err(e);
+            }
+            inner.next_seq += 1;
+            inner.next_seq
+        };
+        let mut w = Writer::new(MSG_OP);
+        w.put_u64(seq).put_bytes(op);
+        cpg::mcast_joined_retry(self.handle, cpg::Guarantee::TypeAgreed, w.as_slice())?;
+        Ok(seq)
+    }
+
+    /// Dispatch callbacks until our operation `seq` has been applied locally
+    pub fn wait_applied(&self, seq: u64, timeout: Duration) -> Result<()> {
+        if cpg::dispatch_until(self.handle, Some(Instant::now() + timeout), || {
+            let inner = self.inner.lock().unwrap();
+            inner.applied_seq >= seq || inner.failed.is_some()
+        })? {
+            match self.error() {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        } else {
+            Err(CsError::CsErrTimeout)
+        }
+    }
+
+    /// Look at the local copy of the state
+    pub fn read<R, F: FnOnce(&S) -> R>(&self, f: F) -> R {
+        f(&self.inner.lock().unwrap().machine)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.closed = true;
+        let _ = cpg::leave(self.handle, &self.group);
+        REPLICA_HASH.lock().unwrap().remove(&self.handle.cpg_handle);
+        cpg::finalize(self.handle)
+    }
+
+    /// Leave the group and close the CPG connection
+    pub fn finalize(mut self) -> Result<()> {
+        self.close()
+    }
+}
+
+impl<S: StateMachine> Drop for ReplicatedState<S> {
+    fn drop(&mut self) {
+        if !self.closed {
+            // Nowhere to report an error
+            let _ = self.close();
+        }
+    }
+}
@@ -0,0 +1,182 @@
+// Flow-control-aware buffered sender for CPG
+// Copyright (c) 2021 Red Hat, Inc.
+//
+// All rights reserved.
+//
+// Author: Christine Caulfield (ccaulfi@redhat.com)
+//
+
+//! When corosync applies flow control [crate::cpg::mcast_joined] fails with CsErrTryAgain.
+//! A [crate::sender::BufferedSender] queues those messages instead and sends them, in order,
+//! once flow control is released. Call [crate::sender::BufferedSender::dispatch] in place of
+//! [crate::cpg::dispatch] (or call [crate::sender::BufferedSender::flush] after dispatching)
+//! to keep the queue moving.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::cpg;
+use crate::{CsError, DispatchFlags, Result};
+
+/// What [BufferedSender::send] does when the queue is full
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Wait until there is room in the queue, dispatching the handle while waiting
+    /// so that flow control can be released
+    Block,
+    /// Return CsErrQueueFull immediately
+    WouldBlock,
+}
+
+/// Queue statistics returned from [BufferedSender::stats]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SenderStats {
+    /// Messages currently waiting to be sent
+    pub queued_msgs: usize,
+    /// Bytes currently waiting to be sent
+    pub queued_bytes: usize,
+    /// The largest number of bytes that have been queued at one time
+    pub high_water_bytes: usize,
+    /// Messages handed to corosync
+    pub sent_msgs: u64,
+    /// Number of times a message had to be queued because of flow control
+    pub deferred_msgs: u64,
+    /// Number of times send() found the queue full
+    pub full_events: u64,
+}
+
+struct Queue {
+    msgs: VecDeque<(cpg::Guarantee, Vec<u8>)>,
+    stats: SenderStats,
+}
+
+/// Buffers outgoing CPG messages while corosync is applying flow control.
+/// Created with [BufferedSender::new] on an already joined [cpg::Handle]
+pub struct BufferedSender {
+    handle: cpg::Handle,
+    max_bytes: usize,
+    backpressure: Backpressure,
+    queue: Mutex<Queue>,
+}
+
+impl Queue {
+    // Send as much as we can, returns the number of messages sent
+    fn drain(&mut self, handle: cpg::Handle) -> Result<usize> {
+        // The flow control state is only updated when the handle is dispatched,
+        // so just try and let corosync say if it's still on
+        let mut sent = 0;
+        while let Some((guarantee, msg)) = self.msgs.front() {
+            match cpg::mcast_joined(handle, *guarantee, msg) {
+                Ok(()) => {
+                    self.stats.queued_bytes -= msg.len();
+                    self.stats.queued_msgs -= 1;
+                    self.stats.sent_msgs += 1;
+                    self.msgs.pop_front();
+                    sent += 1;
+                }
+                Err(CsError::CsErrTryAgain) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(sent)
+    }
+
+    fn push(&mut self, guarantee: cpg::Guarantee, msg: &[u8]) {
+        self.msgs.push_back((guarantee, msg.to_vec()));
+        self.stats.queued_msgs += 1;
+        self.stats.queued_bytes += msg.len();
+        self.stats.deferred_msgs += 1;
+        if self.stats.queued_bytes > self.stats.high_water_bytes {
+            self.stats.high_water_bytes = self.stats.queued_bytes;
+        }
+    }
+}
+
+impl BufferedSender {
+    /// Create a sender for `handle` that will hold up to `max_bytes` of
+    /// queued messages
+    pub fn new(
+        handle: cpg::Handle,
+        max_bytes: usize,
+        backpressure: Backpressure,
+    ) -> BufferedSender {
+        BufferedSender {
+            handle,
+            max_bytes,
+            backpressure,
+            queue: Mutex::new(Queue {
+                msgs: VecDeque::new(),
+                stats: SenderStats::default(),
+            }),
+        }
+    }
+
+    /// Send a message to the joined group, queueing it if corosync is applying flow control.
+    /// Messages are always sent in the order they were passed in.
+    /// With [Backpressure::Block] a full queue makes this call [cpg::dispatch] until there is
+    /// room, so callbacks can be run from inside send().
+    /// Returns CsErrTooBig if the message could never fit in the queue.
+    pub fn send(&self, guarantee: cpg::Guarantee, msg: &[u8]) -> Result<()> {
+        if msg.len() > self.max_bytes {
+            return Err(CsError::CsErrTooBig);
+        }
+        let mut was_full = false;
+        loop {
+            {
+                let mut queue = self.queue.lock().unwrap();
+                queue.drain(self.handle)?;
+
+                // Don't jump the queue
+                if queue.msgs.is_empty() {
+                    match cpg::mcast_joined(self.handle, guarantee, msg) {
+                        Ok(()) => {
+                            queue.stats.sent_msgs += 1;
+                            return Ok(());
+                        }
+                        Err(CsError::CsErrTryAgain) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                if queue.stats.queued_bytes + msg.len() <= self.max_bytes {
+                    queue.push(guarantee, msg);
+                    return Ok(());
+                }
+                if !was_full {
+                    queue.stats.full_events += 1;
+                    was_full = true;
+                }
+                if self.backpressure == Backpressure::WouldBlock {
+                    return Err(CsError::CsErrQueueFull);
+                }
+            }
+            // Flow control is only released when corosync tells us so
+            cpg::dispatch(self.handle, DispatchFlags::All)?;
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Send as many queued messages as flow control allows.
+    /// Returns the number of messages sent
+    pub fn flush(&self) -> Result<usize> {
+        self.queue.lock().unwrap().drain(self.handle)
+    }
+
+    /// Dispatch CPG callbacks (see [cpg::dispatch]) then send any queued messages
+    pub fn dispatch(&self, flags: DispatchFlags) -> Result<()> {
+        let res = cpg::dispatch(self.handle, flags);
+        self.flush()?;
+        res
+    }
+
+    /// Number of messages waiting to be sent
+    pub fn queue_depth(&self) -> usize {
+        self.queue.lock().unwrap().msgs.len()
+    }
+
+    /// Current queue statistics
+    pub fn stats(&self) -> SenderStats {
+        self.queue.lock().unwrap().stats
+    }
+}
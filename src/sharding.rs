@@ -0,0 +1,211 @@
+// Consistent hashing over the members of a CPG group
+// Copyright (c) 2021 Red Hat, Inc.
+//
+// All rights reserved.
+//
+// Author: Christine Caulfield (ccaulfi@redhat.com)
+//
+
+//! Each member of the group is placed at a number of points ("virtual nodes") on a hash
+//! ring, in proportion to its weight, and a key belongs to the member at the first point
+//! after the key's hash. When a member joins or leaves only the keys next to its points
+//! change owner, everything else stays where it was.
+//!
+//! Hashing uses 64 bit FNV-1a, which doesn't change between Rust versions or platforms, so
+//! every node that has seen the same confchg (and uses the same weights and number of virtual
+//! nodes) works out the same owner for every key without needing to send any messages.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::cpg;
+use crate::NodeId;
+
+/// A process in the group that can own keys
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Member {
+    pub nodeid: NodeId,
+    pub pid: u32,
+}
+
+impl fmt::Display for Member {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.nodeid, self.pid)
+    }
+}
+
+impl From<&cpg::Address> for Member {
+    fn from(a: &cpg::Address) -> Member {
+        Member {
+            nodeid: a.nodeid,
+            pid: a.pid,
+        }
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h = FNV_OFFSET;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(FNV_PRIME);
+    }
+    // FNV on its own leaves similar inputs (like our virtual node names) close
+    // together, so mix the bits up a bit more (the splitmix64 finaliser)
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+fn point_hash(member: &Member, vnode: u32) -> u64 {
+    let mut buf = [0u8; 12];
+    buf[0..4].copy_from_slice(&u32::from(member.nodeid).to_le_bytes());
+    buf[4..8].copy_from_slice(&member.pid.to_le_bytes());
+    buf[8..12].copy_from_slice(&vnode.to_le_bytes());
+    hash(&buf)
+}
+
+/// A consistent hash ring
+#[derive(Clone, Debug)]
+pub struct HashRing {
+    vnodes: u32,
+    points: BTreeMap<u64, Member>,
+    weights: BTreeMap<Member, u32>,
+}
+
+impl HashRing {
+    /// Create an empty ring, each member will get `vnodes` points per unit of weight
+    pub fn new(vnodes: u32) -> HashRing {
+        HashRing {
+            vnodes,
+            points: BTreeMap::new(),
+            weights: BTreeMap::new(),
+        }
+    }
+
+    /// Add a member to the ring, or change its weight. A weight of 0 means
+    /// the member never owns anything.
+    pub fn add(&mut self, member: Member, weight: u32) {
+        self.remove(&member);
+        for v in 0..weight.saturating_mul(self.vnodes) {
+            // On the (very) unlikely chance of a collision the lowest member wins,
+            // so the result doesn't depend on the order members were added
+            let p = point_hash(&member, v);
+            let owner = match self.points.get(&p) {
+                Some(o) if *o < member => *o,
+                _ => member,
+            };
+            self.points.insert(p, owner);
+        }
+        self.weights.insert(member, weight);
+    }
+
+    /// Take a member out of the ring
+    pub fn remove(&mut self, member: &Member) {
+        if self.weights.remove(member).is_some() {
+            self.points.retain(|_, m| m != member);
+        }
+    }
+
+    /// The member that owns `key`, or None if the ring is empty
+    pub fn owner<K: AsRef<[u8]> + ?Sized>(&self, key: &K) -> Option<Member> {
+        let h = hash(key.as_ref());
+        self.points
+            .range(h..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, m)| *m)
+    }
+
+    /// The members in the ring, with their weights
+    pub fn members(&self) -> Vec<(Member, u32)> {
+        self.weights.iter().map(|(m, w)| (*m, *w)).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+}
+
+/// Keys that changed owner after a membership change, as returned by [Sharding::confchg]
+#[derive(Clone, Debug)]
+pub struct Moves<K> {
+    /// Keys that the local member now owns but didn't before
+    pub gained: Vec<K>,
+    /// Keys that the local member used to own but now belong to someone else
+    pub lost: Vec<K>,
+}
+
+/// Shares keys out among the members of a CPG group, seen from the point of view
+/// of one member. Create with [Sharding::new] and feed it the member list from each confchg.
+pub struct Sharding {
+    me: Member,
+    ring: HashRing,
+    weights: HashMap<Member, u32>,
+}
+
+impl Sharding {
+    /// `me` is the local process, normally the local nodeid and std::process::id().
+    /// All members must use the same value of `vnodes`, 100 or so gives a fairly even spread.
+    pub fn new(me: Member, vnodes: u32) -> Sharding {
+        Sharding {
+            me,
+            ring: HashRing::new(vnodes),
+            weights: HashMap::new(),
+        }
+    }
+
+    /// Set the weight of a member, the default is 1. Every member must set the same
+    /// weights or they will disagree about who owns what. Takes effect at the next confchg.
+    pub fn set_weight(&mut self, member: Member, weight: u32) {
+        self.weights.insert(member, weight);
+    }
+
+    /// Rebuild the ring from the member list of a confchg callback and report which of
+    /// `keys` have moved to or from the local member
+    pub fn confchg<K, I>(&mut self, members: &[cpg::Address], keys: I) -> Moves<K>
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = K>,
+    {
+        let mut ring = HashRing::new(self.ring.vnodes);
+        for m in members.iter().map(Member::from) {
+            ring.add(m, self.weights.get(&m).copied().unwrap_or(1));
+        }
+        let old = std::mem::replace(&mut self.ring, ring);
+
+        let mut moves = Moves {
+            gained: Vec::new(),
+            lost: Vec::new(),
+        };
+        for k in keys {
+            let was_mine = old.owner(&k) == Some(self.me);
+            let is_mine = self.ring.owner(&k) == Some(self.me);
+            if is_mine && !was_mine {
+                moves.gained.push(k);
+            } else if was_mine && !is_mine {
+                moves.lost.push(k);
+            }
+        }
+        moves
+    }
+
+    /// The member that owns `key`, or None if there are no members yet
+    pub fn owner<K: AsRef<[u8]> + ?Sized>(&self, key: &K) -> Option<Member> {
+        self.ring.owner(key)
+    }
+
+    /// Returns true if the local member owns `key`
+    pub fn is_mine<K: AsRef<[u8]> + ?Sized>(&self, key: &K) -> bool {
+        self.owner(key) == Some(self.me)
+    }
+
+    /// The current ring
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+}
@@ -0,0 +1,113 @@
+// State transfer to joining CPG members
+// Copyright (c) 2021 Red Hat, Inc.
+//
+// All rights reserved.
+//
+// Author: Christine Caulfield (ccaulfi@redhat.com)
+//
+
+// The helper modules that keep a copy of some state on every member of a group
+// (dlm, replicated) bring new members up to date the same way. When processes join,
+// the member with the lowest (nodeid, pid) that is not itself waiting for the state
+// (the "donor") multicasts a copy of it along with the list of members it is for.
+// Joiners queue anything that arrives before then and replay it on top. If the donor
+// leaves before its copy arrives, the next member in line sends one instead.
+//
+// A joiner can't tell which of the members that were already there are synced, so if
+// the last synced member leaves, the joiners that saw everyone else join start from the
+// initial state and send it to the others. Until that arrives they are still counted as
+// joiners, so they are never picked as the donor for anyone who joins in the meantime.
+//
+// Members that are already synced ignore state messages, so when a partition heals
+// the two sides keep whatever state they had.
+
+use std::collections::BTreeSet;
+
+use crate::wire::{Reader, Writer};
+use crate::{NodeId, Result};
+
+pub(crate) type Owner = (NodeId, u32);
+
+/// What the caller needs to do after a configuration change
+pub(crate) enum Action {
+    Nothing,
+    /// We are the donor, send the state to these members
+    SendState(BTreeSet<Owner>),
+    /// Nobody has a copy of the state, so start from the initial one and send it to
+    /// these members (if any), who may be waiting for one of us to do so
+    Initial(BTreeSet<Owner>),
+}
+
+pub(crate) struct Transfer {
+    pub(crate) me: Owner,
+    // Members that have not yet received a copy of the state
+    joiners: BTreeSet<Owner>,
+    pub(crate) synced: bool,
+}
+
+impl Transfer {
+    pub(crate) fn new(me: Owner) -> Transfer {
+        Transfer {
+            me,
+            joiners: BTreeSet::new(),
+            synced: false,
+        }
+    }
+
+    pub(crate) fn confchg(
+        &mut self,
+        members: &[Owner],
+        left: &[Owner],
+        joined: &[Owner],
+    ) -> Action {
+        for j in joined {
+            self.joiners.insert(*j);
+        }
+        for l in left {
+            self.joiners.remove(l);
+        }
+        if self.joiners.is_empty() {
+            return Action::Nothing;
+        }
+
+        let donor = members
+            .iter()
+            .copied()
+            .filter(|m| !self.joiners.contains(m))
+            .min();
+        match donor {
+            Some(donor) if donor == self.me && self.synced => {
+                Action::SendState(self.joiners.clone())
+            }
+            Some(_) => Action::Nothing,
+            None => {
+                self.joiners.remove(&self.me);
+                Action::Initial(self.joiners.clone())
+            }
+        }
+    }
+
+    /// Start a state message for `recipients`, the caller appends the state itself
+    pub(crate) fn state_message(tag: u8, recipients: &BTreeSet<Owner>) -> Writer {
+        let mut w = Writer::new(tag);
+        w.put_u32(recipients.len() as u32);
+        for (nodeid, pid) in recipients {
+            w.put_nodeid(*nodeid).put_u32(*pid);
+        }
+        w
+    }
+
+    /// Read the header of a state message (including the tag). Returns true if the
+    /// state is for us, in which case the caller reads and installs the rest of it.
+    pub(crate) fn state_received(&mut self, r: &mut Reader) -> Result<bool> {
+        r.get_u8()?;
+        let mut recipients = BTreeSet::new();
+        for _ in 0..r.get_u32()? {
+            recipients.insert((r.get_nodeid()?, r.get_u32()?));
+        }
+        for j in &recipients {
+            self.joiners.remove(j);
+        }
+        Ok(!self.synced && recipients.contains(&self.me))
+    }
+}
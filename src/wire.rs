@@ -0,0 +1,118 @@
+// Message encoding for the protocols built on top of CPG
+// Copyright (c) 2021 Red Hat, Inc.
+//
+// All rights reserved.
+//
+// Author: Christine Caulfield (ccaulfi@redhat.com)
+//
+
+// All of the helper modules (dlm etc) send small structured messages around the
+// group. There is no need for anything clever here, every member is running this
+// same code. Integers are little-endian, byte strings & strings are prefixed
+// with a u32 length.
+
+use crate::{CsError, NodeId, Result};
+
+pub(crate) struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    /// Start a new message, all messages start with a one byte tag
+    pub(crate) fn new(tag: u8) -> Writer {
+        Writer { buf: vec![tag] }
+    }
+
+    pub(crate) fn put_u8(&mut self, v: u8) -> &mut Writer {
+        self.buf.push(v);
+        self
+    }
+
+    pub(crate) fn put_u32(&mut self, v: u32) -> &mut Writer {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    pub(crate) fn put_u64(&mut self, v: u64) -> &mut Writer {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    pub(crate) fn put_bool(&mut self, v: bool) -> &mut Writer {
+        self.put_u8(v as u8)
+    }
+
+    pub(crate) fn put_nodeid(&mut self, v: NodeId) -> &mut Writer {
+        self.put_u32(u32::from(v))
+    }
+
+    pub(crate) fn put_bytes(&mut self, v: &[u8]) -> &mut Writer {
+        self.put_u32(v.len() as u32);
+        self.buf.extend_from_slice(v);
+        self
+    }
+
+    pub(crate) fn put_str(&mut self, v: &str) -> &mut Writer {
+        self.put_bytes(v.as_bytes())
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() - self.pos < len {
+            return Err(CsError::CsErrMessageError);
+        }
+        let r = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(r)
+    }
+
+    pub(crate) fn get_u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn get_u32(&mut self) -> Result<u32> {
+        let mut b = [0u8; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    pub(crate) fn get_u64(&mut self) -> Result<u64> {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    pub(crate) fn get_bool(&mut self) -> Result<bool> {
+        Ok(self.get_u8()? != 0)
+    }
+
+    pub(crate) fn get_nodeid(&mut self) -> Result<NodeId> {
+        Ok(NodeId::from(self.get_u32()?))
+    }
+
+    pub(crate) fn get_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.get_u32()? as usize;
+        self.take(len)
+    }
+
+    pub(crate) fn get_str(&mut self) -> Result<String> {
+        match std::str::from_utf8(self.get_bytes()?) {
+            Ok(s) => Ok(s.to_string()),
+            Err(_) => Err(CsError::CsErrRustString),
+        }
+    }
+}
@@ -0,0 +1,32 @@
+// Test the barrier module. Requires that corosync is running and that we are root.
+
+extern crate rust_corosync as corosync;
+use corosync::barrier;
+use std::time::Duration;
+
+fn main() {
+    let b = match barrier::Barrier::new("TESTBARRIER", barrier::LeavePolicy::Exclude) {
+        Ok(b) => b,
+        Err(e) => {
+            println!("Error in barrier init: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Use the same barrier twice, the generation should go up each time
+    for _ in 0..2 {
+        match b.wait("step", Duration::new(5, 0)) {
+            Ok(g) => println!("barrier 'step' complete, generation {}", g),
+            Err(e) => {
+                println!("Error in barrier wait: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    println!("barrier 'step' generation now {}", b.generation("step"));
+
+    if let Err(e) = b.finalize() {
+        println!("Error in barrier finalize: {}", e);
+        std::process::exit(1);
+    }
+}
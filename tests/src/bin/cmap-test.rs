@@ -2,11 +2,10 @@
 
 extern crate rust_corosync as corosync;
 use corosync::cmap;
+use std::convert::TryFrom;
 use std::thread::spawn;
 
 fn track_notify_fn(
-    _handle: &cmap::Handle,
-    _track_handle: &cmap::TrackHandle,
     event: cmap::TrackType,
     key_name: &str,
     old_value: &cmap::Data,
@@ -83,7 +82,7 @@ fn main() {
     };
 
     // get them back again
-    match cmap::get(handle, "test.test_uint32") {
+    match cmap::get::<u32>(handle, "test.test_uint32") {
         Ok(v) => {
             println!("GOT uint32 {}", v);
         }
@@ -93,7 +92,7 @@ fn main() {
             std::process::exit(1);
         }
     };
-    match cmap::get(handle, "test.test_int16") {
+    match cmap::get::<i16>(handle, "test.test_int16") {
         Ok(v) => {
             println!("GOT uint16 {}", v);
         }
@@ -104,7 +103,7 @@ fn main() {
         }
     };
 
-    match cmap::get(handle, "test.test_num_1") {
+    match cmap::get::<u32>(handle, "test.test_num_1") {
         Ok(v) => {
             println!("GOT num {}", v);
         }
@@ -114,7 +113,7 @@ fn main() {
             std::process::exit(1);
         }
     };
-    match cmap::get(handle, "test.test_num_2") {
+    match cmap::get::<f64>(handle, "test.test_num_2") {
         Ok(v) => {
             println!("GOT num {}", v);
         }
@@ -124,7 +123,7 @@ fn main() {
             std::process::exit(1);
         }
     };
-    match cmap::get(handle, "test.test_string") {
+    match cmap::get::<String>(handle, "test.test_string") {
         Ok(v) => {
             println!("GOT string {}", v);
         }
@@ -135,7 +134,7 @@ fn main() {
         }
     };
 
-    match cmap::get(handle, "test.test_data") {
+    match cmap::get::<cmap::Data>(handle, "test.test_data") {
         Ok(v) => match v {
             cmap::Data::UInt64(u) => println!("GOT data value {:x}", u),
             _ => println!("ERROR type was not UInt64, got {}", v),
@@ -147,6 +146,91 @@ fn main() {
         }
     };
 
+    // Typed set/get
+    if let Err(e) = cmap::set(handle, "test.test_u16", &0xbeefu16) {
+        println!("Error in CMAP set of u16: {}", e);
+        std::process::exit(1);
+    };
+    match cmap::get::<u16>(handle, "test.test_u16") {
+        Ok(0xbeef) => println!("GOT u16 0xbeef"),
+        Ok(v) => {
+            println!("ERROR u16 was {:x}, expected beef", v);
+            std::process::exit(2);
+        }
+        Err(e) => {
+            println!("Error in CMAP get of u16: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = cmap::set(handle, "test.test_i64", &-1234567890123i64) {
+        println!("Error in CMAP set of i64: {}", e);
+        std::process::exit(1);
+    };
+    if let Err(e) = cmap::set(handle, "test.test_utf8", "Grüße") {
+        println!("Error in CMAP set of UTF-8 string: {}", e);
+        std::process::exit(1);
+    };
+    match cmap::get::<String>(handle, "test.test_utf8") {
+        Ok(s) if s == "Grüße" => println!("GOT string {}", s),
+        Ok(s) => {
+            println!("ERROR UTF-8 string was {}", s);
+            std::process::exit(2);
+        }
+        Err(e) => {
+            println!("Error in CMAP get of UTF-8 string: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = cmap::set(handle, "test.test_bin", &[1u8, 2, 3][..]) {
+        println!("Error in CMAP set of binary: {}", e);
+        std::process::exit(1);
+    };
+
+    // Asking for the wrong type should fail, not return junk
+    match cmap::get::<u8>(handle, "test.test_i64") {
+        Err(corosync::CsError::CsErrInvalidParam) => println!("got expected type mismatch"),
+        Ok(v) => {
+            println!("ERROR got u8 {} from an i64 key", v);
+            std::process::exit(2);
+        }
+        Err(e) => {
+            println!("Error in CMAP get of i64 as u8: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Data conversions
+    let d = cmap::Data::from(200u8);
+    if u64::try_from(&d) != Ok(200) || i16::try_from(&d) != Ok(200) || i8::try_from(&d).is_ok() {
+        println!("ERROR widening conversions of {} are wrong", d);
+        std::process::exit(2);
+    }
+    match "u16"
+        .parse::<cmap::DataType>()
+        .and_then(|t| cmap::Data::parse(t, "0xbeef"))
+    {
+        Ok(d) if d == cmap::Data::UInt16(0xbeef) => println!("parsed {}", d),
+        r => {
+            println!("ERROR parsing u16 0xbeef gave {:?}", r);
+            std::process::exit(2);
+        }
+    }
+    if cmap::Data::parse(cmap::DataType::UInt8, "256").is_ok() {
+        println!("ERROR parsed 256 as a u8");
+        std::process::exit(2);
+    }
+    if cmap::Data::parse(cmap::DataType::Int32, "--5").is_ok() {
+        println!("ERROR parsed --5 as an i32");
+        std::process::exit(2);
+    }
+    match cmap::get::<cmap::Data>(handle, "test.test_string") {
+        Ok(d) if d.as_str() == Some("Hello from Rust") => {}
+        r => {
+            println!("ERROR test.test_string as_str() was wrong: {:?}", r);
+            std::process::exit(2);
+        }
+    }
+
     // Test an iterator
     match cmap::CmapIterStart::new(handle, "totem.") {
         Ok(cmap_iter) => {
@@ -161,6 +245,424 @@ fn main() {
         }
     }
 
+    // Reading with a reused buffer
+    let mut buf = Vec::new();
+    match cmap::get_into(handle, "test.test_string", &mut buf) {
+        Ok(t) => match cmap::Data::from_bytes(t, &buf) {
+            Ok(d) => println!("GOT into buffer {}", d),
+            Err(e) => {
+                println!("Error converting raw cmap value: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Err(e) => {
+            println!("Error in CMAP get_into: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // Keys only, fetching just the numeric values
+    match cmap::CmapIterStart::new(handle, "test.") {
+        Ok(cmap_iter) => {
+            for k in cmap_iter.keys() {
+                match k {
+                    Ok(k) if k.data_type() == cmap::DataType::UInt32 => {
+                        match k.value_into(&mut buf) {
+                            Ok(_) => println!("KEY: {:?} = {:?}", k, buf),
+                            Err(e) => println!("Error in CMAP value_into: {}", e),
+                        }
+                    }
+                    Ok(k) => println!("KEY: {:?} ({} bytes)", k, k.value_len()),
+                    Err(e) => {
+                        println!("Error in CMAP keys iterator: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            println!("Error in CMAP iter start: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // Snapshots
+    let before = match cmap::snapshot::CmapSnapshot::capture(handle, "test.") {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Error in CMAP snapshot capture: {}", e);
+            std::process::exit(1);
+        }
+    };
+    match before.to_text() {
+        Ok(t) => print!("{}", t),
+        Err(e) => {
+            println!("Error in CMAP snapshot to_text: {}", e);
+            std::process::exit(1);
+        }
+    }
+    match cmap::snapshot::CmapSnapshot::from_json(&before.to_json()) {
+        Ok(s) if s == before => {}
+        r => {
+            println!("ERROR snapshot did not survive JSON: {:?}", r);
+            std::process::exit(2);
+        }
+    }
+    match before
+        .to_text()
+        .and_then(|t| cmap::snapshot::CmapSnapshot::from_text(&t))
+    {
+        Ok(s) if s.len() == before.len() => {}
+        r => {
+            println!("ERROR snapshot did not survive text: {:?}", r);
+            std::process::exit(2);
+        }
+    }
+    let awkward: cmap::snapshot::CmapSnapshot = vec![
+        ("a.str".to_string(), cmap::Data::from(" x (str) = y ")),
+        ("a.empty".to_string(), cmap::Data::from("")),
+        ("a.bin".to_string(), cmap::Data::Binary(vec![0, 0xff])),
+        ("a.u64".to_string(), cmap::Data::UInt64(u64::MAX)),
+        ("a.dbl".to_string(), cmap::Data::Double(0.1)),
+    ]
+    .into_iter()
+    .collect();
+    match awkward
+        .to_text()
+        .and_then(|t| cmap::snapshot::CmapSnapshot::from_text(&t))
+    {
+        Ok(s) if s == awkward => {}
+        r => {
+            println!("ERROR snapshot did not survive text: {:?}", r);
+            std::process::exit(2);
+        }
+    }
+    for (k, v) in [("a.nl", "x\ny"), ("a (b", "x")] {
+        let mut bad = cmap::snapshot::CmapSnapshot::new();
+        bad.insert(k, cmap::Data::from(v));
+        if bad.to_text().is_ok() {
+            println!("ERROR snapshot to_text accepted {:?} = {:?}", k, v);
+            std::process::exit(2);
+        }
+    }
+    let json = r#"{"a": {"type": "str", "value": "\ud83d\ude00"}}"#;
+    match cmap::snapshot::CmapSnapshot::from_json(json) {
+        Ok(s) if s.get("a") == Some(&cmap::Data::from("\u{1f600}")) => {}
+        r => {
+            println!("ERROR surrogate pair from JSON: {:?}", r);
+            std::process::exit(2);
+        }
+    }
+    let json = r#"{"a": {"type": "str", "value": "\ud83d\u0041"}}"#;
+    if cmap::snapshot::CmapSnapshot::from_json(json).is_ok() {
+        println!("ERROR bad low surrogate accepted from JSON");
+        std::process::exit(2);
+    }
+    let json = r#"{"a": {"type": "str", "value": "\u+041"}}"#;
+    if cmap::snapshot::CmapSnapshot::from_json(json).is_ok() {
+        println!("ERROR signed \\u escape accepted from JSON");
+        std::process::exit(2);
+    }
+    let deep = "[".repeat(100_000);
+    if cmap::snapshot::CmapSnapshot::from_json(&deep).is_ok() {
+        println!("ERROR deeply nested JSON accepted");
+        std::process::exit(2);
+    }
+    if let Err(e) = cmap::set_u32(handle, "test.test_uint32", 457) {
+        println!("Error in CMAP set_u32: {}", e);
+        std::process::exit(1);
+    };
+    match cmap::snapshot::CmapSnapshot::capture(handle, "test.") {
+        Ok(after) => {
+            let diff = before.diff(&after);
+            print!("DIFF:\n{}", diff);
+            if !diff.changed.contains_key("test.test_uint32") {
+                println!("ERROR change to test.test_uint32 not in diff");
+                std::process::exit(2);
+            }
+        }
+        Err(e) => {
+            println!("Error in CMAP snapshot capture: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // Patches
+    let mut p = cmap::patch::Patch::new();
+    p.set("test.test_uint32", 458u32)
+        .set("test.test_patch", "patched")
+        .delete("test.test_int16");
+    let dry_run = cmap::patch::ApplyOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    match cmap::apply(handle, &p, &dry_run) {
+        Ok(r) => {
+            println!("DRY RUN: {:?}", r.changes);
+            if cmap::get::<u32>(handle, "test.test_uint32") != Ok(457) {
+                println!("ERROR dry run changed test.test_uint32");
+                std::process::exit(2);
+            }
+        }
+        Err(e) => {
+            println!("Error in CMAP apply (dry run): {}", e);
+            std::process::exit(1);
+        }
+    }
+    match cmap::apply(handle, &p, &Default::default()) {
+        Ok(r) if r.is_complete() => println!("APPLIED: {}", r.succeeded().count()),
+        r => {
+            println!("Error in CMAP apply: {:?}", r);
+            std::process::exit(1);
+        }
+    }
+    let mut bad = cmap::patch::Patch::new();
+    bad.set("test.test_uint32", "not a number").set("x", 1u8);
+    match cmap::apply(handle, &bad, &Default::default()) {
+        Ok(r) if r.invalid.len() == 2 && r.succeeded().count() == 0 => {
+            println!("invalid patch rejected: {:?}", r.invalid)
+        }
+        r => {
+            println!("ERROR invalid patch was not rejected: {:?}", r);
+            std::process::exit(2);
+        }
+    }
+
+    // Key paths
+    let path = cmap::KeyPath::new("test")
+        .and_then(|p| p.child("node"))
+        .and_then(|p| p.index(3))
+        .and_then(|p| p.child("ring0_addr"));
+    let path = match path {
+        Ok(p) if p.as_str() == "test.node.3.ring0_addr" => p,
+        r => {
+            println!("ERROR KeyPath built {:?}", r);
+            std::process::exit(2);
+        }
+    };
+    if !path.starts_with(&cmap::KeyPath::new("test").unwrap())
+        || path.starts_with(&cmap::KeyPath::new("tes").unwrap())
+        || path.indices() != vec![3]
+    {
+        println!(
+            "ERROR KeyPath prefix or index matching is wrong for {}",
+            path
+        );
+        std::process::exit(2);
+    }
+    match cmap::KeyPath::parse("stats.knet.node2.link0.latency_ave") {
+        Ok(p) if p.indices() == vec![2, 0] => {}
+        r => {
+            println!("ERROR KeyPath parse gave {:?}", r);
+            std::process::exit(2);
+        }
+    }
+    if cmap::KeyPath::parse("test..bad").is_ok() || cmap::KeyPath::new("a b").is_ok() {
+        println!("ERROR invalid KeyPath accepted");
+        std::process::exit(2);
+    }
+    if let Err(e) = cmap::set(handle, &path, "192.168.1.3") {
+        println!("Error in CMAP set with KeyPath: {}", e);
+        std::process::exit(1);
+    }
+    match cmap::get::<String>(handle, &path) {
+        Ok(s) => println!("GOT {} = {}", path, s),
+        Err(e) => {
+            println!("Error in CMAP get with KeyPath: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // Test deletion
+    if let Err(e) = cmap::delete(handle, "test.test_u16") {
+        println!("Error in CMAP delete: {}", e);
+        std::process::exit(1);
+    }
+    if cmap::get::<cmap::Data>(handle, "test.test_u16").is_ok() {
+        println!("ERROR test.test_u16 still exists after delete");
+        std::process::exit(2);
+    }
+    match cmap::delete_prefix(handle, "test.") {
+        Ok(d) => {
+            println!("Deleted: {:?}", d.deleted);
+            if !d.errors.is_empty() {
+                println!("Error in CMAP delete_prefix: {:?}", d.errors);
+                std::process::exit(1);
+            }
+            if !d.deleted.iter().any(|k| k == "test.test_uint32") {
+                println!("ERROR test.test_uint32 was not deleted");
+                std::process::exit(2);
+            }
+        }
+        Err(e) => {
+            println!("Error in CMAP delete_prefix: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Ok(mut i) = cmap::CmapIterStart::new(handle, "test.").map(|i| i.into_iter()) {
+        if let Some(k) = i.next() {
+            println!("ERROR key left after delete_prefix: {:?}", k);
+            std::process::exit(2);
+        }
+    }
+
+    // Typed configuration
+    match cmap::config::Config::read(handle) {
+        Ok(c) => {
+            println!("cluster_name: {:?}", c.totem.cluster_name);
+            println!("quorum provider: {:?}", c.quorum.provider);
+            for n in &c.nodelist.nodes {
+                println!("node {:?} {:?} {:?}", n.nodeid, n.name, n.ring_addrs);
+            }
+        }
+        Err(e) => {
+            println!("Error in CMAP config read: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let snap: cmap::snapshot::CmapSnapshot = vec![
+        ("nodelist.node.1.nodeid".to_string(), cmap::Data::from("2")),
+        (
+            "nodelist.node.1.ring1_addr".to_string(),
+            cmap::Data::from("b1"),
+        ),
+        ("nodelist.node.0.nodeid".to_string(), cmap::Data::UInt32(1)),
+        ("nodelist.node.0.bogus".to_string(), cmap::Data::UInt8(7)),
+        ("quorum.two_node".to_string(), cmap::Data::UInt8(1)),
+        ("quorum.wait_for_all".to_string(), cmap::Data::from("maybe")),
+        ("logging.to_syslog".to_string(), cmap::Data::from("yes")),
+    ]
+    .into_iter()
+    .collect();
+    let c = cmap::config::Config::from_snapshot(&snap);
+    if c.nodelist.nodes.len() != 2
+        || c.nodelist.nodes[0].nodeid != Some(1)
+        || c.nodelist.nodes[1].nodeid != Some(2)
+        || c.nodelist.nodes[1].ring_addrs.get(&1).map(|s| s.as_str()) != Some("b1")
+        || !c.nodelist.nodes[0].other.contains_key("bogus")
+        || c.quorum.two_node != Some(true)
+        || c.quorum.wait_for_all.is_some()
+        || !c.quorum.other.contains_key("wait_for_all")
+        || c.logging.to_syslog != Some(true)
+    {
+        println!("ERROR config from snapshot: {:?}", c);
+        std::process::exit(2);
+    }
+    // local_node_pos is the N in nodelist.node.N, which needn't be the position in nodes
+    let snap: cmap::snapshot::CmapSnapshot = vec![
+        ("nodelist.node.0.nodeid".to_string(), cmap::Data::UInt32(1)),
+        ("nodelist.node.2.nodeid".to_string(), cmap::Data::UInt32(3)),
+        ("nodelist.local_node_pos".to_string(), cmap::Data::UInt32(2)),
+    ]
+    .into_iter()
+    .collect();
+    let n = cmap::config::Nodelist::from_snapshot(&snap);
+    if n.local_node().and_then(|l| l.nodeid) != Some(3) {
+        println!("ERROR local node with a gap in the nodelist: {:?}", n);
+        std::process::exit(2);
+    }
+
+    // Changes must come down the channel
+    let (tracker, events) = match cmap::track_channel(
+        handle,
+        "test.channel.",
+        cmap::TrackType::ADD | cmap::TrackType::PREFIX,
+        16,
+    ) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("Error in CMAP track_channel {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = cmap::set(handle, "test.channel.a", &1u32) {
+        println!("Error in CMAP set of tracked key: {}", e);
+        std::process::exit(1);
+    }
+    let deadline = std::time::Instant::now() + std::time::Duration::new(5, 0);
+    let ev = loop {
+        if let Ok(ev) = events.try_recv() {
+            break Some(ev);
+        }
+        if std::time::Instant::now() >= deadline {
+            break None;
+        }
+        if let Err(e) = cmap::dispatch(handle, corosync::DispatchFlags::All) {
+            println!("Error in CMAP dispatch: {}", e);
+            std::process::exit(1);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    };
+    match ev {
+        Some(ev) if ev.key_name == "test.channel.a" && ev.new_value == cmap::Data::UInt32(1) => {
+            println!("EVENT: {:?}", ev)
+        }
+        ev => {
+            println!("ERROR track_channel event for test.channel.a: {:?}", ev);
+            std::process::exit(2);
+        }
+    }
+    drop(tracker);
+    if let Err(e) = cmap::delete(handle, "test.channel.a") {
+        println!("Error in CMAP delete of tracked key: {}", e);
+        std::process::exit(1);
+    }
+
+    // Changes must reach the mirror. Nothing is dispatching this handle, so do it here.
+    let mirror = match cmap::mirror::CmapMirror::new(handle, "test.mirror.") {
+        Ok(m) => m,
+        Err(e) => {
+            println!("Error in CMAP mirror: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let old_snap = mirror.snapshot();
+    if let Err(e) = cmap::set(handle, "test.mirror.a", &1u32) {
+        println!("Error in CMAP set of mirrored key: {}", e);
+        std::process::exit(1);
+    }
+    let deadline = std::time::Instant::now() + std::time::Duration::new(5, 0);
+    while mirror.get("test.mirror.a") != Some(cmap::Data::UInt32(1))
+        && std::time::Instant::now() < deadline
+    {
+        if let Err(e) = cmap::dispatch(handle, corosync::DispatchFlags::All) {
+            println!("Error in CMAP dispatch: {}", e);
+            std::process::exit(1);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    match mirror.get("test.mirror.a") {
+        Some(cmap::Data::UInt32(1)) => println!("mirror has test.mirror.a"),
+        v => {
+            println!("ERROR mirrored test.mirror.a is {:?}", v);
+            std::process::exit(2);
+        }
+    }
+    if old_snap.contains_key("test.mirror.a")
+        || mirror.snapshot().get("test.mirror.a") != Some(&cmap::Data::UInt32(1))
+    {
+        println!("ERROR mirror snapshot: {:?}", mirror.snapshot());
+        std::process::exit(2);
+    }
+    // Nothing has been missed, so a resync has nothing to report
+    let changes = mirror.subscribe();
+    if let Err(e) = mirror.resync() {
+        println!("Error in CMAP mirror resync: {}", e);
+        std::process::exit(1);
+    }
+    if let Ok(ev) = changes.try_recv() {
+        println!(
+            "ERROR resync reported a change that was already seen: {:?}",
+            ev
+        );
+        std::process::exit(2);
+    }
+    drop(mirror);
+    if let Err(e) = cmap::delete(handle, "test.mirror.a") {
+        println!("Error in CMAP delete of mirrored key: {}", e);
+        std::process::exit(1);
+    }
+
     // Close this handle
     if let Err(e) = cmap::finalize(handle) {
         println!("Error in CMAP get: {}", e);
@@ -179,15 +681,13 @@ fn main() {
     let handle_clone = handle.clone();
     let _dispatch_thread = spawn(move || dispatch_routine(handle_clone));
 
-    let cb = cmap::NotifyCallback {
-        notify_fn: Some(track_notify_fn),
-    };
-    let _track_handle = match cmap::track_add(
+    let _tracker = match cmap::track_add(
         handle,
         "stats.srp.memb_merge_detect_tx",
         cmap::TrackType::MODIFY | cmap::TrackType::ADD | cmap::TrackType::DELETE,
-        &cb,
-        997u64,
+        |event, key_name, old_value, new_value| {
+            track_notify_fn(event, key_name, old_value, new_value, 997u64)
+        },
     ) {
         Ok(th) => th,
         Err(e) => {
@@ -196,6 +696,53 @@ fn main() {
         }
     };
 
+    let (tracker, events) = match cmap::track_channel(
+        handle,
+        "stats.srp.",
+        cmap::TrackType::MODIFY | cmap::TrackType::PREFIX,
+        16,
+    ) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("Error in CMAP track_channel {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Ok(ev) = events.recv_timeout(std::time::Duration::new(5, 0)) {
+        println!("EVENT: {:?}", ev);
+    }
+    if let Err(e) = cmap::track_delete(tracker) {
+        println!("Error in CMAP track_delete {}", e);
+        std::process::exit(1);
+    }
+    // Dropping the tracker closes the channel
+    while events.recv().is_ok() {}
+
+    // Mirror a subtree
+    let mirror = match cmap::mirror::CmapMirror::new(handle, "stats.srp.") {
+        Ok(m) => m,
+        Err(e) => {
+            println!("Error in CMAP mirror: {}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("Mirrored {} keys", mirror.snapshot().len());
+    match mirror.get("stats.srp.memb_merge_detect_tx") {
+        Some(v) => println!("mirrored value {}", v),
+        None => {
+            println!("ERROR stats.srp.memb_merge_detect_tx not mirrored");
+            std::process::exit(2);
+        }
+    }
+    let changes = mirror.subscribe();
+    if let Ok(ev) = changes.recv_timeout(std::time::Duration::new(5, 0)) {
+        println!("MIRROR CHANGE: {:?}", ev);
+    }
+    if let Err(e) = mirror.resync() {
+        println!("Error in CMAP mirror resync: {}", e);
+        std::process::exit(1);
+    }
+
     // Let it all finish
     std::thread::sleep(std::time::Duration::new(10, 0));
 }
@@ -9,7 +9,7 @@ static MSGS_RECVD: std::sync::Mutex<i32> = std::sync::Mutex::new(0);
 
 fn deliver_fn(
     _handle: &cpg::Handle,
-    group_name: String,
+    group_name: cpg::CpgName,
     nodeid: NodeId,
     pid: u32,
     msg: &[u8],
@@ -36,7 +36,7 @@ fn deliver_fn(
 
 fn confchg_fn(
     _handle: &cpg::Handle,
-    group_name: &str,
+    group_name: &cpg::CpgName,
     member_list: Vec<cpg::Address>,
     left_list: Vec<cpg::Address>,
     joined_list: Vec<cpg::Address>,
@@ -71,6 +71,11 @@ fn dispatch_routine(handle: cpg::Handle) {
 }
 
 fn main() {
+    if std::env::args().nth(1).as_deref() == Some("big-group-member") {
+        big_group_member();
+        return;
+    }
+
     // Initialise the model data
     let md = cpg::ModelData::ModelV1(cpg::Model1Data {
         flags: cpg::Model1Flags::None,
@@ -87,6 +92,30 @@ fn main() {
         }
     };
 
+    // Names can be a full 128 bytes, and needn't be UTF-8
+    let long_name = [b'x'; 128];
+    if let Err(e) = cpg::CpgName::new(&long_name) {
+        println!("Error in CpgName of 128 bytes: {}", e);
+        std::process::exit(1);
+    }
+    if cpg::CpgName::new(&[b'x'; 129]).is_ok() {
+        println!("Error: CpgName of 129 bytes was accepted");
+        std::process::exit(1);
+    }
+    let binary_name = cpg::CpgName::from(&b"TEST\xff\x00BIN"[..]);
+    if let Err(e) = cpg::join(handle, &binary_name) {
+        println!("Error in CPG join of binary name {:?}: {}", binary_name, e);
+        std::process::exit(1);
+    }
+    match cpg::membership_get(handle, &binary_name) {
+        Ok(m) => println!("  members of {:?}: {:?}", binary_name, m),
+        Err(e) => println!("Error in CPG membership_get of binary name: {}", e),
+    }
+    if let Err(e) = cpg::leave(handle, &binary_name) {
+        println!("Error in CPG leave of binary name: {}", e);
+        std::process::exit(1);
+    }
+
     if let Err(e) = cpg::join(handle, "TEST") {
         println!("Error in CPG join: {}", e);
         std::process::exit(1);
@@ -168,6 +197,18 @@ fn main() {
         }
     }
 
+    match cpg::cluster_groups(handle) {
+        Ok(groups) => {
+            for (g, m) in groups {
+                println!("GROUP: {} {:?}", g, m);
+            }
+        }
+        Err(e) => {
+            println!("Error in CPG cluster_groups: {}", e);
+            std::process::exit(1);
+        }
+    }
+
     // We should receive our own message (at least) in the event loop
     if let Err(e) = cpg::mcast_joined(
         handle,
@@ -180,4 +221,180 @@ fn main() {
 
     // Let it all finish
     std::thread::sleep(std::time::Duration::new(1, 0));
+
+    // The dispatch thread should have seen our join by now
+    match cpg::group_view(handle, "TEST") {
+        Some(v) => println!("group view: {:?}", v),
+        None => {
+            println!("Error: no group view for TEST");
+            std::process::exit(2);
+        }
+    }
+
+    // Test the channel API
+    let (chandle, events) = match cpg::initialize_channel(16, 0) {
+        Ok(h) => h,
+        Err(e) => {
+            println!("Error in CPG initialize_channel: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = cpg::join(chandle, "TESTCHANNEL") {
+        println!("Error in CPG join: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = cpg::mcast_joined(chandle, cpg::Guarantee::TypeAgreed, b"channel test") {
+        println!("Error in CPG mcast_joined: {}", e);
+        std::process::exit(1);
+    }
+    // Our join and our message
+    for _ in 0..2 {
+        if let Err(e) = cpg::dispatch(chandle, corosync::DispatchFlags::One) {
+            println!("Error in CPG dispatch: {}", e);
+            std::process::exit(1);
+        }
+    }
+    for ev in events.try_iter() {
+        println!("EVENT: {:?}", ev);
+    }
+
+    // The confchg for our own leave must not bring the view back
+    if let Err(e) = cpg::leave(chandle, "TESTCHANNEL") {
+        println!("Error in CPG leave: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = cpg::dispatch(chandle, corosync::DispatchFlags::One) {
+        println!("Error in CPG dispatch: {}", e);
+        std::process::exit(1);
+    }
+    if let Some(v) = cpg::group_view(chandle, "TESTCHANNEL") {
+        println!(
+            "Error: group view for TESTCHANNEL still there after leave: {:?}",
+            v
+        );
+        std::process::exit(2);
+    }
+    if let Err(e) = cpg::finalize(chandle) {
+        println!("Error in CPG finalize: {}", e);
+        std::process::exit(1);
+    }
+
+    // Errors are passed back as they are, not turned into CsErrTooBig
+    match cpg::membership_get(chandle, "TEST") {
+        Err(corosync::CsError::CsErrBadHandle) => {
+            println!("membership_get on a closed handle failed")
+        }
+        r => {
+            println!("Error: membership_get on a closed handle returned {:?}", r);
+            std::process::exit(2);
+        }
+    }
+
+    // The watcher reports our join, and stops its thread when dropped
+    let watch = match cpg::GroupWatcher::new(handle) {
+        Ok(w) => w.watch(std::time::Duration::from_millis(100)),
+        Err(e) => {
+            println!("Error in CPG GroupWatcher: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = cpg::join(handle, "TESTWATCH") {
+        println!("Error in CPG join: {}", e);
+        std::process::exit(1);
+    }
+    let deadline = std::time::Instant::now() + std::time::Duration::new(5, 0);
+    let seen = loop {
+        match watch
+            .events()
+            .recv_timeout(std::time::Duration::from_millis(100))
+        {
+            Ok(Ok(cpg::GroupEvent::ProcessJoined { group, pid, .. }))
+                if group == "TESTWATCH" && pid == std::process::id() =>
+            {
+                break true
+            }
+            Ok(ev) => println!("GROUP EVENT: {:?}", ev),
+            Err(_) if std::time::Instant::now() < deadline => {}
+            Err(_) => break false,
+        }
+    };
+    if !seen {
+        println!("Error: GroupWatcher did not report our join of TESTWATCH");
+        std::process::exit(2);
+    }
+    drop(watch);
+    let _ = cpg::leave(handle, "TESTWATCH");
+
+    big_group_test(handle);
+}
+
+// More members than corosync returns from one membership_get, so it has to iterate.
+// corosync only lets a process join a group once, so each member is a copy of this
+// program that stays in the group until its stdin is closed.
+const BIG_GROUP_SIZE: usize = 390;
+
+fn big_group_member() {
+    let md = cpg::ModelData::ModelV1(cpg::Model1Data {
+        flags: cpg::Model1Flags::None,
+        deliver_fn: None,
+        confchg_fn: None,
+        totem_confchg_fn: None,
+    });
+    let handle = match cpg::initialize(&md, 0) {
+        Ok(h) => h,
+        Err(e) => {
+            println!("Error in CPG init of big group member: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = cpg::join(handle, "TESTBIG") {
+        println!("Error in CPG join of big group member: {}", e);
+        std::process::exit(1);
+    }
+    let _ = std::io::Read::read(&mut std::io::stdin(), &mut [0u8; 1]);
+    let _ = cpg::finalize(handle);
+}
+
+fn big_group_test(handle: cpg::Handle) {
+    let mut members = Vec::new();
+    for _ in 0..BIG_GROUP_SIZE {
+        match std::process::Command::new(std::env::current_exe().unwrap())
+            .arg("big-group-member")
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+        {
+            Ok(c) => members.push(c),
+            Err(e) => {
+                println!("Error starting big group member: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let deadline = std::time::Instant::now() + std::time::Duration::new(30, 0);
+    let result = loop {
+        let r = cpg::membership_get(handle, "TESTBIG");
+        match &r {
+            Ok(m) if m.len() < BIG_GROUP_SIZE && std::time::Instant::now() < deadline => {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+            _ => break r,
+        }
+    };
+
+    // Closing stdin tells them to go
+    for c in &mut members {
+        drop(c.stdin.take());
+    }
+    for mut c in members {
+        let _ = c.wait();
+    }
+
+    match result {
+        Ok(m) if m.len() == BIG_GROUP_SIZE => println!("big group has {} members", m.len()),
+        r => {
+            println!("Error: membership_get of big group returned {:?}", r);
+            std::process::exit(2);
+        }
+    }
 }
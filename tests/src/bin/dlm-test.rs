@@ -0,0 +1,52 @@
+// Test the DLM module. Requires that corosync is running and that we are root.
+
+extern crate rust_corosync as corosync;
+use corosync::dlm;
+use std::time::Duration;
+
+fn main() {
+    let lm = match dlm::LockManager::new("TESTDLM") {
+        Ok(l) => l,
+        Err(e) => {
+            println!("Error in DLM init: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Take an exclusive lock
+    if let Err(e) = lm.lock("lock1", dlm::LockMode::Exclusive, Duration::new(5, 0)) {
+        println!("Error in DLM lock: {}", e);
+        std::process::exit(1);
+    }
+    println!("holders of lock1: {:?}", lm.holders("lock1"));
+
+    // We already hold it, so this should fail
+    match lm.try_lock("lock1", dlm::LockMode::Shared, Duration::new(5, 0)) {
+        Err(corosync::CsError::CsErrExist) => {}
+        r => {
+            println!("Error: DLM try_lock of held lock returned {:?}", r);
+            std::process::exit(2);
+        }
+    }
+
+    // Shared locks on another name
+    match lm.try_lock("lock2", dlm::LockMode::Shared, Duration::new(5, 0)) {
+        Ok(true) => {}
+        r => {
+            println!("Error: DLM try_lock returned {:?}", r);
+            std::process::exit(2);
+        }
+    }
+
+    for l in ["lock1", "lock2"] {
+        if let Err(e) = lm.unlock(l) {
+            println!("Error in DLM unlock: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Err(e) = lm.finalize() {
+        println!("Error in DLM finalize: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,127 @@
+// Test the election module. Requires that corosync is running and that we are root.
+
+extern crate rust_corosync as corosync;
+use corosync::{cpg, election};
+use std::time::{Duration, Instant};
+
+// Pretend to be the leader of another partition that has just merged with ours:
+// join the group and announce ourself as leader, with a better priority
+fn merge_child() {
+    let md = cpg::ModelData::ModelV1(cpg::Model1Data {
+        flags: cpg::Model1Flags::None,
+        deliver_fn: None,
+        confchg_fn: None,
+        totem_confchg_fn: None,
+    });
+    let handle = match cpg::initialize(&md, 0) {
+        Ok(h) => h,
+        Err(e) => {
+            println!("Error in CPG init: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let nodeid = match cpg::local_get(handle) {
+        Ok(n) => n,
+        Err(e) => {
+            println!("Error in CPG local_get: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = cpg::join(handle, "TESTELECTION") {
+        println!("Error in CPG join: {}", e);
+        std::process::exit(1);
+    }
+
+    // MSG_ANNOUNCE, priority, has_leader, leader nodeid, leader pid
+    let mut msg = vec![1u8];
+    msg.extend_from_slice(&30u32.to_le_bytes());
+    msg.push(1);
+    msg.extend_from_slice(&u32::from(nodeid).to_le_bytes());
+    msg.extend_from_slice(&std::process::id().to_le_bytes());
+    loop {
+        match cpg::mcast_joined(handle, cpg::Guarantee::TypeAgreed, &msg) {
+            Ok(()) => break,
+            Err(corosync::CsError::CsErrTryAgain) => {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Err(e) => {
+                println!("Error in CPG mcast_joined: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    std::thread::sleep(Duration::new(5, 0));
+    let _ = cpg::finalize(handle);
+}
+
+fn main() {
+    if std::env::args().nth(1).as_deref() == Some("merge-child") {
+        merge_child();
+        return;
+    }
+
+    let el = match election::Election::new("TESTELECTION", 10, election::Policy::Sticky) {
+        Ok(e) => e,
+        Err(e) => {
+            println!("Error in election init: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let changes = el.subscribe();
+
+    match el.wait_for_leader(Duration::new(5, 0)) {
+        Ok(l) => println!("Leader is {}, is_leader: {}", l, el.is_leader()),
+        Err(e) => {
+            println!("Error waiting for leader: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    for c in changes.try_iter() {
+        println!("leader change: {:?}", c);
+    }
+
+    if let Err(e) = el.set_priority(20) {
+        println!("Error in election set_priority: {}", e);
+        std::process::exit(1);
+    }
+
+    // Two incumbents after a merge: both sides must settle on the better one
+    let mut child = match std::process::Command::new(std::env::current_exe().unwrap())
+        .arg("merge-child")
+        .spawn()
+    {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Error starting merge child: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let deadline = Instant::now() + Duration::new(5, 0);
+    while el.leader().map(|l| l.pid) != Some(child.id()) && Instant::now() < deadline {
+        if let Err(e) = el.dispatch(corosync::DispatchFlags::All) {
+            println!("Error in election dispatch: {}", e);
+            std::process::exit(1);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    match el.leader() {
+        Some(l) if l.pid == child.id() => println!("merged leader is {}", l),
+        l => {
+            println!(
+                "ERROR leader after merge is {:?}, expected pid {}",
+                l,
+                child.id()
+            );
+            std::process::exit(2);
+        }
+    }
+
+    if let Err(e) = el.finalize() {
+        println!("Error in election finalize: {}", e);
+        std::process::exit(1);
+    }
+}
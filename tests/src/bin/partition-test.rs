@@ -0,0 +1,119 @@
+// Test the partition tracker. This doesn't need corosync, the totem
+// configurations are made up.
+
+extern crate rust_corosync as corosync;
+use corosync::cpg::RingId;
+use corosync::partition::{PartitionTracker, Transition};
+use corosync::NodeId;
+
+fn ring(seq: u64) -> RingId {
+    RingId {
+        nodeid: NodeId::from(1),
+        seq,
+    }
+}
+
+fn nodes(ids: &[u32]) -> Vec<NodeId> {
+    ids.iter().map(|n| NodeId::from(*n)).collect()
+}
+
+fn check(what: &str, got: Vec<Transition>, expected: Vec<Transition>) {
+    println!("{}: {:?}", what, got);
+    if got != expected {
+        println!("Error: expected {:?}", expected);
+        std::process::exit(2);
+    }
+}
+
+fn main() {
+    let mut pt = PartitionTracker::new();
+    let events = pt.subscribe();
+
+    check(
+        "initial",
+        pt.totem_confchg(ring(4), &nodes(&[1, 2, 3, 4, 5])),
+        vec![Transition::Initial {
+            ring_id: ring(4),
+            members: nodes(&[1, 2, 3, 4, 5]),
+        }],
+    );
+    pt.set_quorate(true);
+
+    // One node down, we are still quorate
+    check(
+        "node loss",
+        pt.totem_confchg(ring(8), &nodes(&[1, 2, 3, 4])),
+        vec![Transition::NodeLoss {
+            ring_id: ring(8),
+            lost: nodes(&[5]),
+        }],
+    );
+
+    // Two more go and quorum goes with them
+    check(
+        "loss before quorum",
+        pt.totem_confchg(ring(12), &nodes(&[1, 2])),
+        vec![Transition::NodeLoss {
+            ring_id: ring(12),
+            lost: nodes(&[3, 4]),
+        }],
+    );
+    check(
+        "quorum lost",
+        pt.set_quorate(false).into_iter().collect(),
+        vec![Transition::Partition {
+            ring_id: ring(12),
+            lost: nodes(&[3, 4]),
+        }],
+    );
+    if !pt.is_partitioned() {
+        println!("Error: tracker should be partitioned");
+        std::process::exit(2);
+    }
+
+    // The other side comes back, along with node 5
+    check(
+        "merge",
+        pt.totem_confchg(ring(16), &nodes(&[1, 2, 3, 4, 5])),
+        vec![
+            Transition::Merge {
+                ring_id: ring(16),
+                rejoined: nodes(&[3, 4]),
+                since: ring(12),
+            },
+            Transition::NodesJoined {
+                ring_id: ring(16),
+                joined: nodes(&[5]),
+            },
+        ],
+    );
+    pt.set_quorate(true);
+
+    check(
+        "same members",
+        pt.totem_confchg(ring(20), &nodes(&[1, 2, 3, 4, 5])),
+        vec![Transition::NoChange { ring_id: ring(20) }],
+    );
+    check(
+        "regression",
+        pt.totem_confchg(ring(18), &nodes(&[1, 2, 3, 4, 5])),
+        vec![Transition::RingIdRegression {
+            previous: ring(20),
+            current: ring(18),
+        }],
+    );
+
+    // Without quorum information, losing half the nodes is a partition
+    let mut pt = PartitionTracker::new();
+    pt.totem_confchg(ring(4), &nodes(&[1, 2, 3, 4]));
+    check(
+        "no quorum info",
+        pt.totem_confchg(ring(8), &nodes(&[1, 2])),
+        vec![Transition::Partition {
+            ring_id: ring(8),
+            lost: nodes(&[3, 4]),
+        }],
+    );
+
+    println!("{} events sent to subscriber", events.try_iter().count());
+}
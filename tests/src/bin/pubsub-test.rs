@@ -0,0 +1,93 @@
+// Test the pubsub module. Requires that corosync is running and that we are root.
+
+extern crate rust_corosync as corosync;
+use corosync::{cpg, pubsub, NodeId};
+use std::time::{Duration, Instant};
+
+static ALPHA_RECVD: std::sync::Mutex<i32> = std::sync::Mutex::new(0);
+
+fn topic_handler(topic: &str, nodeid: NodeId, pid: u32, payload: &[u8]) {
+    println!(
+        "TEST topic_handler: topic {} from {}/{}: {:?}",
+        topic,
+        nodeid,
+        pid,
+        String::from_utf8_lossy(payload)
+    );
+    if topic == "alpha" {
+        *ALPHA_RECVD.lock().unwrap() += 1;
+    }
+}
+
+// Dispatch until `done` is true, or give up after 5 seconds
+fn dispatch_until<F: Fn() -> bool>(ps: &pubsub::PubSub, done: F) -> bool {
+    let deadline = Instant::now() + Duration::new(5, 0);
+    while !done() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        if let Err(e) = ps.dispatch(corosync::DispatchFlags::All) {
+            println!("Error in pubsub dispatch: {}", e);
+            std::process::exit(1);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+fn main() {
+    let ps = match pubsub::PubSub::new("TESTPUBSUB") {
+        Ok(p) => p,
+        Err(e) => {
+            println!("Error in pubsub init: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = ps.subscribe("alpha", topic_handler) {
+        println!("Error in pubsub subscribe: {}", e);
+        std::process::exit(1);
+    }
+    ps.set_guarantee("alpha", cpg::Guarantee::TypeFifo);
+
+    // Let our own subscription arrive
+    let me = std::process::id();
+    if !dispatch_until(&ps, || ps.subscribers("alpha").iter().any(|s| s.1 == me)) {
+        println!("ERROR our subscription to alpha never arrived");
+        std::process::exit(2);
+    }
+    println!("topics: {:?}", ps.topics());
+    println!("subscribers of alpha: {:?}", ps.subscribers("alpha"));
+
+    match ps.publish("alpha", b"hello alpha") {
+        Ok(true) => println!("published on alpha"),
+        Ok(false) => {
+            println!("ERROR publish on alpha sent nothing");
+            std::process::exit(2);
+        }
+        Err(e) => {
+            println!("Error in pubsub publish: {}", e);
+            std::process::exit(1);
+        }
+    }
+    // Nobody wants this, so it should not be sent
+    match ps.publish("beta", b"hello beta") {
+        Ok(sent) => println!("published on beta: {}", sent),
+        Err(e) => {
+            println!("Error in pubsub publish: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if !dispatch_until(&ps, || *ALPHA_RECVD.lock().unwrap() > 0) {
+        println!("ERROR message published on alpha was not received");
+        std::process::exit(2);
+    }
+
+    if let Err(e) = ps.unsubscribe("alpha") {
+        println!("Error in pubsub unsubscribe: {}", e);
+    }
+    if let Err(e) = ps.finalize() {
+        println!("Error in pubsub finalize: {}", e);
+        std::process::exit(1);
+    }
+}
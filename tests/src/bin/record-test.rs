@@ -0,0 +1,149 @@
+// Test CPG record & replay. Requires that corosync is running and that we are root.
+
+extern crate rust_corosync as corosync;
+use corosync::{cpg, record, NodeId};
+
+const RECORDING: &str = "/tmp/rust-corosync-record-test.cpgrec";
+const RECORDING2: &str = "/tmp/rust-corosync-record-test2.cpgrec";
+const DAMAGED: &str = "/tmp/rust-corosync-record-test-damaged.cpgrec";
+
+fn deliver_fn(
+    _handle: &cpg::Handle,
+    group_name: cpg::CpgName,
+    nodeid: NodeId,
+    pid: u32,
+    _msg: &[u8],
+    msg_len: usize,
+) {
+    println!(
+        "TEST deliver_fn called for {}, from nodeid/pid {}/{}. len={}",
+        group_name, nodeid, pid, msg_len
+    );
+}
+
+fn confchg_fn(
+    _handle: &cpg::Handle,
+    group_name: &cpg::CpgName,
+    member_list: Vec<cpg::Address>,
+    _left_list: Vec<cpg::Address>,
+    _joined_list: Vec<cpg::Address>,
+) {
+    println!("TEST confchg_fn called for {}", group_name);
+    println!("  members: {:?}", member_list);
+}
+
+fn main() {
+    let md = cpg::ModelData::ModelV1(cpg::Model1Data {
+        flags: cpg::Model1Flags::None,
+        deliver_fn: Some(deliver_fn),
+        confchg_fn: Some(confchg_fn),
+        totem_confchg_fn: None,
+    });
+
+    let handle = match cpg::initialize(&md, 0) {
+        Ok(h) => h,
+        Err(e) => {
+            println!("Error in CPG init: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let recorder = match record::Recorder::start(handle, RECORDING) {
+        Ok(r) => r,
+        Err(e) => {
+            println!("Error starting recorder: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = cpg::join(handle, "TESTRECORD") {
+        println!("Error in CPG join: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = cpg::mcast_joined(handle, cpg::Guarantee::TypeAgreed, b"recorded message") {
+        println!("Error in CPG mcast_joined: {}", e);
+    }
+    // Wait for our join and message to come back
+    for _ in 0..2 {
+        if let Err(e) = cpg::dispatch(handle, corosync::DispatchFlags::One) {
+            println!("Error in CPG dispatch: {}", e);
+        }
+    }
+
+    println!("recorded {} events", recorder.records());
+    if let Err(e) = recorder.stop() {
+        println!("Error stopping recorder: {}", e);
+        std::process::exit(1);
+    }
+
+    // Dropping a recorder must not stop a newer one on the same handle
+    let (old, new) = match record::Recorder::start(handle, DAMAGED)
+        .and_then(|old| record::Recorder::start(handle, RECORDING2).map(|new| (old, new)))
+    {
+        Ok(r) => r,
+        Err(e) => {
+            println!("Error starting recorder: {}", e);
+            std::process::exit(1);
+        }
+    };
+    drop(old);
+    if let Err(e) = cpg::mcast_joined(handle, cpg::Guarantee::TypeAgreed, b"second message") {
+        println!("Error in CPG mcast_joined: {}", e);
+    }
+    if let Err(e) = cpg::dispatch(handle, corosync::DispatchFlags::One) {
+        println!("Error in CPG dispatch: {}", e);
+    }
+    if new.records() == 0 {
+        println!("ERROR dropping the old recorder stopped the new one");
+        std::process::exit(2);
+    }
+    drop(new);
+    let _ = std::fs::remove_file(RECORDING2);
+    let _ = cpg::finalize(handle);
+
+    // Read it back
+    match record::Replay::open(RECORDING) {
+        Ok(replay) => {
+            for r in replay {
+                match r {
+                    Ok(rec) => println!("RECORD: {:?}", rec),
+                    Err(e) => println!("Error reading recording: {}", e),
+                }
+            }
+        }
+        Err(e) => {
+            println!("Error opening recording: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // And through the callbacks again
+    match record::Replay::open(RECORDING).and_then(|r| r.run(&md)) {
+        Ok(n) => println!("replayed {} events", n),
+        Err(e) => {
+            println!("Error replaying recording: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let _ = std::fs::remove_file(RECORDING);
+
+    // A damaged length must be an error, not a huge allocation
+    let mut damaged = b"CPGREC".to_vec();
+    damaged.extend_from_slice(&1u16.to_le_bytes());
+    damaged.extend_from_slice(&u32::MAX.to_le_bytes());
+    damaged.extend_from_slice(b"short");
+    if let Err(e) = std::fs::write(DAMAGED, &damaged) {
+        println!("Error writing damaged recording: {}", e);
+        std::process::exit(1);
+    }
+    match record::Replay::open(DAMAGED).map(|mut r| r.next()) {
+        Ok(Some(Err(corosync::CsError::CsErrMessageError))) => {
+            println!("damaged recording rejected")
+        }
+        r => {
+            println!("ERROR reading damaged recording gave {:?}", r);
+            std::process::exit(2);
+        }
+    }
+    let _ = std::fs::remove_file(DAMAGED);
+}
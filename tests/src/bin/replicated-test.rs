@@ -0,0 +1,281 @@
+// Test the replicated state module. Requires that corosync is running and that we are root.
+
+extern crate rust_corosync as corosync;
+use corosync::cpg;
+use corosync::replicated::{ReplicatedState, StateMachine};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+// A simple counter
+struct Counter {
+    value: u64,
+}
+
+impl StateMachine for Counter {
+    fn apply(&mut self, op: &[u8]) {
+        self.value += op.len() as u64;
+    }
+    fn snapshot(&self) -> Vec<u8> {
+        self.value.to_le_bytes().to_vec()
+    }
+    fn restore(&mut self, snapshot: &[u8]) -> corosync::Result<()> {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(snapshot);
+        self.value = u64::from_le_bytes(b);
+        Ok(())
+    }
+}
+
+fn main() {
+    match std::env::args().nth(1).as_deref() {
+        Some("silent-member") => return silent_member(),
+        Some("late-joiner") => return late_joiner(),
+        Some("bad-restore") => return bad_restore(),
+        _ => {}
+    }
+
+    let rs = match ReplicatedState::new("TESTREPLICATED", Counter { value: 0 }) {
+        Ok(r) => r,
+        Err(e) => {
+            println!("Error in replicated state init: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = rs.wait_synced(Duration::new(5, 0)) {
+        println!("Error waiting for state sync: {}", e);
+        std::process::exit(1);
+    }
+    let before = rs.read(|c| c.value);
+
+    match rs.submit(b"xxx") {
+        Ok(seq) => {
+            if let Err(e) = rs.wait_applied(seq, Duration::new(5, 0)) {
+                println!("Error waiting for op to be applied: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            println!("Error in replicated state submit: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let after = rs.read(|c| c.value);
+    println!("counter was {}, now {}", before, after);
+    if after < before + 3 {
+        println!("Error: counter did not increase");
+        std::process::exit(2);
+    }
+
+    // A joiner that can't restore our snapshot must say so rather than wait forever
+    let mut child = spawn_child("bad-restore");
+    match wait_exit(&rs, &mut child) {
+        Some(s) if s.success() => println!("bad snapshot reported"),
+        s => {
+            println!("ERROR bad-restore child: {:?}", s);
+            std::process::exit(2);
+        }
+    }
+
+    if let Err(e) = rs.finalize() {
+        println!("Error in replicated state finalize: {}", e);
+        std::process::exit(1);
+    }
+
+    lost_donor_test();
+}
+
+// Can't be restored from a Counter snapshot
+struct BadRestore;
+
+impl StateMachine for BadRestore {
+    fn apply(&mut self, _op: &[u8]) {}
+    fn snapshot(&self) -> Vec<u8> {
+        Vec::new()
+    }
+    fn restore(&mut self, _snapshot: &[u8]) -> corosync::Result<()> {
+        Err(corosync::CsError::CsErrInvalidParam)
+    }
+}
+
+fn bad_restore() {
+    let rs = match ReplicatedState::new("TESTREPLICATED", BadRestore) {
+        Ok(r) => r,
+        Err(e) => {
+            println!("Error in replicated state init: {}", e);
+            std::process::exit(1);
+        }
+    };
+    match rs.wait_synced(Duration::new(10, 0)) {
+        Err(corosync::CsError::CsErrInvalidParam)
+            if rs.error() == Some(corosync::CsError::CsErrInvalidParam) => {}
+        r => {
+            println!("ERROR wait_synced with a bad snapshot returned {:?}", r);
+            std::process::exit(2);
+        }
+    }
+    if rs.submit(b"x").is_ok() {
+        println!("ERROR submit accepted after a bad snapshot");
+        std::process::exit(2);
+    }
+}
+
+// Dispatch `rs` until `child` exits, so that it can get the state from us
+fn wait_exit<S: StateMachine>(
+    rs: &ReplicatedState<S>,
+    child: &mut Child,
+) -> Option<std::process::ExitStatus> {
+    let deadline = Instant::now() + Duration::new(15, 0);
+    loop {
+        if let Err(e) = rs.dispatch(corosync::DispatchFlags::All) {
+            println!("Error in replicated state dispatch: {}", e);
+            std::process::exit(1);
+        }
+        match child.try_wait() {
+            Ok(Some(status)) => return Some(status),
+            Ok(None) if Instant::now() < deadline => {}
+            _ => {
+                let _ = child.kill();
+                return None;
+            }
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// A group where the only member that joiners can get a copy of the state from leaves
+// while two of them are waiting, one of which doesn't know the other isn't synced
+const LOST_DONOR_GROUP: &str = "TESTREPLDONOR";
+
+// Stands in for the existing member: joins the group but never sends anything,
+// and leaves when its stdin is closed
+fn silent_member() {
+    let md = cpg::ModelData::ModelV1(cpg::Model1Data {
+        flags: cpg::Model1Flags::None,
+        deliver_fn: None,
+        confchg_fn: None,
+        totem_confchg_fn: None,
+    });
+    let handle = match cpg::initialize(&md, 0) {
+        Ok(h) => h,
+        Err(e) => {
+            println!("Error in CPG init: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = cpg::join(handle, LOST_DONOR_GROUP) {
+        println!("Error in CPG join: {}", e);
+        std::process::exit(1);
+    }
+    let _ = std::io::Read::read(&mut std::io::stdin(), &mut [0u8; 1]);
+    let _ = cpg::finalize(handle);
+}
+
+fn late_joiner() {
+    let rs = match ReplicatedState::new(LOST_DONOR_GROUP, Counter { value: 0 }) {
+        Ok(r) => r,
+        Err(e) => {
+            println!("Error in replicated state init: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = rs.wait_synced(Duration::new(10, 0)) {
+        println!("ERROR late joiner never got the state: {}", e);
+        std::process::exit(2);
+    }
+    let _ = rs.finalize();
+}
+
+fn spawn_child(mode: &str) -> Child {
+    match Command::new(std::env::current_exe().unwrap())
+        .arg(mode)
+        .stdin(Stdio::piped())
+        .spawn()
+    {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Error starting {}: {}", mode, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Wait until the group has `count` members, dispatching `rs` meanwhile if there is one
+fn wait_members(watch: cpg::Handle, rs: Option<&ReplicatedState<Counter>>, count: usize) {
+    let deadline = Instant::now() + Duration::new(5, 0);
+    loop {
+        let members =
+            match cpg::CpgIterStart::new(watch, LOST_DONOR_GROUP, cpg::CpgIterType::OneGroup) {
+                Ok(i) => i.into_iter().filter(|m| m.is_ok()).count(),
+                Err(e) => {
+                    println!("Error in CPG iter start: {}", e);
+                    std::process::exit(1);
+                }
+            };
+        if members == count {
+            return;
+        }
+        if Instant::now() >= deadline {
+            println!("ERROR waiting for {} members, have {}", count, members);
+            std::process::exit(2);
+        }
+        if let Some(rs) = rs {
+            if let Err(e) = rs.dispatch(corosync::DispatchFlags::All) {
+                println!("Error in replicated state dispatch: {}", e);
+                std::process::exit(1);
+            }
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn lost_donor_test() {
+    let md = cpg::ModelData::ModelV1(cpg::Model1Data {
+        flags: cpg::Model1Flags::None,
+        deliver_fn: None,
+        confchg_fn: None,
+        totem_confchg_fn: None,
+    });
+    let watch = match cpg::initialize(&md, 0) {
+        Ok(h) => h,
+        Err(e) => {
+            println!("Error in CPG init: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // We wait for the silent member to send the state, and so does the late joiner,
+    // which doesn't know that we are not synced either
+    let mut silent = spawn_child("silent-member");
+    wait_members(watch, None, 1);
+    let rs = match ReplicatedState::new(LOST_DONOR_GROUP, Counter { value: 0 }) {
+        Ok(r) => r,
+        Err(e) => {
+            println!("Error in replicated state init: {}", e);
+            std::process::exit(1);
+        }
+    };
+    wait_members(watch, Some(&rs), 2);
+    let mut late = spawn_child("late-joiner");
+    wait_members(watch, Some(&rs), 3);
+
+    // Now the silent member goes, and we have to send the late joiner the initial state
+    drop(silent.stdin.take());
+    let _ = silent.wait();
+    let status = wait_exit(&rs, &mut late);
+    if status.map(|s| s.success()) != Some(true) {
+        println!(
+            "ERROR late joiner did not sync after the donor left: {:?}",
+            status
+        );
+        std::process::exit(2);
+    }
+    if !rs.is_synced() {
+        println!("ERROR not synced after the donor left");
+        std::process::exit(2);
+    }
+    println!("late joiner synced after the donor left");
+    let _ = rs.finalize();
+    let _ = cpg::finalize(watch);
+}
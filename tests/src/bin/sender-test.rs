@@ -0,0 +1,45 @@
+// Test the buffered CPG sender. Requires that corosync is running and that we are root.
+
+extern crate rust_corosync as corosync;
+use corosync::{cpg, sender};
+
+fn main() {
+    let md = cpg::ModelData::ModelV1(cpg::Model1Data {
+        flags: cpg::Model1Flags::None,
+        deliver_fn: None,
+        confchg_fn: None,
+        totem_confchg_fn: None,
+    });
+    let handle = match cpg::initialize(&md, 0) {
+        Ok(h) => h,
+        Err(e) => {
+            println!("Error in CPG init: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = cpg::join(handle, "TESTSENDER") {
+        println!("Error in CPG join: {}", e);
+        std::process::exit(1);
+    }
+
+    let s = sender::BufferedSender::new(handle, 1024 * 1024, sender::Backpressure::Block);
+    let msg = [0u8; 1000];
+    for _ in 0..10000 {
+        if let Err(e) = s.send(cpg::Guarantee::TypeAgreed, &msg) {
+            println!("Error in BufferedSender send: {}", e);
+            std::process::exit(1);
+        }
+    }
+    while s.queue_depth() > 0 {
+        if let Err(e) = s.dispatch(corosync::DispatchFlags::All) {
+            println!("Error in BufferedSender dispatch: {}", e);
+            std::process::exit(1);
+        }
+    }
+    println!("stats: {:?}", s.stats());
+
+    if let Err(e) = cpg::finalize(handle) {
+        println!("Error in CPG finalize: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,68 @@
+// Test the sharding module. This doesn't need corosync, the members are made up.
+
+extern crate rust_corosync as corosync;
+use corosync::sharding::{Member, Sharding};
+use corosync::{cpg, NodeId};
+
+fn address(nodeid: u32) -> cpg::Address {
+    cpg::Address {
+        nodeid: NodeId::from(nodeid),
+        pid: 1000 + nodeid,
+        reason: cpg::Reason::Join,
+    }
+}
+
+fn member(nodeid: u32) -> Member {
+    Member::from(&address(nodeid))
+}
+
+fn main() {
+    let keys: Vec<String> = (0..1000).map(|i| format!("key-{}", i)).collect();
+    let three = [address(1), address(2), address(3)];
+    let four = [address(1), address(2), address(3), address(4)];
+
+    // Two nodes looking at the same membership must agree
+    let mut s1 = Sharding::new(member(1), 100);
+    let mut s4 = Sharding::new(member(4), 100);
+    let gained = s1.confchg(&three, &keys).gained;
+    s4.confchg(&three, &keys);
+    println!("node 1 owns {} of {} keys", gained.len(), keys.len());
+    for k in &keys {
+        if s1.owner(k) != s4.owner(k) {
+            println!("Error: nodes disagree about the owner of {}", k);
+            std::process::exit(2);
+        }
+    }
+
+    // When node 4 joins, keys should only move to node 4
+    let before: Vec<Option<Member>> = keys.iter().map(|k| s1.owner(k)).collect();
+    let moves = s1.confchg(&four, &keys);
+    let joiner = s4.confchg(&four, &keys);
+    println!(
+        "node 1 lost {} keys, node 4 gained {}",
+        moves.lost.len(),
+        joiner.gained.len()
+    );
+    for (k, old) in keys.iter().zip(before) {
+        let new = s1.owner(k);
+        if new != old && new != Some(member(4)) {
+            println!("Error: {} moved from {:?} to {:?}", k, old, new);
+            std::process::exit(2);
+        }
+    }
+    if !moves.gained.is_empty() {
+        println!("Error: node 1 gained keys when a node joined");
+        std::process::exit(2);
+    }
+
+    // A heavier member should own more
+    let mut weighted = Sharding::new(member(1), 100);
+    weighted.set_weight(member(1), 3);
+    weighted.confchg(&three, &keys);
+    let mine = keys.iter().filter(|k| weighted.is_mine(*k)).count();
+    println!("node 1 with weight 3 owns {} keys", mine);
+    if mine <= gained.len() {
+        println!("Error: weight made no difference");
+        std::process::exit(2);
+    }
+}